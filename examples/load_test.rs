@@ -0,0 +1,114 @@
+//! Runs a metrics server alongside a concurrent scrape load generator, reporting latency
+//! percentiles so the capacity of a configuration can be validated.
+//!
+//! ```sh
+//! cargo run --release --example load_test -- --clients 16 --requests 200 --size 1048576 --workers 4
+//! ```
+use std::env;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use metrics_server::MetricsServer;
+
+// Load test options, set with `--name value` flags.
+struct Options {
+    clients: usize,
+    requests: usize,
+    size: usize,
+    workers: usize,
+}
+
+fn main() {
+    let opts = parse_args();
+
+    let mut server = MetricsServer::builder()
+        .address("localhost:0")
+        .workers(opts.workers)
+        .build()
+        .unwrap();
+    server.serve();
+
+    // Serve a payload of the requested size in the text exposition format.
+    let line = "load_test_metric{label=\"value\"} 1\n";
+    let payload = line.repeat(opts.size / line.len() + 1);
+    server.update(payload.into_bytes()[..opts.size].to_vec());
+
+    let addr = server.local_addr();
+    println!(
+        "scraping {} bytes from http://{addr}/metrics with {} clients, {} requests each, {} workers",
+        opts.size, opts.clients, opts.requests, opts.workers
+    );
+
+    let start = Instant::now();
+    let mut latencies: Vec<Duration> = thread::scope(|s| {
+        let clients: Vec<_> = (0..opts.clients)
+            .map(|_| s.spawn(|| (0..opts.requests).map(|_| scrape(addr)).collect::<Vec<_>>()))
+            .collect();
+        clients
+            .into_iter()
+            .flat_map(|c| c.join().unwrap())
+            .collect()
+    });
+    let elapsed = start.elapsed();
+    server.stop().unwrap();
+
+    latencies.sort();
+    let percentile = |p: f64| latencies[((latencies.len() - 1) as f64 * p) as usize];
+    println!(
+        "{} requests in {:.2?} ({:.0} req/s)",
+        latencies.len(),
+        elapsed,
+        latencies.len() as f64 / elapsed.as_secs_f64()
+    );
+    println!(
+        "latency p50={:.2?} p90={:.2?} p99={:.2?} max={:.2?}",
+        percentile(0.5),
+        percentile(0.9),
+        percentile(0.99),
+        latencies[latencies.len() - 1]
+    );
+}
+
+// Performs a single scrape, returning how long it took to read the whole response.
+fn scrape(addr: SocketAddr) -> Duration {
+    let start = Instant::now();
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let mut res = Vec::new();
+    stream.read_to_end(&mut res).unwrap();
+    assert!(res.starts_with(b"HTTP/1.1 200 OK\r\n"));
+    start.elapsed()
+}
+
+fn parse_args() -> Options {
+    let mut opts = Options {
+        clients: 8,
+        requests: 100,
+        size: 64 * 1024,
+        workers: 4,
+    };
+
+    let mut args = env::args().skip(1);
+    while let Some(flag) = args.next() {
+        let value = args.next().and_then(|v| v.parse::<usize>().ok());
+        let target = match flag.as_str() {
+            "--clients" => &mut opts.clients,
+            "--requests" => &mut opts.requests,
+            "--size" => &mut opts.size,
+            "--workers" => &mut opts.workers,
+            _ => usage(),
+        };
+        *target = value.filter(|&v| v > 0).unwrap_or_else(|| usage());
+    }
+    opts
+}
+
+fn usage() -> ! {
+    eprintln!("usage: load_test [--clients N] [--requests N] [--size BYTES] [--workers N]");
+    process::exit(2);
+}
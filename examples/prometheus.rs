@@ -21,7 +21,7 @@ fn main() {
     .unwrap();
 
     // Expose the Prometheus metrics.
-    let server = MetricsServer::http("localhost:8001");
+    let mut server = MetricsServer::http("localhost:8001");
     info!("Starting metrics server: http://localhost:8001/metrics");
 
     thread::scope(|s| {
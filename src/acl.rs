@@ -0,0 +1,146 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use crate::error::ServerError;
+
+/// A range of IP addresses in CIDR notation, e.g. `10.0.0.0/8` or `2001:db8::/32`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Returns true if the address is within this range. IPv4 and IPv6 ranges only contain
+    /// addresses of the same family.
+    pub(crate) fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_eq(u32::from(net).into(), u32::from(ip).into(), 32, self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_eq(u128::from(net), u128::from(ip), 128, self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = ServerError;
+
+    /// Parses a range in CIDR notation, or a single address.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ServerError::Create(format!("invalid CIDR range {s:?}"));
+
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|&p| p <= max)
+                .ok_or_else(invalid)?,
+            None => max,
+        };
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+// Returns true if the first `prefix` bits of two addresses of the given width are equal.
+fn prefix_eq(a: u128, b: u128, bits: u8, prefix: u8) -> bool {
+    let shift = u32::from(bits - prefix);
+    a.checked_shr(shift).unwrap_or(0) == b.checked_shr(shift).unwrap_or(0)
+}
+
+/// Restricts which peer addresses may send requests.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct AccessList {
+    pub(crate) allow: Vec<Cidr>,
+    pub(crate) deny: Vec<Cidr>,
+}
+
+impl AccessList {
+    /// Returns true if a peer with the given address may send requests.
+    ///
+    /// Denied ranges take precedence over allowed ones. If any ranges are allowed, peers must be
+    /// within one of them, so peers with an unknown address are rejected.
+    pub(crate) fn permits(&self, ip: Option<IpAddr>) -> bool {
+        match ip {
+            Some(ip) => {
+                !self.deny.iter().any(|c| c.contains(ip))
+                    && (self.allow.is_empty() || self.allow.iter().any(|c| c.contains(ip)))
+            }
+            None => self.allow.is_empty(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_cidr() {
+        let net = cidr("10.1.0.0/16");
+        assert!(net.contains("10.1.0.1".parse().unwrap()));
+        assert!(net.contains("10.1.255.255".parse().unwrap()));
+        assert!(!net.contains("10.2.0.1".parse().unwrap()));
+        assert!(!net.contains("::ffff:10.1.0.1".parse().unwrap()));
+
+        let net = cidr("2001:db8::/32");
+        assert!(net.contains("2001:db8::1".parse().unwrap()));
+        assert!(!net.contains("2001:db9::1".parse().unwrap()));
+
+        assert!(cidr("0.0.0.0/0").contains("192.0.2.1".parse().unwrap()));
+        assert!(cidr("::/0").contains("::1".parse().unwrap()));
+        assert!(cidr("192.0.2.1").contains("192.0.2.1".parse().unwrap()));
+        assert!(!cidr("192.0.2.1").contains("192.0.2.2".parse().unwrap()));
+
+        for s in [
+            "",
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0.0/",
+            "10.0.0/8",
+            "localhost",
+        ] {
+            assert!(s.parse::<Cidr>().is_err(), "{s:?}");
+        }
+    }
+
+    #[test]
+    fn test_access_list() {
+        let ip = |s: &str| Some(s.parse().unwrap());
+
+        // Everything is permitted by default.
+        let acl = AccessList::default();
+        assert!(acl.permits(ip("192.0.2.1")));
+        assert!(acl.permits(None));
+
+        let acl = AccessList {
+            allow: vec![cidr("10.0.0.0/8")],
+            deny: vec![cidr("10.0.0.0/24")],
+        };
+        assert!(acl.permits(ip("10.1.0.1")));
+        assert!(!acl.permits(ip("10.0.0.1")));
+        assert!(!acl.permits(ip("192.0.2.1")));
+        assert!(!acl.permits(None));
+
+        let acl = AccessList {
+            allow: Vec::new(),
+            deny: vec![cidr("192.0.2.0/24")],
+        };
+        assert!(!acl.permits(ip("192.0.2.1")));
+        assert!(acl.permits(ip("10.0.0.1")));
+        assert!(acl.permits(None));
+    }
+}
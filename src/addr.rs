@@ -0,0 +1,175 @@
+use std::io;
+use std::net::{SocketAddr, SocketAddrV6, ToSocketAddrs};
+
+/// A socket address that may contain an IPv6 zone identifier given as an interface name.
+///
+/// The standard library only understands numeric scope IDs, e.g. `[fe80::1%2]:9100`. Wrapping an
+/// address in `ScopedAddr` also resolves interface names, e.g. `[fe80::1%eth0]:9100`, so servers
+/// can bind to link-local addresses. Addresses without a zone are resolved as usual.
+///
+/// ```rust,no_run
+/// use metrics_server::{MetricsServer, ScopedAddr};
+///
+/// let server = MetricsServer::http(ScopedAddr("[fe80::1%eth0]:9100"));
+/// ```
+#[derive(Clone, Debug)]
+pub struct ScopedAddr<S>(pub S);
+
+impl<S> ToSocketAddrs for ScopedAddr<S>
+where
+    S: AsRef<str>,
+{
+    type Iter = std::vec::IntoIter<SocketAddr>;
+
+    fn to_socket_addrs(&self) -> io::Result<Self::Iter> {
+        let addr = self.0.as_ref();
+        match parse_scoped(addr)? {
+            Some(addr) => Ok(vec![addr].into_iter()),
+            None => addr
+                .to_socket_addrs()
+                .map(|a| a.collect::<Vec<_>>().into_iter()),
+        }
+    }
+}
+
+// Parses an address of the form `[ip%zone]:port`, returning None if it has no zone.
+fn parse_scoped(addr: &str) -> io::Result<Option<SocketAddr>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "invalid scoped address");
+
+    let (host, port) = match addr.strip_prefix('[').and_then(|a| a.split_once("]:")) {
+        Some(parts) => parts,
+        None => return Ok(None),
+    };
+    let (ip, zone) = match host.split_once('%') {
+        Some(parts) => parts,
+        None => return Ok(None),
+    };
+
+    let ip = ip.parse().map_err(|_| invalid())?;
+    let port = port.parse().map_err(|_| invalid())?;
+    let scope_id = match zone.parse() {
+        Ok(id) => id,
+        Err(_) => interface_index(zone)?,
+    };
+
+    Ok(Some(SocketAddr::V6(SocketAddrV6::new(
+        ip, port, 0, scope_id,
+    ))))
+}
+
+/// Converts an IPv4-mapped IPv6 address, e.g. `[::ffff:10.0.0.1]:9100`, to the IPv4 address it
+/// represents.
+///
+/// Dual-stack listeners report IPv4 clients with mapped addresses, so canonicalizing them means
+/// logs and address checks treat a client the same whichever listener it connected to.
+pub(crate) fn canonical(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(a) => match a.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(ip.into(), a.port()),
+            None => addr,
+        },
+        SocketAddr::V4(_) => addr,
+    }
+}
+
+/// Formats an address for logging, showing IPv6 scope IDs as interface names where possible.
+pub(crate) fn display(addr: &SocketAddr) -> String {
+    match addr {
+        SocketAddr::V6(a) if a.scope_id() != 0 => match interface_name(a.scope_id()) {
+            Some(name) => format!("[{}%{}]:{}", a.ip(), name, a.port()),
+            None => addr.to_string(),
+        },
+        _ => addr.to_string(),
+    }
+}
+
+#[cfg(unix)]
+fn interface_index(name: &str) -> io::Result<u32> {
+    let name = std::ffi::CString::new(name)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid interface name"))?;
+
+    // SAFETY: `name` is a valid, NUL terminated C string.
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => Err(io::Error::last_os_error()),
+        index => Ok(index),
+    }
+}
+
+#[cfg(not(unix))]
+fn interface_index(_name: &str) -> io::Result<u32> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "interface names are not supported as zone identifiers on this platform",
+    ))
+}
+
+#[cfg(unix)]
+fn interface_name(index: u32) -> Option<String> {
+    let mut buf = [0 as libc::c_char; libc::IF_NAMESIZE];
+
+    // SAFETY: `buf` is IF_NAMESIZE bytes long, as required by if_indextoname.
+    let ptr = unsafe { libc::if_indextoname(index, buf.as_mut_ptr()) };
+    if ptr.is_null() {
+        return None;
+    }
+
+    // SAFETY: on success, if_indextoname writes a NUL terminated string to `buf`.
+    let name = unsafe { std::ffi::CStr::from_ptr(buf.as_ptr()) };
+    Some(name.to_string_lossy().into_owned())
+}
+
+#[cfg(not(unix))]
+fn interface_name(_index: u32) -> Option<String> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_scoped() {
+        // No zone.
+        assert!(parse_scoped("localhost:8001").unwrap().is_none());
+        assert!(parse_scoped("[::1]:8001").unwrap().is_none());
+
+        // Numeric zone.
+        let addr = parse_scoped("[fe80::1%2]:9100").unwrap().unwrap();
+        assert_eq!(addr, "[fe80::1%2]:9100".parse().unwrap());
+
+        // Invalid.
+        assert!(parse_scoped("[fe80::1%2]:port").is_err());
+        assert!(parse_scoped("[not-an-ip%2]:9100").is_err());
+        assert!(parse_scoped("[fe80::1%does-not-exist0]:9100").is_err());
+    }
+
+    #[test]
+    fn test_canonical() {
+        let cases = [
+            ("[::ffff:10.0.0.1]:9100", "10.0.0.1:9100"),
+            ("10.0.0.1:9100", "10.0.0.1:9100"),
+            ("[::1]:9100", "[::1]:9100"),
+            // IPv4-compatible addresses are deprecated, and aren't converted.
+            ("[::10.0.0.1]:9100", "[::a00:1]:9100"),
+        ];
+        for (addr, expected) in cases {
+            let addr: SocketAddr = addr.parse().unwrap();
+            assert_eq!(canonical(addr).to_string(), expected);
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_parse_scoped_interface_name() {
+        // The loopback interface always has index 1 on Linux.
+        let addr = parse_scoped("[fe80::1%lo]:9100").unwrap().unwrap();
+        assert_eq!(addr, "[fe80::1%1]:9100".parse().unwrap());
+        assert_eq!(display(&addr), "[fe80::1%lo]:9100");
+
+        let addrs: Vec<_> = ScopedAddr("[fe80::1%lo]:9100")
+            .to_socket_addrs()
+            .unwrap()
+            .collect();
+        assert_eq!(addrs, vec![addr]);
+    }
+}
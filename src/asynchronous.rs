@@ -0,0 +1,230 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use http::StatusCode;
+use log::{debug, error};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinSet;
+use tokio::time::{self, Duration, Instant};
+
+use crate::error::ServerError;
+use crate::listener::SocketConfig;
+use crate::request::Request;
+use crate::response::{Response, WriteConfig};
+use crate::server::{self, Endpoint, SharedData, ACCEPT_ERROR_DELAY};
+
+/// Serves requests on every listener until the returned future is dropped.
+pub(crate) async fn serve(shared: Arc<SharedData>, path: String) -> Result<(), ServerError> {
+    let listeners = shared
+        .listeners
+        .iter()
+        .map(|l| l.to_async())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| ServerError::Create(e.to_string()))?;
+
+    // Dropping the set aborts the accept loops, so they stop with the returned future.
+    let mut tasks = JoinSet::new();
+    for (i, listener) in listeners.into_iter().enumerate() {
+        tasks.spawn(accept(Arc::clone(&shared), i, listener, path.clone()));
+    }
+    while tasks.join_next().await.is_some() {}
+    Ok(())
+}
+
+// Accepts connections, handling each in a new task so slow clients don't block others.
+async fn accept(s: Arc<SharedData>, i: usize, listener: TcpListener, path: String) {
+    let path: Arc<str> = path.into();
+    loop {
+        let (mut stream, remote_addr) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                error!("error accepting connection: {e}");
+                time::sleep(ACCEPT_ERROR_DELAY).await;
+                continue;
+            }
+        };
+
+        let s = Arc::clone(&s);
+        let path = Arc::clone(&path);
+        tokio::spawn(async move {
+            let _permit = match s.admit() {
+                Some(permit) => permit,
+                None => {
+                    let res = server::rejected();
+                    s.responded(res.status_code());
+                    let timeout = s.listeners[i].socket_config().write_timeout;
+                    send(
+                        &mut stream,
+                        http::Version::HTTP_11,
+                        res,
+                        &s.config.write,
+                        timeout,
+                    )
+                    .await;
+                    return;
+                }
+            };
+            let endpoint = Endpoint::new(&s.listeners[i], &path, &s.config);
+            if let Err(e) = s.listeners[i].configure_async(&stream) {
+                error!("error configuring connection: {e}");
+                return;
+            }
+            let config = s.listeners[i].socket_config();
+            handle(&s, &endpoint, config, stream, remote_addr).await;
+        });
+    }
+}
+
+// Reads a single request from the connection and writes the response.
+async fn handle(
+    s: &SharedData,
+    endpoint: &Endpoint<'_>,
+    config: &SocketConfig,
+    mut stream: TcpStream,
+    addr: SocketAddr,
+) {
+    // The whole request must be read by the deadline, so slow clients can't hold the connection.
+    let deadline = Instant::now() + config.read_timeout;
+    let buf = match time::timeout_at(deadline, read_head(&mut stream, s)).await {
+        Ok(Ok(Some(buf))) => buf,
+        Ok(Ok(None)) => {
+            let res = Response::empty(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+            s.responded(res.status_code());
+            send(
+                &mut stream,
+                http::Version::HTTP_11,
+                res,
+                &s.config.write,
+                config.write_timeout,
+            )
+            .await;
+            return;
+        }
+        Ok(Err(e)) => {
+            debug!("error reading request: {e}");
+            return;
+        }
+        Err(_) => return,
+    };
+
+    let mut reader = buf.as_slice();
+    let req = match Request::read(&mut reader, Some(addr), &s.config.limits) {
+        Ok(req) => req,
+        Err(status) => {
+            // Only respond if the client is still there to read it.
+            if let Some(status) = status {
+                s.responded(status);
+                let res = Response::empty(status);
+                send(
+                    &mut stream,
+                    http::Version::HTTP_11,
+                    res,
+                    &s.config.write,
+                    config.write_timeout,
+                )
+                .await;
+            }
+            return;
+        }
+    };
+
+    // Handle expectations before reading any request body.
+    if let Some(expect) = req.expect() {
+        if !server::expect_continue(s, expect) {
+            let res = Response::empty(StatusCode::EXPECTATION_FAILED);
+            server::log(s, &req, &res);
+            send(
+                &mut stream,
+                req.http_version(),
+                res,
+                &s.config.write,
+                config.write_timeout,
+            )
+            .await;
+            return;
+        }
+
+        if let Err(e) = stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await {
+            error!("error sending continue response: {e}");
+            return;
+        }
+    }
+
+    // Part of the body may already have been read along with the head.
+    let remaining = req.discard_length().saturating_sub(reader.len() as u64);
+    let (mut body, mut sink) = ((&mut stream).take(remaining), tokio::io::sink());
+    let discard = tokio::io::copy(&mut body, &mut sink);
+    if let Err(e) = time::timeout_at(deadline, discard)
+        .await
+        .unwrap_or_else(|e| Err(e.into()))
+    {
+        error!("error reading request body: {e}");
+        return;
+    }
+
+    let res = server::route(s, endpoint, &req);
+    server::log(s, &req, &res);
+    send(
+        &mut stream,
+        req.http_version(),
+        res,
+        &s.config.write,
+        config.write_timeout,
+    )
+    .await;
+}
+
+// Reads until the end of the request head, returning None if it exceeds the configured limits.
+async fn read_head(stream: &mut TcpStream, s: &SharedData) -> std::io::Result<Option<Vec<u8>>> {
+    let max = s.config.limits.max_head_size();
+    let mut buf = Vec::new();
+    let mut chunk = [0; 4096];
+    loop {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            // Let the parser decide whether the truncated head is worth a response.
+            return Ok(Some(buf));
+        }
+
+        // Only search the newly read bytes, along with the end of the previous read.
+        let start = buf.len().saturating_sub(3);
+        buf.extend_from_slice(&chunk[..n]);
+        let tail = &buf[start..];
+        if tail.windows(2).any(|w| w == b"\n\n") || tail.windows(4).any(|w| w == b"\r\n\r\n") {
+            return Ok(Some(buf));
+        }
+        if buf.len() > max {
+            return Ok(None);
+        }
+    }
+}
+
+// Serializes and sends a response within the timeout, if any, then closes the connection.
+async fn send(
+    stream: &mut TcpStream,
+    version: http::Version,
+    res: Response,
+    config: &WriteConfig,
+    timeout: Option<Duration>,
+) {
+    let mut buf = Vec::new();
+    let result = match res.write_to(version, &mut buf, config) {
+        Ok(()) => {
+            let write = async {
+                stream.write_all(&buf).await?;
+                stream.shutdown().await
+            };
+            match timeout {
+                Some(timeout) => time::timeout(timeout, write)
+                    .await
+                    .unwrap_or_else(|e| Err(e.into())),
+                None => write.await,
+            }
+        }
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        error!("error sending metrics response: {e}");
+    }
+}
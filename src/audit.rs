@@ -0,0 +1,141 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use time::{format_description, OffsetDateTime};
+
+use crate::clock::Clock;
+
+/// The path the audit log is served at, if enabled with `Builder::audit_endpoint`.
+pub(crate) const AUDIT_PATH: &str = "/-/audit";
+
+/// The kind of administrative action recorded in the audit log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AuditKind {
+    /// The server started serving requests.
+    Start,
+    /// The server stopped serving requests.
+    Stop,
+    /// A request was rejected by the configured authentication.
+    AuthFailure,
+    /// A TLS certificate was reloaded from its files, or failed to.
+    TlsReload,
+}
+
+impl fmt::Display for AuditKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self {
+            AuditKind::Start => "start",
+            AuditKind::Stop => "stop",
+            AuditKind::AuthFailure => "auth_failure",
+            AuditKind::TlsReload => "tls_reload",
+        };
+        f.write_str(kind)
+    }
+}
+
+/// An administrative action recorded in the audit log.
+#[derive(Clone, Debug)]
+pub struct AuditEvent {
+    /// When the action happened.
+    pub time: SystemTime,
+    /// The kind of action.
+    pub kind: AuditKind,
+    /// A human readable description of the action, e.g. the rejected client's address.
+    pub detail: String,
+}
+
+impl fmt::Display for AuditEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let time = OffsetDateTime::from(self.time)
+            .format(&format_description::well_known::Rfc3339)
+            .unwrap_or_else(|_| "-".to_string());
+        write!(f, "{time} {} {}", self.kind, self.detail)
+    }
+}
+
+/// A bounded, in-memory log of the most recent administrative actions.
+pub(crate) struct AuditLog {
+    capacity: usize,
+    events: Mutex<VecDeque<AuditEvent>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl fmt::Debug for AuditLog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AuditLog")
+            .field("capacity", &self.capacity)
+            .field("events", &self.events)
+            .finish_non_exhaustive()
+    }
+}
+
+impl AuditLog {
+    /// Creates an audit log keeping at most `capacity` events, timestamped by the given clock.
+    pub(crate) fn new(capacity: usize, clock: Arc<dyn Clock>) -> Self {
+        AuditLog {
+            capacity: capacity.max(1),
+            events: Mutex::new(VecDeque::new()),
+            clock,
+        }
+    }
+
+    /// Records an event, discarding the oldest one if the log is full.
+    pub(crate) fn record(&self, kind: AuditKind, detail: String) {
+        let event = AuditEvent {
+            time: self.clock.now(),
+            kind,
+            detail,
+        };
+
+        let mut events = self.events.lock().unwrap();
+        if events.len() == self.capacity {
+            events.pop_front();
+        }
+        events.push_back(event);
+    }
+
+    /// Returns the recorded events, oldest first.
+    pub(crate) fn events(&self) -> Vec<AuditEvent> {
+        self.events.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Writes the recorded events as plain text, one per line.
+    pub(crate) fn to_text(&self) -> Vec<u8> {
+        self.events()
+            .iter()
+            .map(|e| format!("{e}\n"))
+            .collect::<String>()
+            .into_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SystemClock;
+
+    #[test]
+    fn test_audit_log() {
+        let log = AuditLog::new(2, Arc::new(SystemClock));
+        log.record(AuditKind::Start, "serving /metrics".to_string());
+        log.record(
+            AuditKind::AuthFailure,
+            "401 GET /metrics from 10.0.0.1".to_string(),
+        );
+        log.record(AuditKind::Stop, "stopped".to_string());
+
+        // Assert only the most recent events are kept, oldest first.
+        let events = log.events();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].kind, AuditKind::AuthFailure);
+        assert_eq!(events[1].kind, AuditKind::Stop);
+
+        let text = String::from_utf8(log.to_text()).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert!(lines[0].ends_with("Z auth_failure 401 GET /metrics from 10.0.0.1"));
+        assert!(lines[1].ends_with("Z stop stopped"));
+    }
+}
@@ -0,0 +1,847 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::prelude::{Engine, BASE64_STANDARD};
+use http::header::AUTHORIZATION;
+use http::{HeaderMap, HeaderValue, Method};
+use md5::Md5;
+use sha2::{Digest as _, Sha256};
+
+use crate::error::ServerError;
+use crate::request::Request;
+use crate::secret::Secret;
+
+// How long a Digest nonce remains valid after being issued.
+const NONCE_LIFETIME_SECS: u64 = 300;
+
+/// Decides whether a request is allowed to scrape metrics.
+///
+/// Implement this trait to plug in custom authentication, e.g. LDAP or JWT validation. The server
+/// handles sending the appropriate response for each `AuthDecision`.
+///
+/// Authentication can be configured for the whole server with `Builder::auth`, or for the path
+/// served by a single listener with `ListenerConfig::auth`.
+pub trait Authenticator: Send + Sync {
+    /// Authenticates the given request.
+    fn authenticate(&self, req: &RequestMeta) -> AuthDecision;
+
+    /// Returns a short name for the authentication scheme, logged when the server starts.
+    ///
+    /// This must never include credentials.
+    fn scheme(&self) -> &str {
+        "custom"
+    }
+}
+
+/// Closures taking the request and returning a decision can be used as authenticators directly,
+/// e.g. `Builder::auth(|req: &RequestMeta| ...)`.
+impl<F> Authenticator for F
+where
+    F: Fn(&RequestMeta) -> AuthDecision + Send + Sync,
+{
+    fn authenticate(&self, req: &RequestMeta) -> AuthDecision {
+        self(req)
+    }
+}
+
+/// Authentication by a function deciding whether to allow each request, see `Builder::auth_fn`.
+pub(crate) struct AuthFn<F>(pub(crate) F);
+
+impl<F> Authenticator for AuthFn<F>
+where
+    F: Fn(&RequestMeta) -> bool + Send + Sync,
+{
+    fn authenticate(&self, req: &RequestMeta) -> AuthDecision {
+        match (self.0)(req) {
+            true => AuthDecision::Allow,
+            false => AuthDecision::Forbidden,
+        }
+    }
+}
+
+/// The outcome of authenticating a request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AuthDecision {
+    /// The request is authenticated and metrics will be served.
+    Allow,
+    /// The request is missing valid credentials. A 401 response is sent with the given
+    /// `WWW-Authenticate` challenges.
+    Unauthorized(Vec<HeaderValue>),
+    /// The request presented credentials that are not allowed. A 403 response is sent.
+    Forbidden,
+}
+
+/// The parts of a request made available to an `Authenticator`.
+pub struct RequestMeta<'a> {
+    req: &'a Request,
+    time: SystemTime,
+}
+
+impl<'a> RequestMeta<'a> {
+    pub(crate) fn new(req: &'a Request, time: SystemTime) -> Self {
+        RequestMeta { req, time }
+    }
+
+    /// Returns the time the request is authenticated at, according to the clock set with
+    /// `Builder::clock`.
+    pub fn time(&self) -> SystemTime {
+        self.time
+    }
+
+    /// Returns the request method.
+    pub fn method(&self) -> &Method {
+        self.req.method()
+    }
+
+    /// Returns the raw request target, including any query string.
+    pub fn url(&self) -> &str {
+        self.req.url()
+    }
+
+    /// Returns the request headers.
+    pub fn headers(&self) -> &HeaderMap {
+        self.req.headers()
+    }
+
+    /// Returns the address of the client, if known.
+    ///
+    /// IPv4 clients of dual-stack listeners are reported with their IPv4 address, rather than
+    /// an IPv4-mapped IPv6 address, so they can be checked against IPv4 rules.
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        self.req.remote_addr()
+    }
+
+    // Returns the value of the Authorization header with the given scheme, without the scheme.
+    pub(crate) fn credentials(&self, scheme: &str) -> Option<&str> {
+        let header = self.headers().get(AUTHORIZATION)?.to_str().ok()?;
+        let (name, creds) = header.split_once(' ')?;
+        name.eq_ignore_ascii_case(scheme).then(|| creds.trim())
+    }
+}
+
+/// HTTP Basic authentication with a single set of credentials.
+#[derive(Clone)]
+pub struct BasicAuth {
+    username: String,
+    password: Password,
+}
+
+// The password required by `BasicAuth`.
+#[derive(Clone)]
+enum Password {
+    Plain(Secret),
+    // A bcrypt hash, and a digest of the last credentials verified against it, so that
+    // repeated scrapes don't each pay for bcrypt's deliberately slow verification.
+    Bcrypt(String, Arc<Mutex<Option<[u8; 32]>>>),
+}
+
+impl BasicAuth {
+    /// Requires HTTP Basic authentication with the given credentials.
+    pub fn new(username: &str, password: &str) -> Self {
+        BasicAuth {
+            username: username.to_string(),
+            password: Password::Plain(password.into()),
+        }
+    }
+
+    /// Requires HTTP Basic authentication with the given username and bcrypt password hash, as
+    /// used by the `basic_auth_users` of Prometheus web configs, so the plain text password
+    /// doesn't need to be stored with the application.
+    ///
+    /// Returns an error if the hash isn't a valid bcrypt hash.
+    pub fn with_hash(username: &str, password_hash: &str) -> Result<Self, ServerError> {
+        bcrypt::HashParts::from_str(password_hash)
+            .map_err(|e| ServerError::Create(format!("invalid password hash: {e}")))?;
+        Ok(BasicAuth {
+            username: username.to_string(),
+            password: Password::Bcrypt(password_hash.to_string(), Arc::default()),
+        })
+    }
+
+    /// Requires HTTP Basic authentication with the given username and the password contained in
+    /// a file.
+    ///
+    /// The file is re-read whenever it changes, allowing the password to be rotated without
+    /// restarting the server. Trailing whitespace is ignored.
+    pub fn from_file<P: AsRef<Path>>(username: &str, password: P) -> Result<Self, ServerError> {
+        Ok(BasicAuth {
+            username: username.to_string(),
+            password: Password::Plain(Secret::from_file(password)?),
+        })
+    }
+
+    // Checks the given base64 encoded "username:password" credentials.
+    fn verify(&self, creds: &str) -> bool {
+        let Ok(decoded) = BASE64_STANDARD.decode(creds) else {
+            return false;
+        };
+        let Some(i) = decoded.iter().position(|&b| b == b':') else {
+            return false;
+        };
+        let (username, password) = (&decoded[..i], &decoded[i + 1..]);
+
+        // Compare both parts to avoid leaking which one was wrong.
+        constant_time_eq(username, self.username.as_bytes()) & self.password.verify(password)
+    }
+}
+
+impl Password {
+    // Checks the given password.
+    fn verify(&self, password: &[u8]) -> bool {
+        match self {
+            Password::Plain(secret) => constant_time_eq(password, &secret.value()),
+            Password::Bcrypt(hash, verified) => {
+                let digest: [u8; 32] = Sha256::digest(password).into();
+                if verified
+                    .lock()
+                    .unwrap()
+                    .is_some_and(|v| constant_time_eq(&v, &digest))
+                {
+                    return true;
+                }
+
+                // Hashing is deliberately slow, so don't hold the lock and block other workers.
+                let ok = bcrypt::verify(password, hash).unwrap_or(false);
+                if ok {
+                    *verified.lock().unwrap() = Some(digest);
+                }
+                ok
+            }
+        }
+    }
+}
+
+impl Authenticator for BasicAuth {
+    fn authenticate(&self, req: &RequestMeta) -> AuthDecision {
+        match req.credentials("Basic") {
+            Some(creds) if self.verify(creds) => AuthDecision::Allow,
+            _ => AuthDecision::Unauthorized(vec![HeaderValue::from_static(
+                "Basic realm=\"metrics\", charset=\"UTF-8\"",
+            )]),
+        }
+    }
+
+    fn scheme(&self) -> &str {
+        "basic"
+    }
+}
+
+impl fmt::Debug for BasicAuth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Never print credentials.
+        f.debug_struct("BasicAuth")
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Bearer token authentication with a single static token.
+///
+/// Requests without a token are challenged with a 401 response, while requests presenting the
+/// wrong token are rejected with a 403 response.
+#[derive(Clone)]
+pub struct BearerAuth {
+    token: Token,
+}
+
+// The tokens accepted by `BearerAuth`.
+#[derive(Clone)]
+enum Token {
+    Static(Secret),
+    Validator(Arc<dyn Fn(&str) -> bool + Send + Sync>),
+}
+
+impl BearerAuth {
+    /// Requires the given bearer token in the `Authorization` header.
+    pub fn new(token: &str) -> Self {
+        BearerAuth {
+            token: Token::Static(token.into()),
+        }
+    }
+
+    /// Accepts the bearer tokens that the given function returns true for, e.g. to check tokens
+    /// against a set loaded at runtime or issued by another service.
+    ///
+    /// The function is called with the token of every request presenting one, so it should
+    /// compare secrets in constant time and avoid blocking for long.
+    pub fn with_validator<F>(validator: F) -> Self
+    where
+        F: Fn(&str) -> bool + Send + Sync + 'static,
+    {
+        BearerAuth {
+            token: Token::Validator(Arc::new(validator)),
+        }
+    }
+
+    /// Requires the bearer token contained in a file.
+    ///
+    /// The file is re-read whenever it changes, allowing the token to be rotated without
+    /// restarting the server. Trailing whitespace is ignored.
+    pub fn from_file<P: AsRef<Path>>(token: P) -> Result<Self, ServerError> {
+        Ok(BearerAuth {
+            token: Token::Static(Secret::from_file(token)?),
+        })
+    }
+}
+
+impl Authenticator for BearerAuth {
+    fn authenticate(&self, req: &RequestMeta) -> AuthDecision {
+        match req.credentials("Bearer") {
+            Some(token) if self.token.verify(token) => AuthDecision::Allow,
+            Some(_) => AuthDecision::Forbidden,
+            None => AuthDecision::Unauthorized(vec![HeaderValue::from_static(
+                "Bearer realm=\"metrics\"",
+            )]),
+        }
+    }
+
+    fn scheme(&self) -> &str {
+        "bearer"
+    }
+}
+
+impl Token {
+    // Checks the given token.
+    fn verify(&self, token: &str) -> bool {
+        match self {
+            Token::Static(secret) => constant_time_eq(token.as_bytes(), &secret.value()),
+            Token::Validator(validator) => validator(token),
+        }
+    }
+}
+
+impl fmt::Debug for BearerAuth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Never print credentials.
+        f.debug_struct("BearerAuth").finish_non_exhaustive()
+    }
+}
+
+/// HTTP Digest authentication with a single set of credentials, as described in RFC 7616.
+///
+/// Both the `SHA-256` and `MD5` algorithms are offered, and the password itself is never sent
+/// by clients. Only the password hashes are kept in memory.
+///
+/// Every challenge carries a unique nonce, and the highest nonce count seen for each nonce is
+/// remembered, so a captured `Authorization` header can't be replayed. Clones share this state.
+#[derive(Clone)]
+pub struct DigestAuth {
+    realm: String,
+    username: String,
+    ha1_sha256: String,
+    ha1_md5: String,
+    nonces: Arc<Nonces>,
+}
+
+// Issues nonces signed with a random key, and tracks the nonce counts used with them.
+struct Nonces {
+    key: [u8; 32],
+    next_id: AtomicU64,
+    // The highest nonce count accepted for each unexpired nonce.
+    counts: Mutex<HashMap<String, u64>>,
+}
+
+impl DigestAuth {
+    /// Requires HTTP Digest authentication with the given realm and credentials.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the operating system's random number generator is unavailable.
+    pub fn new(realm: &str, username: &str, password: &str) -> Self {
+        let a1 = format!("{username}:{realm}:{password}");
+        let mut key = [0; 32];
+        getrandom::fill(&mut key).expect("random number generator is unavailable");
+        DigestAuth {
+            realm: realm.to_string(),
+            username: username.to_string(),
+            ha1_sha256: Algorithm::Sha256.hash(&a1),
+            ha1_md5: Algorithm::Md5.hash(&a1),
+            nonces: Arc::new(Nonces {
+                key,
+                next_id: AtomicU64::new(0),
+                counts: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+}
+
+impl Authenticator for DigestAuth {
+    fn authenticate(&self, req: &RequestMeta) -> AuthDecision {
+        let params = req.credentials("Digest").map(parse_params);
+        match params.map(|p| self.verify(req, &p)) {
+            Some(Verified::Ok) => AuthDecision::Allow,
+            Some(Verified::Stale) => AuthDecision::Unauthorized(self.challenges(req, true)),
+            _ => AuthDecision::Unauthorized(self.challenges(req, false)),
+        }
+    }
+
+    fn scheme(&self) -> &str {
+        "digest"
+    }
+}
+
+impl fmt::Debug for DigestAuth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Never print credentials.
+        f.debug_struct("DigestAuth")
+            .field("realm", &self.realm)
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
+enum Verified {
+    Ok,
+    Stale,
+    Invalid,
+}
+
+impl DigestAuth {
+    // Verifies the parameters of a Digest Authorization header.
+    fn verify(&self, req: &RequestMeta, params: &HashMap<String, String>) -> Verified {
+        let param = |name: &str| params.get(name).map(String::as_str);
+
+        let (algorithm, ha1) = match param("algorithm").unwrap_or("MD5") {
+            a if a.eq_ignore_ascii_case("MD5") => (Algorithm::Md5, &self.ha1_md5),
+            a if a.eq_ignore_ascii_case("SHA-256") => (Algorithm::Sha256, &self.ha1_sha256),
+            _ => return Verified::Invalid,
+        };
+        let (Some(nonce), Some(nc), Some(cnonce), Some(response)) = (
+            param("nonce"),
+            param("nc"),
+            param("cnonce"),
+            param("response"),
+        ) else {
+            return Verified::Invalid;
+        };
+        if param("username") != Some(&self.username)
+            || param("realm") != Some(&self.realm)
+            || param("uri") != Some(req.url())
+            || param("qop") != Some("auth")
+        {
+            return Verified::Invalid;
+        }
+
+        let ha2 = algorithm.hash(&format!("{}:{}", req.method(), req.url()));
+        let expected = algorithm.hash(&format!("{ha1}:{nonce}:{nc}:{cnonce}:auth:{ha2}"));
+        if !constant_time_eq(response.as_bytes(), expected.as_bytes()) {
+            return Verified::Invalid;
+        }
+
+        // Only check the nonce once the client has proven it knows the password, so that
+        // clients holding an expired nonce are told to retry with a fresh one.
+        let now = unix_time(req.time());
+        match self.nonce_age(nonce, now) {
+            Some(age) if age <= NONCE_LIFETIME_SECS => {}
+            Some(_) => return Verified::Stale,
+            None => return Verified::Invalid,
+        }
+
+        // Reject nonce counts that were already used with this nonce, i.e. replayed requests.
+        let Ok(nc) = u64::from_str_radix(nc, 16) else {
+            return Verified::Invalid;
+        };
+        let mut counts = self.nonces.counts.lock().unwrap();
+        if counts.get(nonce).is_some_and(|&last| nc <= last) {
+            return Verified::Invalid;
+        }
+        counts.retain(|n, _| {
+            self.nonce_age(n, now)
+                .is_some_and(|a| a <= NONCE_LIFETIME_SECS)
+        });
+        counts.insert(nonce.to_string(), nc);
+        Verified::Ok
+    }
+
+    // Returns the challenges to send for each supported algorithm, strongest first.
+    fn challenges(&self, req: &RequestMeta, stale: bool) -> Vec<HeaderValue> {
+        let nonce = self.nonce(unix_time(req.time()));
+        [Algorithm::Sha256, Algorithm::Md5]
+            .iter()
+            .filter_map(|algorithm| {
+                let mut challenge = format!(
+                    "Digest realm=\"{}\", qop=\"auth\", algorithm={}, nonce=\"{}\"",
+                    self.realm,
+                    algorithm.name(),
+                    nonce
+                );
+                if stale {
+                    challenge.push_str(", stale=true");
+                }
+                HeaderValue::try_from(challenge).ok()
+            })
+            .collect()
+    }
+
+    // Creates a unique nonce embedding the time it was issued, signed with the server key.
+    fn nonce(&self, timestamp: u64) -> String {
+        let id = self.nonces.next_id.fetch_add(1, Ordering::Relaxed);
+        self.sign(&format!("{timestamp:x}.{id:x}"))
+    }
+
+    // Appends the truncated HMAC of the given nonce data.
+    fn sign(&self, data: &str) -> String {
+        let mac = hmac_sha256(&self.nonces.key, data.as_bytes());
+        let mac: String = mac[..16].iter().map(|b| format!("{b:02x}")).collect();
+        format!("{data}.{mac}")
+    }
+
+    // Returns the age in seconds at the given Unix time of a nonce issued by this server.
+    fn nonce_age(&self, nonce: &str, now: u64) -> Option<u64> {
+        let (data, _) = nonce.rsplit_once('.')?;
+        let (timestamp, _) = data.split_once('.')?;
+        let timestamp = u64::from_str_radix(timestamp, 16).ok()?;
+        if !constant_time_eq(nonce.as_bytes(), self.sign(data).as_bytes()) {
+            return None;
+        }
+
+        Some(now.saturating_sub(timestamp))
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Algorithm {
+    Md5,
+    Sha256,
+}
+
+impl Algorithm {
+    fn name(&self) -> &'static str {
+        match self {
+            Algorithm::Md5 => "MD5",
+            Algorithm::Sha256 => "SHA-256",
+        }
+    }
+
+    // Returns the lowercase hex encoded hash of the given data.
+    fn hash(&self, data: &str) -> String {
+        let hash = match self {
+            Algorithm::Md5 => Md5::digest(data).to_vec(),
+            Algorithm::Sha256 => Sha256::digest(data).to_vec(),
+        };
+        hash.iter().map(|b| format!("{b:02x}")).collect()
+    }
+}
+
+// Parses comma separated `key=value` or `key="value"` pairs.
+fn parse_params(s: &str) -> HashMap<String, String> {
+    let mut params = HashMap::new();
+    let mut rest = s.trim();
+    while let Some((key, value)) = rest.split_once('=') {
+        let key = key.trim().to_ascii_lowercase();
+        let value = value.trim_start();
+
+        let (value, remaining) = match value.strip_prefix('"') {
+            Some(quoted) => match quoted.split_once('"') {
+                Some((v, r)) => (v, r),
+                None => (quoted, ""),
+            },
+            None => value.split_once(',').map_or((value, ""), |(v, r)| (v, r)),
+        };
+        params.insert(key, value.trim().to_string());
+
+        rest = remaining.trim_start_matches(|c: char| c == ',' || c.is_whitespace());
+    }
+    params
+}
+
+// Computes the HMAC-SHA256 of a message, as described in RFC 2104. Keys must be no longer than
+// the 64 byte block size.
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let pad = |byte: u8| {
+        let mut pad = [byte; 64];
+        pad.iter_mut().zip(key).for_each(|(p, k)| *p ^= k);
+        pad
+    };
+    let inner = Sha256::new()
+        .chain_update(pad(0x36))
+        .chain_update(message)
+        .finalize();
+    Sha256::new()
+        .chain_update(pad(0x5c))
+        .chain_update(inner)
+        .finalize()
+        .into()
+}
+
+// Compares two byte slices in constant time with respect to their contents.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::request::Limits;
+
+    fn request(authorization: Option<&str>) -> Request {
+        let mut raw = "GET /dir/index.html HTTP/1.1\r\n".to_string();
+        if let Some(auth) = authorization {
+            raw.push_str(&format!("Authorization: {auth}\r\n"));
+        }
+        raw.push_str("\r\n");
+        Request::read(&mut raw.as_bytes(), None, &Limits::default()).unwrap()
+    }
+
+    fn authenticate<A: Authenticator>(auth: &A, authorization: Option<&str>) -> AuthDecision {
+        let req = request(authorization);
+        auth.authenticate(&RequestMeta::new(&req, SystemTime::now()))
+    }
+
+    #[test]
+    fn test_parse_params() {
+        let params = parse_params(r#"username="Mufasa", nc=00000001 ,qop=auth, uri="/a,b""#);
+        assert_eq!(params["username"], "Mufasa");
+        assert_eq!(params["nc"], "00000001");
+        assert_eq!(params["qop"], "auth");
+        assert_eq!(params["uri"], "/a,b");
+    }
+
+    #[test]
+    fn test_basic_with_hash() {
+        // The bcrypt hash of "pass", with the minimum cost to keep the test fast.
+        let hash = bcrypt::hash_with_salt("pass", 4, [0; 16])
+            .unwrap()
+            .to_string();
+        let auth = BasicAuth::with_hash("user", &hash).unwrap();
+
+        // "user:pass", twice to also check the cached digest.
+        for _ in 0..2 {
+            assert_eq!(
+                authenticate(&auth, Some("Basic dXNlcjpwYXNz")),
+                AuthDecision::Allow
+            );
+        }
+        // "user:wrong"
+        assert!(matches!(
+            authenticate(&auth, Some("Basic dXNlcjp3cm9uZw==")),
+            AuthDecision::Unauthorized(_)
+        ));
+        // "admin:pass"
+        assert!(matches!(
+            authenticate(&auth, Some("Basic YWRtaW46cGFzcw==")),
+            AuthDecision::Unauthorized(_)
+        ));
+
+        assert!(matches!(
+            BasicAuth::with_hash("user", "pass"),
+            Err(ServerError::Create(_))
+        ));
+    }
+
+    #[test]
+    fn test_basic() {
+        let auth = BasicAuth::new("user", "pass");
+        let challenge = HeaderValue::from_static("Basic realm=\"metrics\", charset=\"UTF-8\"");
+
+        // "user:pass"
+        assert_eq!(
+            authenticate(&auth, Some("Basic dXNlcjpwYXNz")),
+            AuthDecision::Allow
+        );
+        // "user:wrong"
+        assert_eq!(
+            authenticate(&auth, Some("Basic dXNlcjp3cm9uZw==")),
+            AuthDecision::Unauthorized(vec![challenge.clone()])
+        );
+        assert_eq!(
+            authenticate(&auth, None),
+            AuthDecision::Unauthorized(vec![challenge])
+        );
+    }
+
+    #[test]
+    fn test_bearer() {
+        let auth = BearerAuth::new("token");
+
+        assert_eq!(
+            authenticate(&auth, Some("Bearer token")),
+            AuthDecision::Allow
+        );
+        assert_eq!(
+            authenticate(&auth, Some("bearer token")),
+            AuthDecision::Allow
+        );
+        assert_eq!(
+            authenticate(&auth, Some("Bearer wrong")),
+            AuthDecision::Forbidden
+        );
+        assert_eq!(
+            authenticate(&auth, None),
+            AuthDecision::Unauthorized(vec![HeaderValue::from_static("Bearer realm=\"metrics\"")])
+        );
+    }
+
+    #[test]
+    fn test_bearer_with_validator() {
+        let auth = BearerAuth::with_validator(|token| token.starts_with("valid-"));
+
+        assert_eq!(
+            authenticate(&auth, Some("Bearer valid-1")),
+            AuthDecision::Allow
+        );
+        assert_eq!(
+            authenticate(&auth, Some("Bearer invalid")),
+            AuthDecision::Forbidden
+        );
+        assert!(matches!(
+            authenticate(&auth, None),
+            AuthDecision::Unauthorized(_)
+        ));
+    }
+
+    #[test]
+    fn test_fn() {
+        // "user:pass"
+        let auth = |req: &RequestMeta| match req.credentials("Basic") {
+            Some("dXNlcjpwYXNz") => AuthDecision::Allow,
+            _ => AuthDecision::Unauthorized(Vec::new()),
+        };
+        assert_eq!(
+            authenticate(&auth, Some("Basic dXNlcjpwYXNz")),
+            AuthDecision::Allow
+        );
+        assert_eq!(
+            authenticate(&auth, None),
+            AuthDecision::Unauthorized(Vec::new())
+        );
+
+        let auth = AuthFn(|req: &RequestMeta| req.headers().contains_key(AUTHORIZATION));
+        assert_eq!(
+            authenticate(&auth, Some("Basic dXNlcjpwYXNz")),
+            AuthDecision::Allow
+        );
+        assert_eq!(authenticate(&auth, None), AuthDecision::Forbidden);
+    }
+
+    #[test]
+    fn test_digest() {
+        // Example from RFC 7616 section 3.9.1, with a nonce issued by this server.
+        let auth = DigestAuth::new("http-auth@example.org", "Mufasa", "Circle of Life");
+        let cnonce = "f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ";
+
+        for algorithm in [Algorithm::Md5, Algorithm::Sha256] {
+            let nonce = auth.nonce(unix_time(SystemTime::now()));
+            let ha1 = algorithm.hash("Mufasa:http-auth@example.org:Circle of Life");
+            let ha2 = algorithm.hash("GET:/dir/index.html");
+            let response = algorithm.hash(&format!("{ha1}:{nonce}:00000001:{cnonce}:auth:{ha2}"));
+            let header = format!(
+                "Digest username=\"Mufasa\", realm=\"http-auth@example.org\", uri=\"/dir/index.html\", \
+                 algorithm={}, nonce=\"{nonce}\", nc=00000001, cnonce=\"{cnonce}\", qop=auth, \
+                 response=\"{response}\"",
+                algorithm.name()
+            );
+            assert_eq!(authenticate(&auth, Some(&header)), AuthDecision::Allow);
+
+            // Replayed requests.
+            assert_ne!(authenticate(&auth, Some(&header)), AuthDecision::Allow);
+
+            // Wrong password.
+            let header = header.replace(&response, &algorithm.hash("wrong"));
+            assert_ne!(authenticate(&auth, Some(&header)), AuthDecision::Allow);
+        }
+
+        // Missing credentials returns a challenge per algorithm.
+        let AuthDecision::Unauthorized(challenges) = authenticate(&auth, None) else {
+            panic!("expected challenges");
+        };
+        assert_eq!(challenges.len(), 2);
+        assert!(challenges[0]
+            .to_str()
+            .unwrap()
+            .contains("algorithm=SHA-256"));
+        assert!(challenges[1].to_str().unwrap().contains("algorithm=MD5"));
+    }
+
+    #[test]
+    fn test_digest_stale() {
+        let auth = DigestAuth::new("http-auth@example.org", "Mufasa", "Circle of Life");
+        let issued = SystemTime::now();
+        let nonce = auth.nonce(unix_time(issued));
+        let cnonce = "f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ";
+
+        let algorithm = Algorithm::Sha256;
+        let ha1 = algorithm.hash("Mufasa:http-auth@example.org:Circle of Life");
+        let ha2 = algorithm.hash("GET:/dir/index.html");
+        let response = algorithm.hash(&format!("{ha1}:{nonce}:00000001:{cnonce}:auth:{ha2}"));
+        let header = format!(
+            "Digest username=\"Mufasa\", realm=\"http-auth@example.org\", uri=\"/dir/index.html\", \
+             algorithm=SHA-256, nonce=\"{nonce}\", nc=00000001, cnonce=\"{cnonce}\", qop=auth, \
+             response=\"{response}\""
+        );
+        let req = request(Some(&header));
+
+        // Assert nonces expire according to the time the request is authenticated at.
+        let later = issued + Duration::from_secs(NONCE_LIFETIME_SECS + 1);
+        let AuthDecision::Unauthorized(challenges) =
+            auth.authenticate(&RequestMeta::new(&req, later))
+        else {
+            panic!("expected challenges");
+        };
+        assert!(challenges[0].to_str().unwrap().ends_with(", stale=true"));
+        assert_eq!(
+            auth.authenticate(&RequestMeta::new(&req, issued)),
+            AuthDecision::Allow
+        );
+    }
+
+    #[test]
+    fn test_digest_known_answer() {
+        // Expected responses from RFC 7616 section 3.9.1.
+        let nonce = "7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v";
+        let cnonce = "f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ";
+        for (algorithm, expected) in [
+            (Algorithm::Md5, "8ca523f5e9506fed4657c9700eebdbec"),
+            (
+                Algorithm::Sha256,
+                "753927fa0e85d155564e2e272a28d1802ca10daf4496794697cf8db5856cb6c1",
+            ),
+        ] {
+            let ha1 = algorithm.hash("Mufasa:http-auth@example.org:Circle of Life");
+            let ha2 = algorithm.hash("GET:/dir/index.html");
+            let response = algorithm.hash(&format!("{ha1}:{nonce}:00000001:{cnonce}:auth:{ha2}"));
+            assert_eq!(response, expected);
+        }
+    }
+
+    #[test]
+    fn test_hmac_sha256() {
+        // Test case 2 from RFC 4231.
+        let mac = hmac_sha256(b"Jefe", b"what do ya want for nothing?");
+        let mac: String = mac.iter().map(|b| format!("{b:02x}")).collect();
+        assert_eq!(
+            mac,
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_digest_nonce() {
+        let digest = DigestAuth::new("metrics", "user", "pass");
+
+        let now = unix_time(SystemTime::now());
+        assert_eq!(digest.nonce_age(&digest.nonce(now), now), Some(0));
+        assert_eq!(digest.nonce_age(&digest.nonce(now - 600), now), Some(600));
+
+        // Nonces are unique.
+        assert_ne!(digest.nonce(now), digest.nonce(now));
+
+        // Nonces not issued by this server are rejected.
+        assert_eq!(digest.nonce_age("5f5e100.0.0123456789abcdef", now), None);
+        let other = DigestAuth::new("metrics", "user", "pass");
+        assert_eq!(digest.nonce_age(&other.nonce(now), now), None);
+        assert_eq!(digest.nonce_age("invalid", now), None);
+    }
+}
@@ -0,0 +1,214 @@
+//! A tiny standalone exporter serving metrics from stdin, a file or a textfile directory.
+//!
+//! ```sh
+//! # Serve each payload written to stdin, terminated by a "# EOF" line or the end of input.
+//! ./collect.sh | metrics_server --address 0.0.0.0:9100 --stdin
+//!
+//! # Serve a file, read on every request.
+//! metrics_server --file /var/lib/metrics/app.prom
+//!
+//! # Serve every *.prom file in a directory, re-read every 15 seconds.
+//! metrics_server --textfile-dir /var/lib/node_exporter/textfile
+//! ```
+use std::env;
+use std::fs;
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use metrics_server::{MetricsPath, MetricsServer};
+
+const USAGE: &str = "usage: metrics_server [--address ADDR] [--path PATH] \
+(--stdin | --file PATH | --textfile-dir DIR [--interval SECS])";
+
+// Where payloads are read from.
+#[derive(Debug, PartialEq)]
+enum Source {
+    Stdin,
+    File(PathBuf),
+    TextfileDir(PathBuf, Duration),
+}
+
+#[derive(Debug, PartialEq)]
+struct Options {
+    address: String,
+    path: MetricsPath,
+    source: Source,
+}
+
+fn main() {
+    let opts = match parse_args(env::args().skip(1)) {
+        Ok(opts) => opts,
+        Err(e) => {
+            eprintln!("{e}\n{USAGE}");
+            process::exit(2);
+        }
+    };
+
+    let mut server = match MetricsServer::builder().address(&opts.address).build() {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("{e}");
+            process::exit(1);
+        }
+    };
+    if let Err(e) = server.try_serve_uri(opts.path) {
+        eprintln!("{e}");
+        process::exit(1);
+    }
+
+    match opts.source {
+        Source::Stdin => {
+            let mut payload = Vec::new();
+            for line in io::stdin().lock().split(b'\n') {
+                let line = line.unwrap_or_else(|e| fail(&e));
+                if line == b"# EOF" {
+                    server.update(std::mem::take(&mut payload));
+                    continue;
+                }
+                payload.extend_from_slice(&line);
+                payload.push(b'\n');
+            }
+            if !payload.is_empty() {
+                server.update(payload);
+            }
+        }
+        Source::File(path) => {
+            server.update_file(&path).unwrap_or_else(|e| fail(&e));
+        }
+        Source::TextfileDir(dir, interval) => loop {
+            match read_textfiles(&dir) {
+                Ok(payload) => {
+                    server.update(payload);
+                }
+                Err(e) => eprintln!("error reading {}: {e}", dir.display()),
+            }
+            thread::sleep(interval);
+        },
+    }
+
+    // Keep serving the last payload until the process is killed.
+    loop {
+        thread::park();
+    }
+}
+
+// Concatenates every `.prom` file in the directory, in name order.
+fn read_textfiles(dir: &Path) -> io::Result<Vec<u8>> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "prom") && path.is_file())
+        .collect();
+    paths.sort();
+
+    let mut payload = Vec::new();
+    for path in paths {
+        match fs::read(&path) {
+            Ok(data) => {
+                payload.extend_from_slice(&data);
+                if !payload.ends_with(b"\n") {
+                    payload.push(b'\n');
+                }
+            }
+            // Files may be removed between listing and reading them.
+            Err(e) => eprintln!("error reading {}: {e}", path.display()),
+        }
+    }
+    Ok(payload)
+}
+
+fn parse_args<I>(mut args: I) -> Result<Options, String>
+where
+    I: Iterator<Item = String>,
+{
+    let mut address = "localhost:9100".to_string();
+    let mut path = MetricsPath::default();
+    let mut source = None;
+    let mut interval = Duration::from_secs(15);
+
+    while let Some(flag) = args.next() {
+        let mut value = || args.next().ok_or(format!("missing value for {flag}"));
+        match flag.as_str() {
+            "--address" => address = value()?,
+            "--path" => path = value()?.parse().map_err(|e| format!("{e}"))?,
+            "--stdin" => source = Some(Source::Stdin),
+            "--file" => source = Some(Source::File(value()?.into())),
+            "--textfile-dir" => source = Some(Source::TextfileDir(value()?.into(), Duration::ZERO)),
+            "--interval" => {
+                let secs = value()?;
+                interval = secs
+                    .parse()
+                    .ok()
+                    .filter(|&s| s > 0)
+                    .map(Duration::from_secs)
+                    .ok_or(format!("invalid interval: {secs}"))?;
+            }
+            _ => return Err(format!("unknown flag: {flag}")),
+        }
+    }
+
+    let source = match source {
+        Some(Source::TextfileDir(dir, _)) => Source::TextfileDir(dir, interval),
+        Some(source) => source,
+        None => return Err("no source given".to_string()),
+    };
+    Ok(Options {
+        address,
+        path,
+        source,
+    })
+}
+
+fn fail(e: &io::Error) -> ! {
+    eprintln!("{e}");
+    process::exit(1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        parse_args(args.iter().map(|a| a.to_string()))
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let opts = parse(&["--textfile-dir", "/tmp", "--interval", "5"]).unwrap();
+        assert_eq!(opts.address, "localhost:9100");
+        assert_eq!(opts.path, MetricsPath::default());
+        assert_eq!(
+            opts.source,
+            Source::TextfileDir("/tmp".into(), Duration::from_secs(5))
+        );
+
+        let opts = parse(&["--address", "0.0.0.0:9000", "--stdin"]).unwrap();
+        assert_eq!(opts.address, "0.0.0.0:9000");
+        assert_eq!(opts.source, Source::Stdin);
+
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["--file"]).is_err());
+        assert!(parse(&["--stdin", "--interval", "0"]).is_err());
+        assert!(parse(&["--unknown"]).is_err());
+
+        let opts = parse(&["--path", "/custom", "--stdin"]).unwrap();
+        assert_eq!(opts.path.as_ref(), "/custom");
+        assert!(parse(&["--path", "no-slash", "--stdin"]).is_err());
+    }
+
+    #[test]
+    fn test_read_textfiles() {
+        let dir = env::temp_dir().join("metrics_server_test_read_textfiles");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("b.prom"), "b 2\n").unwrap();
+        fs::write(dir.join("a.prom"), "a 1").unwrap();
+        fs::write(dir.join("ignored.txt"), "ignored 1\n").unwrap();
+
+        // Assert files are concatenated in name order, each ending with a newline.
+        assert_eq!(read_textfiles(&dir).unwrap(), b"a 1\nb 2\n");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,913 @@
+use std::io;
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+#[cfg(feature = "tls")]
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use http::HeaderValue;
+use log::debug;
+
+use crate::addr;
+use crate::audit::AuditLog;
+use crate::auth::{AuthFn, Authenticator, BasicAuth, RequestMeta};
+use crate::clock::Clock;
+use crate::error::ServerError;
+use crate::filter::{Aggregation, Filters, Redaction, Rule};
+use crate::listener::{Listener, SocketConfig};
+use crate::normalize::CaseFolding;
+use crate::path::MetricsPath;
+use crate::server::{Config, MetricsServer};
+#[cfg(feature = "tls")]
+use crate::tls::{TlsOptions, TlsSource, TlsVersion};
+
+// The number of events kept in the audit log if enabled without a capacity.
+const DEFAULT_AUDIT_CAPACITY: usize = 256;
+
+/// A builder used to configure a `MetricsServer` before binding its listeners.
+#[derive(Default)]
+pub struct Builder {
+    primary: Option<ListenerConfig>,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsSource>,
+    #[cfg(feature = "tls")]
+    client_ca: Option<Vec<u8>>,
+    #[cfg(feature = "encrypted-keys")]
+    key_passphrase: Option<String>,
+    #[cfg(feature = "tls")]
+    tls_options: TlsOptions,
+    listeners: Vec<ListenerConfig>,
+    socket: SocketConfig,
+    filters: Filters,
+    content_type: Option<String>,
+    basic_auth: Option<(String, String)>,
+    allow: Vec<String>,
+    deny: Vec<String>,
+    audit: Option<usize>,
+    config: Config,
+}
+
+impl Builder {
+    /// Creates a new `Builder` with the default configuration.
+    pub fn new() -> Self {
+        Builder::default()
+    }
+
+    /// Sets the address that the server will listen on.
+    ///
+    /// If the address resolves to multiple socket addresses, the first one that can be bound is
+    /// used.
+    pub fn address<A>(mut self, addr: A) -> Self
+    where
+        A: ToSocketAddrs,
+    {
+        self.primary = Some(ListenerConfig::new(addr));
+        self
+    }
+
+    /// Serves requests on a listener the caller has already bound, instead of binding an address.
+    ///
+    /// See `ListenerConfig::from_listener`.
+    pub fn tcp_listener(mut self, listener: TcpListener) -> Self {
+        self.primary = Some(ListenerConfig::from_listener(listener));
+        self
+    }
+
+    /// Serve requests to the primary address over HTTPS using the given PEM encoded certificate
+    /// chain and private key.
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, certificate: Vec<u8>, private_key: Vec<u8>) -> Self {
+        self.tls = Some(TlsSource::Pem(certificate, private_key));
+        self
+    }
+
+    /// Serve requests to the primary address over HTTPS using the PEM encoded certificate chain
+    /// and private key at the given paths.
+    ///
+    /// The files are re-read whenever they change, so renewed certificates are picked up by the
+    /// running server.
+    #[cfg(feature = "tls")]
+    pub fn tls_files<P: AsRef<Path>>(mut self, certificate: P, private_key: P) -> Self {
+        self.tls = Some(TlsSource::Files(
+            certificate.as_ref().to_path_buf(),
+            private_key.as_ref().to_path_buf(),
+        ));
+        self
+    }
+
+    /// Serve requests to the primary address over HTTPS using the certificate chain and private
+    /// key in the given DER encoded PKCS#12 archive, e.g. a `.p12` or `.pfx` file, protected by
+    /// `passphrase`. Requires the `pkcs12` feature, which depends on OpenSSL.
+    ///
+    /// Returns an error from `build` if the archive can't be decrypted or doesn't contain both a
+    /// certificate and a private key.
+    #[cfg(feature = "pkcs12")]
+    pub fn tls_pkcs12(mut self, archive: Vec<u8>, passphrase: &str) -> Self {
+        self.tls = Some(TlsSource::Pkcs12(archive, passphrase.to_string()));
+        self
+    }
+
+    /// Decrypts the primary address's PEM encoded private key with `passphrase`, so encrypted keys
+    /// don't have to be decrypted on disk. Requires the `encrypted-keys` feature, which depends
+    /// on OpenSSL.
+    ///
+    /// Applies to keys given to `tls` and `tls_files`, including reloaded keys. Both PKCS#8 and
+    /// legacy OpenSSL encrypted keys are supported, and unencrypted keys are still accepted.
+    #[cfg(feature = "encrypted-keys")]
+    pub fn tls_key_passphrase(mut self, passphrase: &str) -> Self {
+        self.key_passphrase = Some(passphrase.to_string());
+        self
+    }
+
+    /// Sets the lowest TLS version negotiated by every HTTPS listener, e.g. `TlsVersion::Tls13`
+    /// to only accept TLS 1.3 clients. Both TLS 1.2 and 1.3 are accepted by default.
+    #[cfg(feature = "tls")]
+    pub fn tls_min_version(mut self, version: TlsVersion) -> Self {
+        self.tls_options.min_version = Some(version);
+        self
+    }
+
+    /// Restricts the cipher suites offered by every HTTPS listener to those with the given
+    /// IANA names, e.g. `TLS13_AES_256_GCM_SHA384` or `TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384`.
+    ///
+    /// Every suite supported by the `ring` crypto provider is offered by default. Returns an
+    /// error from `build` if a name is unknown, or no given suite supports the negotiated TLS
+    /// versions.
+    #[cfg(feature = "tls")]
+    pub fn tls_cipher_suites<I, S>(mut self, suites: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.tls_options.cipher_suites = suites.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Requires HTTPS clients of the primary address to present a certificate issued by one of
+    /// the CAs in the given PEM encoded bundle, rejecting other clients during the TLS handshake.
+    ///
+    /// Requires `tls` or `tls_files` to also be set. Note that `self_check` can't present a
+    /// client certificate, so it only verifies these listeners up to the server's certificate.
+    #[cfg(feature = "tls")]
+    pub fn tls_client_ca(mut self, ca: Vec<u8>) -> Self {
+        self.client_ca = Some(ca);
+        self
+    }
+
+    /// Requires clients to authenticate before metrics are served.
+    ///
+    /// This applies to every listener unless overridden with `ListenerConfig::auth` or
+    /// `ListenerConfig::without_auth`.
+    pub fn auth<A>(mut self, auth: A) -> Self
+    where
+        A: Authenticator + 'static,
+    {
+        self.config.auth = Some(Arc::new(auth));
+        self.basic_auth = None;
+        self
+    }
+
+    /// Requires requests to be allowed by the given function before metrics are served, e.g. to
+    /// check a custom header such as `X-Scrape-Key` against keys the application rotates.
+    ///
+    /// Requests the function returns false for receive a 403 response. To also challenge
+    /// clients with a 401 response, pass a closure returning an `AuthDecision` to `auth`
+    /// instead. This replaces any authentication set with `auth`.
+    pub fn auth_fn<F>(self, allow: F) -> Self
+    where
+        F: Fn(&RequestMeta) -> bool + Send + Sync + 'static,
+    {
+        self.auth(AuthFn(allow))
+    }
+
+    /// Requires HTTP Basic authentication with the given username and bcrypt password hash.
+    ///
+    /// This is a shortcut for `auth` with `BasicAuth::with_hash`, and returns an error from
+    /// `build` if the hash is invalid. Clients without valid credentials, such as scrapers
+    /// missing `basic_auth` in their scrape config, receive a 401 response with a
+    /// `WWW-Authenticate` challenge.
+    pub fn basic_auth(mut self, username: &str, password_hash: &str) -> Self {
+        self.basic_auth = Some((username.to_string(), password_hash.to_string()));
+        self
+    }
+
+    /// Only serves peers with addresses within the given range, in CIDR notation such as
+    /// `10.0.0.0/8` or `2001:db8::/32`, or a single address.
+    ///
+    /// This can be called multiple times to allow several ranges. Once any range is allowed,
+    /// every other peer receives a 403 response, including requests to `MetricsService` which
+    /// have no peer address. IPv4-mapped IPv6 peers are matched against IPv4 ranges. Returns an
+    /// error from `build` if the range is invalid.
+    pub fn allow_cidr(mut self, range: &str) -> Self {
+        self.allow.push(range.to_string());
+        self
+    }
+
+    /// Rejects peers with addresses within the given range with a 403 response, taking
+    /// precedence over `allow_cidr`.
+    ///
+    /// Accepts the same notation as `allow_cidr`, and can be called multiple times.
+    pub fn deny_cidr(mut self, range: &str) -> Self {
+        self.deny.push(range.to_string());
+        self
+    }
+
+    /// Adds an additional listener that serves the same metrics as the primary address.
+    ///
+    /// This can be used to serve HTTPS externally while serving plain HTTP on localhost.
+    pub fn listener(mut self, config: ListenerConfig) -> Self {
+        self.listeners.push(config);
+        self
+    }
+
+    /// Sets whether request paths are matched against the metrics path ignoring ASCII case.
+    ///
+    /// Paths are case sensitive by default. The configured path is never changed, so a
+    /// `MetricsPath` such as `/Metrics` keeps its case end-to-end, e.g. in logs, while also
+    /// serving requests to `/metrics`. Plain strings passed to `serve_uri` are still lowercased,
+    /// see `MetricsPath::legacy`.
+    pub fn case_insensitive_paths(mut self, enabled: bool) -> Self {
+        self.config.path_case = match enabled {
+            true => CaseFolding::Lowercase,
+            false => CaseFolding::Preserve,
+        };
+        self
+    }
+
+    /// Sets the maximum length of the listener's queue of pending connections.
+    ///
+    /// Defaults to 128.
+    pub fn backlog(mut self, backlog: i32) -> Self {
+        self.socket.backlog = backlog;
+        self
+    }
+
+    /// Accept connections in nonblocking mode, polling for new connections rather than blocking.
+    ///
+    /// This avoids the need to wake the listener with a loopback connection when stopping the
+    /// server.
+    pub fn nonblocking(mut self, nonblocking: bool) -> Self {
+        self.socket.nonblocking = nonblocking;
+        self
+    }
+
+    /// Sets the `SO_LINGER` option on accepted connections.
+    ///
+    /// A value of `Some(Duration::ZERO)` causes connections to be reset rather than gracefully
+    /// closed.
+    pub fn linger(mut self, linger: Option<Duration>) -> Self {
+        self.socket.linger = linger;
+        self
+    }
+
+    /// Sets the time allowed for a client to send a whole request, from when its connection is
+    /// accepted, after which the connection is closed.
+    ///
+    /// This applies to the request as a whole rather than to each read, so clients can't hold a
+    /// connection open by sending a few bytes at a time. Defaults to 5 seconds.
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.socket.read_timeout = timeout;
+        self
+    }
+
+    /// Sets the time allowed for a client to accept each write of a response, after which the
+    /// connection is closed, or `None` to wait indefinitely.
+    ///
+    /// This stops clients that never read their response from holding a connection open.
+    /// Responses served by `serve_async` must be written in full within the timeout. Defaults to
+    /// 30 seconds.
+    pub fn write_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.socket.write_timeout = timeout;
+        self
+    }
+
+    /// Sets the `SO_REUSEADDR` option on the listener, allowing a restarted server to bind its
+    /// address while connections from the previous process are still closing.
+    ///
+    /// Enabled by default, except on Windows where it allows other sockets to steal the address.
+    pub fn reuse_address(mut self, reuse: bool) -> Self {
+        self.socket.reuse_address = reuse;
+        self
+    }
+
+    /// Sets the `SO_REUSEPORT` option on the listener, allowing multiple processes to bind the
+    /// same address and have the kernel balance connections between them.
+    ///
+    /// Disabled by default.
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    pub fn reuse_port(mut self, reuse: bool) -> Self {
+        self.socket.reuse_port = reuse;
+        self
+    }
+
+    /// Sets the maximum length of a request URL, above which requests are rejected with
+    /// `414 URI Too Long`.
+    ///
+    /// Defaults to 2048.
+    pub fn max_url_length(mut self, length: usize) -> Self {
+        self.config.limits.max_url_length = length;
+        self
+    }
+
+    /// Sets the maximum number of request headers, above which requests are rejected with
+    /// `431 Request Header Fields Too Large`.
+    ///
+    /// Defaults to 64.
+    pub fn max_header_count(mut self, count: usize) -> Self {
+        self.config.limits.max_header_count = count;
+        self
+    }
+
+    /// Sets the maximum total size in bytes of the request headers, excluding line terminators,
+    /// above which requests are rejected with `431 Request Header Fields Too Large`.
+    ///
+    /// Headers are rejected as soon as they exceed the limit, so larger headers are never
+    /// buffered. Defaults to 64 KiB.
+    pub fn max_header_size(mut self, size: usize) -> Self {
+        self.config.limits.max_header_size = size;
+        self
+    }
+
+    /// Sets the maximum size in bytes of a request body, above which requests are rejected with
+    /// `413 Payload Too Large` before their body is read.
+    ///
+    /// Request bodies are never used, only read and discarded so the connection can respond.
+    /// Bodies must have a valid `Content-Length`, so chunked requests are rejected with
+    /// `501 Not Implemented`. Defaults to 64 KiB.
+    pub fn max_body_size(mut self, size: u64) -> Self {
+        self.config.limits.max_body_size = size;
+        self
+    }
+
+    /// Sets the capacity of the buffer used to coalesce response writes to each connection.
+    ///
+    /// Defaults to 8 KiB. Writes larger than the buffer bypass it.
+    pub fn write_buffer_size(mut self, size: usize) -> Self {
+        self.config.write.buffer_size = size;
+        self
+    }
+
+    /// Sets the maximum number of response body bytes written to a connection at once.
+    ///
+    /// By default the whole body is written at once. Smaller chunks can improve throughput for
+    /// large payloads over high-latency links, e.g. by limiting the size of TLS records. Chunks
+    /// bypass the write buffer, so they aren't coalesced into larger writes.
+    pub fn write_chunk_size(mut self, size: usize) -> Self {
+        self.config.write.chunk_size = Some(size);
+        self
+    }
+
+    /// Limits the server to `requests_per_second` requests, allowing bursts of up to `burst`.
+    ///
+    /// Requests over the limit are rejected with a `503 Service Unavailable` response, and a
+    /// `Retry-After` header set to when the next request will be allowed, rather than queueing
+    /// them. With `self_metrics`, rejected requests are counted by
+    /// `metrics_server_overloaded_requests_total`, so an overloaded server can be told apart from
+    /// one that is down. The limit applies to requests across all listeners.
+    pub fn rate_limit(mut self, requests_per_second: u32, burst: u32) -> Self {
+        self.config.rate_limit = Some((requests_per_second, burst));
+        self
+    }
+
+    /// Limits the number of connections open at once across all listeners.
+    ///
+    /// Connections over the limit are sent a `503 Service Unavailable` response and closed
+    /// immediately, without reading their request, so a flood of connections can't exhaust the
+    /// file descriptors of the application. A warning is logged when the limit is first reached,
+    /// and with `self_metrics`, rejected connections are counted by
+    /// `metrics_server_overloaded_requests_total`.
+    ///
+    /// Without the `tokio` feature, connections are accepted by a single thread per listener and
+    /// queued for the `workers`, so connections waiting for a worker count towards the limit.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.config.max_connections = Some(max);
+        self
+    }
+
+    /// Refuses requests made with HTTP/1.0, responding with `505 HTTP Version Not Supported`.
+    ///
+    /// With `self_metrics`, requests are counted by version in
+    /// `metrics_server_http_requests_by_version_total`, which can be used to find clients still
+    /// using HTTP/1.0 before refusing them.
+    pub fn refuse_http10(mut self, refuse: bool) -> Self {
+        self.config.refuse_http10 = refuse;
+        self
+    }
+
+    /// Sets the clock used by features that depend on the current time, such as `rate_limit`,
+    /// the `Last-Modified` time of updates, the timestamps of sections, audit events and the
+    /// access log and the expiry of `DigestAuth` nonces. Socket read and write timeouts, and the
+    /// timeout of `stop_graceful`, always use the system clock.
+    ///
+    /// Defaults to `SystemClock`. See `MockClock` for testing time-dependent behaviour without
+    /// sleeping.
+    pub fn clock<C>(mut self, clock: C) -> Self
+    where
+        C: Clock + 'static,
+    {
+        self.config.clock = Arc::new(clock);
+        self
+    }
+
+    /// Sets whether requests sent with `Expect: 100-continue` are told to continue sending their
+    /// body, or rejected immediately with `417 Expectation Failed`.
+    ///
+    /// Defaults to true.
+    pub fn expect_continue(mut self, expect_continue: bool) -> Self {
+        self.config.expect_continue = expect_continue;
+        self
+    }
+
+    /// Sets the number of worker threads serving requests on each listener, defaulting to 1.
+    ///
+    /// Requests are otherwise handled one at a time, so a single slow client delays every other
+    /// scraper of the same listener.
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.config.workers = workers.max(1);
+        self
+    }
+
+    /// Stores payloads in memory-mapped files created in the given directory, rather than on the
+    /// heap.
+    ///
+    /// This suits very large payloads, as the OS can page the data out while it isn't being
+    /// served. Each update is written to a new, unlinked file, so requests reading the previous
+    /// payload are unaffected. If the file can't be created, the payload is kept on the heap.
+    #[cfg(unix)]
+    pub fn mmap<P: Into<std::path::PathBuf>>(mut self, dir: P) -> Self {
+        self.config.mmap_dir = Some(dir.into());
+        self
+    }
+
+    /// Sets whether metrics responses include an `X-Content-Checksum` header.
+    ///
+    /// The checksum is an XXH3 64-bit hash of the payload, computed once per update, allowing
+    /// downstream pipelines to detect corruption or skip identical payloads cheaply.
+    pub fn checksum(mut self, checksum: bool) -> Self {
+        self.config.checksum = checksum;
+        self
+    }
+
+    /// Sets the Content-Type header sent with metrics responses.
+    ///
+    /// Defaults to `DEFAULT_CONTENT_TYPE`, the Prometheus text exposition format. Payloads in the
+    /// OpenMetrics format should use `application/openmetrics-text; version=1.0.0; charset=utf-8`,
+    /// or be published alongside the text format with `MetricsServer::update_openmetrics`.
+    /// Values that aren't valid header values are rejected by `build`.
+    pub fn content_type(mut self, content_type: &str) -> Self {
+        self.content_type = Some(content_type.to_string());
+        self
+    }
+
+    /// Sets whether updates identical to the data currently served are skipped.
+    ///
+    /// Each update is hashed and, if unchanged, the payload isn't transformed, paginated or
+    /// swapped, saving work for producers that republish the same data on a timer. Transforms
+    /// that add changing data, such as `tokio_runtime_metrics`, are therefore only refreshed when
+    /// the data itself changes.
+    pub fn deduplicate_updates(mut self, deduplicate: bool) -> Self {
+        self.config.deduplicate = deduplicate;
+        self
+    }
+
+    /// Records administrative actions, such as starting and stopping the server, authentication
+    /// failures and TLS certificate reloads, in an in-memory log of at most `capacity` events.
+    ///
+    /// The log is read with `MetricsServer::audit_events`, or served with `audit_endpoint`.
+    pub fn audit(mut self, capacity: usize) -> Self {
+        self.audit = Some(capacity);
+        self
+    }
+
+    /// Sets whether the audit log is served as plain text at `/-/audit`, requiring the same
+    /// authentication as the metrics. Enables the audit log with a capacity of 256 events, unless
+    /// set with `audit`.
+    pub fn audit_endpoint(mut self, enabled: bool) -> Self {
+        self.config.audit_endpoint = enabled;
+        if enabled && self.audit.is_none() {
+            self = self.audit(DEFAULT_AUDIT_CAPACITY);
+        }
+        self
+    }
+
+    /// Sets whether the variables set with `MetricsServer::set_var` are served as a JSON object
+    /// at `/debug/vars`, like Go's `expvar` package, requiring the same authentication as the
+    /// metrics.
+    pub fn debug_vars(mut self, enabled: bool) -> Self {
+        self.config.debug_vars = enabled;
+        self
+    }
+
+    /// Sets the order in which sections published with `update_section` are served.
+    ///
+    /// The named sections are served first, in the given order, followed by any others in
+    /// name order.
+    pub fn section_order<I, S>(mut self, order: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.config.sections.order = order.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Sets whether each section is preceded by a `# --- section: name ---` comment, making it
+    /// easy to tell which producer published a metric when debugging.
+    pub fn section_headers(mut self, headers: bool) -> Self {
+        self.config.sections.headers = headers;
+        self
+    }
+
+    /// Sets whether the time each section was last updated is served, as the
+    /// `metrics_section_last_update_timestamp_seconds{section="..."}` gauge.
+    ///
+    /// This shows which producer stopped publishing, rather than only that the payload is stale.
+    pub fn section_timestamps(mut self, timestamps: bool) -> Self {
+        self.config.sections.timestamps = timestamps;
+        self
+    }
+
+    /// Redacts the values of the given label before metrics are served, so accidental PII such
+    /// as email or IP addresses doesn't leave the process.
+    ///
+    /// Redaction is applied to payloads in the Prometheus text exposition format when they are
+    /// updated. Other payloads are served unchanged.
+    pub fn redact_label(mut self, label: &str, redaction: Redaction) -> Self {
+        self.filters.redactions.insert(label.to_string(), redaction);
+        self
+    }
+
+    /// Splits the metrics into pages of at most `max_size` bytes, served at `?page=1`, `?page=2`
+    /// and so on, to stay under proxy body size limits.
+    ///
+    /// Metric families are never split across pages. An index describing each page is served at
+    /// `?page=index`, and every response includes an `X-Metrics-Page-Count` header. Requests
+    /// without a page are still served the whole payload.
+    pub fn paginate(mut self, max_size: usize) -> Self {
+        self.config.page_size = Some(max_size);
+        self
+    }
+
+    /// Adds a rule to keep or drop samples before metrics are served, letting operators suppress
+    /// noisy metric families without changing application code.
+    ///
+    /// Rules are applied in order to payloads in the Prometheus text exposition format when they
+    /// are updated, and a sample is only served if every rule keeps it.
+    pub fn rule(mut self, rule: Rule) -> Self {
+        self.filters.rules.push(rule);
+        self
+    }
+
+    /// Adds an aggregation that sums samples over some of their labels before metrics are served,
+    /// reducing cardinality before the payload leaves the process.
+    ///
+    /// Aggregations are applied to payloads in the Prometheus text exposition format when they
+    /// are updated, after any rules and redaction. Each sample is aggregated by the first
+    /// matching aggregation only.
+    pub fn aggregate(mut self, aggregation: Aggregation) -> Self {
+        self.filters.aggregations.push(aggregation);
+        self
+    }
+
+    /// Adds a transformation applied to the payload before it is served, e.g. to inject labels.
+    ///
+    /// Transforms are applied in order each time the metrics are updated, rather than on every
+    /// request. Built-in filters such as rules, redaction and aggregation are applied first.
+    pub fn transform<F>(mut self, transform: F) -> Self
+    where
+        F: Fn(Vec<u8>) -> Vec<u8> + Send + Sync + 'static,
+    {
+        self.config.transforms.push(Arc::new(transform));
+        self
+    }
+
+    /// Serves the metrics as a JSON document to clients preferring `application/json` in their
+    /// `Accept` header, and at the metrics path followed by `.json`, e.g. `/metrics.json`.
+    ///
+    /// This is intended for dashboards that don't understand the Prometheus text format. The
+    /// document is rendered from the text payload on each request, listing every sample with
+    /// its labels, value, optional timestamp, and its family's help text and type if declared.
+    pub fn json(mut self, enabled: bool) -> Self {
+        self.config.json = enabled;
+        self
+    }
+
+    /// Appends metrics describing the server itself to the payload, such as
+    /// `metrics_server_http_requests_total` counting the responses sent by status code.
+    ///
+    /// Operators can alert on rising 401 or 404 rates to find misconfigured scrapers. Like other
+    /// transforms, the metrics are added each time the metrics are updated, after any other
+    /// transforms, so they report the counts as of the latest update.
+    pub fn self_metrics(mut self, enabled: bool) -> Self {
+        self.config.self_metrics = enabled;
+        self
+    }
+
+    /// Checks that every listener responds once the server starts serving.
+    ///
+    /// `MetricsServer::try_serve_uri` sends a request to each listener over loopback, completing
+    /// a TLS handshake first for HTTPS listeners, and stops the server again, returning
+    /// `ServerError::SelfCheck`, if a listener can't be reached or responds with a server error.
+    /// This catches problems such as expired certificates or broken chains at startup, rather
+    /// than on the first scrape. The presented chain is trusted up to its last certificate, and
+    /// isn't checked against the listener's host name.
+    pub fn self_check(mut self, enabled: bool) -> Self {
+        self.config.self_check = enabled;
+        self
+    }
+
+    /// Appends metrics describing the given tokio runtime, such as its number of workers and
+    /// queue depth, to the payload. Requires the `tokio` feature.
+    ///
+    /// The metrics are sampled each time the metrics are updated, after any other transforms.
+    /// Metrics that tokio only provides with `--cfg tokio_unstable`, such as budget exhaustion,
+    /// are included when the application is built with it.
+    #[cfg(feature = "tokio")]
+    pub fn tokio_runtime_metrics(mut self, handle: tokio::runtime::Handle) -> Self {
+        self.config.runtimes.push(handle);
+        self
+    }
+
+    /// Binds all listeners and creates an empty `MetricsServer`.
+    ///
+    /// The server will not handle requests until `serve` or `serve_uri` is called.
+    pub fn build(mut self) -> Result<MetricsServer, ServerError> {
+        #[allow(unused_mut)]
+        let mut configs: Vec<ListenerConfig> = self.primary.take().into_iter().collect();
+        #[cfg(feature = "tls")]
+        if let Some(primary) = configs.first_mut() {
+            primary.tls = self.tls.take().or(primary.tls.take());
+            primary.client_ca = self.client_ca.take().or(primary.client_ca.take());
+        }
+        #[cfg(feature = "encrypted-keys")]
+        if let Some(primary) = configs.first_mut() {
+            primary.key_passphrase = self.key_passphrase.take().or(primary.key_passphrase.take());
+        }
+        configs.append(&mut self.listeners);
+        #[cfg(feature = "tls")]
+        for config in &mut configs {
+            config.tls_options = self.tls_options.clone();
+        }
+
+        if configs.is_empty() {
+            return Err(ServerError::Create(
+                "no listen address configured".to_string(),
+            ));
+        }
+
+        self.create_audit_log();
+        let listeners = configs
+            .into_iter()
+            .map(|c| c.bind(&self.socket, self.config.audit.as_ref()))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(MetricsServer::from_parts(listeners, self.into_config()?))
+    }
+
+    /// Creates an empty `MetricsService` that can be mounted in an existing HTTP server, without
+    /// binding any listeners. Requires the `tower` feature.
+    ///
+    /// Listeners can't be configured for a service, so configuring an address is an error.
+    #[cfg(feature = "tower")]
+    pub fn build_service(self) -> Result<crate::MetricsService, ServerError> {
+        if self.primary.is_some() || !self.listeners.is_empty() {
+            return Err(ServerError::Create(
+                "listen addresses can't be configured for a service".to_string(),
+            ));
+        }
+
+        let server = MetricsServer::from_parts(Vec::new(), self.into_config()?);
+        Ok(server.service())
+    }
+
+    // Creates the audit log, if enabled, once the clock timestamping its events is known.
+    fn create_audit_log(&mut self) {
+        let clock = &self.config.clock;
+        self.config.audit = self
+            .audit
+            .map(|capacity| Arc::new(AuditLog::new(capacity, Arc::clone(clock))));
+    }
+
+    // Completes the server-wide settings, validating the content type and adding transforms for
+    // the configured filters.
+    fn into_config(mut self) -> Result<Config, ServerError> {
+        if self.config.audit.is_none() {
+            self.create_audit_log();
+        }
+        if let Some(content_type) = self.content_type {
+            self.config.content_type = HeaderValue::try_from(content_type)
+                .map_err(|e| ServerError::Create(format!("invalid content type: {e}")))?;
+        }
+        if let Some((username, password_hash)) = self.basic_auth {
+            let auth = BasicAuth::with_hash(&username, &password_hash)?;
+            self.config.auth = Some(Arc::new(auth));
+        }
+        for range in &self.allow {
+            self.config.access.allow.push(range.parse()?);
+        }
+        for range in &self.deny {
+            self.config.access.deny.push(range.parse()?);
+        }
+        if !self.filters.is_empty() {
+            let filters = self.filters;
+            let transform = Arc::new(move |data| filters.apply(data));
+            self.config.transforms.insert(0, transform);
+        }
+        #[cfg(feature = "tokio")]
+        for handle in std::mem::take(&mut self.config.runtimes) {
+            let transform = Arc::new(move |data| crate::runtime::append_metrics(&handle, data));
+            self.config.transforms.push(transform);
+        }
+        Ok(self.config)
+    }
+}
+
+/// The configuration of a single listener added to a `Builder`.
+///
+/// Settings configured on a listener take precedence over those configured for the whole server,
+/// e.g. a listener path overrides the path given to `MetricsServer::serve_uri`, and listener
+/// authentication overrides `Builder::auth`.
+pub struct ListenerConfig {
+    socket: Socket,
+    path: Option<String>,
+    auth: Option<Option<Arc<dyn Authenticator>>>,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsSource>,
+    #[cfg(feature = "tls")]
+    client_ca: Option<Vec<u8>>,
+    #[cfg(feature = "encrypted-keys")]
+    key_passphrase: Option<String>,
+    #[cfg(feature = "tls")]
+    tls_options: TlsOptions,
+}
+
+// Where a listener's socket comes from.
+enum Socket {
+    // Addresses to bind a new socket to.
+    Addrs(io::Result<Vec<SocketAddr>>),
+    // A socket bound by the caller.
+    Listener(TcpListener),
+}
+
+impl ListenerConfig {
+    /// Creates a plain HTTP listener configuration for the given address.
+    pub fn new<A>(addr: A) -> Self
+    where
+        A: ToSocketAddrs,
+    {
+        ListenerConfig::with_socket(Socket::Addrs(addr.to_socket_addrs().map(|a| a.collect())))
+    }
+
+    /// Creates a plain HTTP listener configuration for a listener the caller has already bound,
+    /// e.g. with custom socket options or on a privileged port before dropping privileges.
+    ///
+    /// Socket options such as the backlog are left as configured by the caller.
+    pub fn from_listener(listener: TcpListener) -> Self {
+        ListenerConfig::with_socket(Socket::Listener(listener))
+    }
+
+    fn with_socket(socket: Socket) -> Self {
+        ListenerConfig {
+            socket,
+            path: None,
+            auth: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+            #[cfg(feature = "tls")]
+            client_ca: None,
+            #[cfg(feature = "encrypted-keys")]
+            key_passphrase: None,
+            #[cfg(feature = "tls")]
+            tls_options: TlsOptions::default(),
+        }
+    }
+
+    /// Requires clients of this listener to authenticate, overriding `Builder::auth`.
+    pub fn auth<A>(mut self, auth: A) -> Self
+    where
+        A: Authenticator + 'static,
+    {
+        self.auth = Some(Some(Arc::new(auth)));
+        self
+    }
+
+    /// Serves this listener without authentication, even if `Builder::auth` is configured.
+    pub fn without_auth(mut self) -> Self {
+        self.auth = Some(None);
+        self
+    }
+
+    /// Serve metrics on a specific URL path for this listener only.
+    ///
+    /// Plain strings are converted with `MetricsPath::legacy`, so invalid paths fall back to the
+    /// default metrics path. Pass a `MetricsPath` to use the path exactly as validated.
+    pub fn path<P>(mut self, path: P) -> Self
+    where
+        P: Into<MetricsPath>,
+    {
+        self.path = Some(path.into().into_string());
+        self
+    }
+
+    /// Serve requests to this listener over HTTPS using the given PEM encoded certificate chain
+    /// and private key.
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, certificate: Vec<u8>, private_key: Vec<u8>) -> Self {
+        self.tls = Some(TlsSource::Pem(certificate, private_key));
+        self
+    }
+
+    /// Serve requests to this listener over HTTPS using the PEM encoded certificate chain and
+    /// private key at the given paths.
+    ///
+    /// See `Builder::tls_files`.
+    #[cfg(feature = "tls")]
+    pub fn tls_files<P: AsRef<Path>>(mut self, certificate: P, private_key: P) -> Self {
+        self.tls = Some(TlsSource::Files(
+            certificate.as_ref().to_path_buf(),
+            private_key.as_ref().to_path_buf(),
+        ));
+        self
+    }
+
+    /// Serve requests to this listener over HTTPS using the certificate chain and private key in
+    /// the given PKCS#12 archive, protected by `passphrase`.
+    ///
+    /// See `Builder::tls_pkcs12`.
+    #[cfg(feature = "pkcs12")]
+    pub fn tls_pkcs12(mut self, archive: Vec<u8>, passphrase: &str) -> Self {
+        self.tls = Some(TlsSource::Pkcs12(archive, passphrase.to_string()));
+        self
+    }
+
+    /// Decrypts this listener's PEM encoded private key with `passphrase`.
+    ///
+    /// See `Builder::tls_key_passphrase`.
+    #[cfg(feature = "encrypted-keys")]
+    pub fn tls_key_passphrase(mut self, passphrase: &str) -> Self {
+        self.key_passphrase = Some(passphrase.to_string());
+        self
+    }
+
+    /// Requires HTTPS clients of this listener to present a certificate issued by one of the CAs
+    /// in the given PEM encoded bundle. See `Builder::tls_client_ca`.
+    #[cfg(feature = "tls")]
+    pub fn tls_client_ca(mut self, ca: Vec<u8>) -> Self {
+        self.client_ca = Some(ca);
+        self
+    }
+
+    // Binds a listener with the given socket options.
+    #[cfg_attr(not(feature = "tls"), allow(unused_variables))]
+    fn bind(
+        self,
+        socket: &SocketConfig,
+        audit: Option<&Arc<AuditLog>>,
+    ) -> Result<Listener, ServerError> {
+        // Parse TLS config before binding so invalid credentials don't leave a socket open.
+        #[cfg(feature = "encrypted-keys")]
+        let passphrase = self.key_passphrase;
+        #[cfg(all(feature = "tls", not(feature = "encrypted-keys")))]
+        let passphrase = None;
+        #[cfg(feature = "tls")]
+        let tls = match (self.tls, self.client_ca) {
+            (Some(source), client_ca) => {
+                let tls = crate::tls::server_config(
+                    source,
+                    client_ca.as_deref(),
+                    passphrase,
+                    &self.tls_options,
+                    audit.cloned(),
+                )?;
+                Some((tls, client_ca.is_some()))
+            }
+            (None, Some(_)) => {
+                return Err(ServerError::Create(
+                    "client certificates require TLS".to_string(),
+                ))
+            }
+            (None, None) => None,
+        };
+
+        let listener = match self.socket {
+            Socket::Addrs(addrs) => addrs.and_then(|addrs| Listener::bind(&addrs, socket.clone())),
+            Socket::Listener(listener) => Listener::from_std(listener, socket.clone()),
+        };
+        let listener = listener
+            .map_err(|e| ServerError::Create(e.to_string()))?
+            .with_path(self.path)
+            .with_auth(self.auth);
+        debug!(
+            "metrics server listening on {}",
+            addr::display(&listener.local_addr())
+        );
+
+        #[cfg(feature = "tls")]
+        let listener = match tls {
+            Some((tls, client_auth)) => listener.with_tls(tls, client_auth),
+            None => listener,
+        };
+
+        Ok(listener)
+    }
+}
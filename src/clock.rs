@@ -0,0 +1,112 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+/// A source of the current time for features that depend on it, such as rate limiting, the
+/// `Last-Modified` time of updates and the timestamps of sections and audit events.
+///
+/// The default `SystemClock` reads the system clocks. Tests can configure a `MockClock` with
+/// `Builder::clock` instead, advancing time deterministically rather than sleeping. Socket
+/// timeouts always use the system clocks.
+pub trait Clock: Send + Sync {
+    /// Returns the current wall-clock time.
+    fn now(&self) -> SystemTime;
+
+    /// Returns the current monotonic time, used to measure elapsed durations.
+    fn instant(&self) -> Instant;
+}
+
+/// The `Clock` reading the system clocks.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A `Clock` that only moves when advanced, for deterministic tests.
+///
+/// Clones share the same time, so a clone kept by a test can advance the clock given to a
+/// `Builder`.
+///
+/// ```rust
+/// use std::time::{Duration, SystemTime};
+/// use metrics_server::{MetricsServer, MockClock};
+///
+/// let clock = MockClock::new(SystemTime::UNIX_EPOCH);
+/// let server = MetricsServer::builder()
+///     .address("127.0.0.1:0")
+///     .clock(clock.clone())
+///     .rate_limit(1, 1)
+///     .build()?;
+///
+/// // Refill the rate limit without sleeping.
+/// clock.advance(Duration::from_secs(1));
+/// # Ok::<(), metrics_server::ServerError>(())
+/// ```
+#[derive(Clone)]
+pub struct MockClock {
+    state: Arc<Mutex<(SystemTime, Instant)>>,
+}
+
+impl MockClock {
+    /// Creates a clock stopped at the given wall-clock time.
+    pub fn new(now: SystemTime) -> Self {
+        MockClock {
+            state: Arc::new(Mutex::new((now, Instant::now()))),
+        }
+    }
+
+    /// Moves the clock forward by the given duration.
+    pub fn advance(&self, duration: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.0 += duration;
+        state.1 += duration;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> SystemTime {
+        self.state.lock().unwrap().0
+    }
+
+    fn instant(&self) -> Instant {
+        self.state.lock().unwrap().1
+    }
+}
+
+impl fmt::Debug for MockClock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MockClock")
+            .field("now", &self.now())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mock_clock() {
+        let clock = MockClock::new(SystemTime::UNIX_EPOCH);
+        let shared = clock.clone();
+        let start = clock.instant();
+        assert_eq!(clock.now(), SystemTime::UNIX_EPOCH);
+
+        // Assert clones advance together, and only when advanced.
+        shared.advance(Duration::from_secs(90));
+        assert_eq!(
+            clock.now(),
+            SystemTime::UNIX_EPOCH + Duration::from_secs(90)
+        );
+        assert_eq!(clock.instant() - start, Duration::from_secs(90));
+        assert_eq!(clock.instant(), shared.instant());
+    }
+}
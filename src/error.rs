@@ -8,6 +8,12 @@ pub enum ServerError {
     Create(String),
     /// Represents an error encountered while stopping the server.
     Stop(String),
+    /// The server is already serving requests, to the contained URL path.
+    AlreadyRunning(String),
+    /// Represents an invalid URL path passed to `MetricsPath::new`.
+    InvalidPath(String),
+    /// A listener failed the check enabled with `Builder::self_check`.
+    SelfCheck(String),
 }
 
 impl fmt::Display for ServerError {
@@ -15,6 +21,11 @@ impl fmt::Display for ServerError {
         match self {
             ServerError::Create(s) => write!(f, "error creating metrics server: {}", s),
             ServerError::Stop(s) => write!(f, "error stopping metrics server: {}", s),
+            ServerError::AlreadyRunning(s) => {
+                write!(f, "metrics server already running, serving {}", s)
+            }
+            ServerError::InvalidPath(s) => write!(f, "invalid metrics path {}", s),
+            ServerError::SelfCheck(s) => write!(f, "metrics server self-check failed: {}", s),
         }
     }
 }
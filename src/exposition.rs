@@ -0,0 +1,645 @@
+use std::borrow::Cow;
+use std::error::Error;
+use std::fmt::{self, Write};
+use std::ops::Range;
+
+// The metric types allowed by `# TYPE` lines, in the text and OpenMetrics formats.
+const METRIC_TYPES: [&str; 9] = [
+    "counter",
+    "gauge",
+    "histogram",
+    "summary",
+    "untyped",
+    "unknown",
+    "gaugehistogram",
+    "stateset",
+    "info",
+];
+
+/// A single line of the Prometheus text exposition format.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Line<'a> {
+    /// A sample, e.g. `http_requests_total{code="200"} 1027 1395066363000`.
+    Sample(Sample<'a>),
+    /// A comment, blank line, or any line that couldn't be parsed, which is kept verbatim.
+    Other(&'a str),
+}
+
+/// A parsed sample line.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Sample<'a> {
+    pub(crate) name: &'a str,
+    pub(crate) labels: Vec<(&'a str, Cow<'a, str>)>,
+    /// The sample value and optional timestamp.
+    pub(crate) value: &'a str,
+}
+
+impl<'a> Line<'a> {
+    /// Parses a single line, without its trailing newline.
+    pub(crate) fn parse(line: &'a str) -> Self {
+        match Sample::parse(line) {
+            Some(sample) => Line::Sample(sample),
+            None => Line::Other(line),
+        }
+    }
+}
+
+impl<'a> Sample<'a> {
+    fn parse(line: &'a str) -> Option<Self> {
+        let line = line.trim_start();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+
+        let end = line
+            .find(|c: char| c == '{' || c.is_ascii_whitespace())
+            .unwrap_or(line.len());
+        let name = &line[..end];
+        if !is_valid_name(name, true) {
+            return None;
+        }
+
+        let mut rest = &line[end..];
+        let mut labels = Vec::new();
+        if let Some(mut s) = rest.strip_prefix('{') {
+            loop {
+                s = s.trim_start();
+                if let Some(r) = s.strip_prefix('}') {
+                    s = r;
+                    break;
+                }
+
+                let (label, r) = s.split_once('=')?;
+                let label = label.trim();
+                if !is_valid_name(label, false) {
+                    return None;
+                }
+                let (value, r) = parse_quoted(r.trim_start())?;
+                labels.push((label, value));
+
+                s = r.trim_start();
+                s = s.strip_prefix(',').unwrap_or(s);
+            }
+            rest = s;
+        }
+
+        let value = rest.trim();
+        if value.is_empty() {
+            return None;
+        }
+
+        Some(Sample {
+            name,
+            labels,
+            value,
+        })
+    }
+
+    /// Returns the numeric value of the sample, ignoring any timestamp.
+    pub(crate) fn number(&self) -> Option<f64> {
+        let value = self.value.split_ascii_whitespace().next()?;
+        value.parse().ok()
+    }
+
+    /// Writes the sample in the text exposition format, followed by a newline.
+    pub(crate) fn write(&self, out: &mut String) {
+        out.push_str(self.name);
+        if !self.labels.is_empty() {
+            out.push('{');
+            for (i, (name, value)) in self.labels.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                let _ = write!(out, "{name}=\"");
+                escape(value, out);
+                out.push('"');
+            }
+            out.push('}');
+        }
+        out.push(' ');
+        out.push_str(self.value);
+        out.push('\n');
+    }
+}
+
+/// Formats a sample value in the text exposition format.
+pub(crate) fn format_number(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value.is_infinite() {
+        if value > 0.0 { "+Inf" } else { "-Inf" }.to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+/// Returns the metric family described by a `# HELP`, `# TYPE` or `# UNIT` line.
+pub(crate) fn metadata_family(line: &str) -> Option<&str> {
+    let mut parts = line.strip_prefix('#')?.split_whitespace();
+    match parts.next()? {
+        "HELP" | "TYPE" | "UNIT" => parts.next(),
+        _ => None,
+    }
+}
+
+/// An error found by `validate`, pointing at the invalid part of the payload.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    line: usize,
+    column: usize,
+    span: Range<usize>,
+    message: String,
+}
+
+impl ParseError {
+    /// Returns the line number of the error, starting at 1.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// Returns the column of the start of the error within its line, in characters, starting at 1.
+    pub fn column(&self) -> usize {
+        self.column
+    }
+
+    /// Returns the byte range of the payload that the error refers to.
+    pub fn span(&self) -> Range<usize> {
+        self.span.clone()
+    }
+
+    /// Returns a description of the error, without its location.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "line {}, column {}: {}",
+            self.line, self.column, self.message
+        )
+    }
+}
+
+impl Error for ParseError {}
+
+/// Checks that a payload is valid in the Prometheus text exposition format, or OpenMetrics,
+/// returning the location of the first error found.
+///
+/// Unlike serving, which passes payloads through as given, this checks metric and label names,
+/// label value escapes, values, timestamps and `# HELP` and `# TYPE` lines, so payloads built by
+/// hand can be checked before they're published, e.g. in tests.
+///
+/// ```rust
+/// let err = metrics_server::validate(b"up 1\nhttp_requests_total{code=200} 3\n").unwrap_err();
+/// assert_eq!((err.line(), err.column()), (2, 26));
+/// ```
+pub fn validate(data: &[u8]) -> Result<(), ParseError> {
+    let text = std::str::from_utf8(data).map_err(|e| {
+        let offset = e.valid_up_to();
+        let start = data[..offset]
+            .iter()
+            .rposition(|&b| b == b'\n')
+            .map_or(0, |i| i + 1);
+        let line = data[..offset].iter().filter(|&&b| b == b'\n').count() + 1;
+        let column = String::from_utf8_lossy(&data[start..offset])
+            .chars()
+            .count()
+            + 1;
+        ParseError {
+            line,
+            column,
+            span: offset..offset + e.error_len().unwrap_or(data.len() - offset),
+            message: "invalid UTF-8".to_string(),
+        }
+    })?;
+
+    let mut offset = 0;
+    for (i, line) in text.split_inclusive('\n').enumerate() {
+        let content = line.strip_suffix('\n').unwrap_or(line);
+        let content = content.strip_suffix('\r').unwrap_or(content);
+        if let Err((span, message)) = validate_line(content) {
+            let column = content[..span.start].chars().count() + 1;
+            return Err(ParseError {
+                line: i + 1,
+                column,
+                span: offset + span.start..offset + span.end,
+                message,
+            });
+        }
+        offset += line.len();
+    }
+    Ok(())
+}
+
+// The location within a line, and description, of a validation error.
+type LineError = (Range<usize>, String);
+
+// Validates a single line, without its trailing newline.
+fn validate_line(line: &str) -> Result<(), LineError> {
+    let mut cursor = Cursor { line, pos: 0 };
+    cursor.skip_whitespace();
+    if cursor.is_empty() {
+        return Ok(());
+    }
+    if cursor.eat('#') {
+        return validate_comment(&mut cursor);
+    }
+
+    cursor.name(true, "metric")?;
+    if cursor.eat('{') {
+        validate_labels(&mut cursor)?;
+    }
+
+    if !cursor.skip_whitespace() {
+        return Err(cursor.error_at_next("expected whitespace before the value"));
+    }
+    let (span, value) = cursor.token();
+    if value.is_empty() {
+        return Err((span, "missing value".to_string()));
+    }
+    if value.parse::<f64>().is_err() {
+        return Err((span, format!("invalid value {value:?}")));
+    }
+
+    cursor.skip_whitespace();
+    let (span, timestamp) = cursor.token();
+    // OpenMetrics timestamps may be fractional, and exemplars follow a `#`.
+    if !timestamp.is_empty() && timestamp != "#" && timestamp.parse::<f64>().is_err() {
+        return Err((span, format!("invalid timestamp {timestamp:?}")));
+    }
+    cursor.skip_whitespace();
+    if !cursor.is_empty() && !cursor.rest().starts_with('#') {
+        let (span, _) = cursor.token();
+        return Err((span, "unexpected text after the timestamp".to_string()));
+    }
+    Ok(())
+}
+
+// Validates a comment line following the `#`.
+fn validate_comment(cursor: &mut Cursor) -> Result<(), LineError> {
+    cursor.skip_whitespace();
+    let (_, keyword) = cursor.token();
+    if !matches!(keyword, "HELP" | "TYPE" | "UNIT") {
+        return Ok(());
+    }
+
+    if !cursor.skip_whitespace() {
+        return Err(cursor.error_at_next(&format!("expected a metric name after {keyword}")));
+    }
+    cursor.name(true, "metric")?;
+    if keyword == "TYPE" {
+        cursor.skip_whitespace();
+        let (span, kind) = cursor.token();
+        if !METRIC_TYPES.contains(&kind) {
+            return Err((span, format!("invalid metric type {kind:?}")));
+        }
+    }
+    Ok(())
+}
+
+// Validates a label set following the opening brace, up to and including the closing brace.
+fn validate_labels(cursor: &mut Cursor) -> Result<(), LineError> {
+    loop {
+        cursor.skip_whitespace();
+        if cursor.eat('}') {
+            return Ok(());
+        }
+        if cursor.is_empty() {
+            return Err(cursor.error_at_next("unterminated label set"));
+        }
+
+        cursor.name(false, "label")?;
+        cursor.skip_whitespace();
+        if !cursor.eat('=') {
+            return Err(cursor.error_at_next("expected '=' after the label name"));
+        }
+        cursor.skip_whitespace();
+        let start = cursor.pos;
+        if !cursor.eat('"') {
+            return Err(cursor.error_at_next("expected a quoted label value"));
+        }
+        loop {
+            match cursor.next() {
+                Some('"') => break,
+                Some('\\') => {
+                    let escape = cursor.pos - 1;
+                    if !matches!(cursor.next(), Some('\\' | '"' | 'n')) {
+                        return Err((escape..cursor.pos, "invalid escape sequence".to_string()));
+                    }
+                }
+                Some(_) => {}
+                None => return Err((start..cursor.pos, "unterminated label value".to_string())),
+            }
+        }
+
+        cursor.skip_whitespace();
+        if !cursor.eat(',') && !cursor.rest().starts_with('}') {
+            return Err(cursor.error_at_next("expected ',' or '}' after the label value"));
+        }
+    }
+}
+
+// A position within a line being validated.
+struct Cursor<'a> {
+    line: &'a str,
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn rest(&self) -> &'a str {
+        &self.line[self.pos..]
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos == self.line.len()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.rest().chars().next()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    // Consumes the given character if it's next.
+    fn eat(&mut self, c: char) -> bool {
+        let found = self.rest().starts_with(c);
+        if found {
+            self.pos += c.len_utf8();
+        }
+        found
+    }
+
+    // Skips whitespace, returning whether any was found.
+    fn skip_whitespace(&mut self) -> bool {
+        let start = self.pos;
+        self.pos = self.line.len() - self.rest().trim_start().len();
+        self.pos > start
+    }
+
+    // Consumes everything up to the next whitespace.
+    fn token(&mut self) -> (Range<usize>, &'a str) {
+        let start = self.pos;
+        let len = self
+            .rest()
+            .find(|c: char| c.is_whitespace())
+            .unwrap_or(self.rest().len());
+        self.pos += len;
+        (start..self.pos, &self.line[start..self.pos])
+    }
+
+    // Consumes a metric or label name.
+    fn name(&mut self, colons: bool, kind: &str) -> Result<&'a str, LineError> {
+        let start = self.pos;
+        let len = self
+            .rest()
+            .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == ':' || c == '.'))
+            .unwrap_or(self.rest().len());
+        self.pos += len;
+
+        let name = &self.line[start..self.pos];
+        if name.is_empty() {
+            return Err(self.error_at_next(&format!("expected a {kind} name")));
+        }
+        if !is_valid_name(name, colons) {
+            return Err((start..self.pos, format!("invalid {kind} name {name:?}")));
+        }
+        Ok(name)
+    }
+
+    // Returns an error pointing at the next character, or the end of the line.
+    fn error_at_next(&self, message: &str) -> LineError {
+        let len = self.rest().chars().next().map_or(0, char::len_utf8);
+        (self.pos..self.pos + len, message.to_string())
+    }
+}
+
+// Returns whether the given string is a valid metric name, or label name if colons are not allowed.
+fn is_valid_name(name: &str, colons: bool) -> bool {
+    let mut chars = name.chars();
+    let valid_start = |c: char| c.is_ascii_alphabetic() || c == '_' || (colons && c == ':');
+    chars.next().is_some_and(valid_start) && chars.all(|c| valid_start(c) || c.is_ascii_digit())
+}
+
+// Parses a double quoted label value, returning the unescaped value and the remaining input.
+fn parse_quoted(s: &str) -> Option<(Cow<'_, str>, &str)> {
+    let s = s.strip_prefix('"')?;
+    match s.find(['"', '\\'])? {
+        i if s.as_bytes()[i] == b'"' => Some((Cow::Borrowed(&s[..i]), &s[i + 1..])),
+        // Only allocate once an escape sequence is found.
+        i => parse_escaped(s, i),
+    }
+}
+
+fn parse_escaped(s: &str, start: usize) -> Option<(Cow<'_, str>, &str)> {
+    let mut value = s[..start].to_string();
+    let mut chars = s[start..].char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => match chars.next()?.1 {
+                'n' => value.push('\n'),
+                c => value.push(c),
+            },
+            '"' => return Some((Cow::Owned(value), &s[start + i + 1..])),
+            c => value.push(c),
+        }
+    }
+    None
+}
+
+// Escapes a label value for the text exposition format.
+pub(crate) fn escape(value: &str, out: &mut String) {
+    for c in value.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            '\n' => out.push_str("\\n"),
+            c => out.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_parse_line() {
+        let line =
+            Line::parse(r#"http_requests_total{method="post",code="200"} 1027 1395066363000"#);
+        let Line::Sample(sample) = line else {
+            panic!("expected sample");
+        };
+        assert_eq!(sample.name, "http_requests_total");
+        assert_eq!(
+            sample.labels,
+            vec![("method", "post".into()), ("code", "200".into())]
+        );
+        assert_eq!(sample.value, "1027 1395066363000");
+        assert_eq!(sample.number(), Some(1027.0));
+
+        let line = Line::parse(
+            r#"msdos_file_access_time_seconds{path="C:\\DIR\\FILE.TXT",error="Cannot find file:\n\"FILE.TXT\""} 1.458255915e9"#,
+        );
+        let Line::Sample(sample) = line else {
+            panic!("expected sample");
+        };
+        assert_eq!(sample.labels[0].1, "C:\\DIR\\FILE.TXT");
+        assert_eq!(sample.labels[1].1, "Cannot find file:\n\"FILE.TXT\"");
+
+        // Trailing commas and whitespace are allowed.
+        let Line::Sample(sample) = Line::parse(r#"metric_without_timestamp{ a = "b", } 12.47"#)
+        else {
+            panic!("expected sample");
+        };
+        assert_eq!(sample.labels, vec![("a", "b".into())]);
+
+        let cases = [
+            "",
+            "# HELP http_requests_total The total number of HTTP requests.",
+            "# TYPE http_requests_total counter",
+            "metric_without_value",
+            "0invalid_name 1",
+            r#"unterminated{a="b} 1"#,
+        ];
+        for line in cases {
+            assert_eq!(Line::parse(line), Line::Other(line));
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let valid = "# HELP http_requests_total The total number of HTTP requests.\n\
+            # TYPE http_requests_total counter\n\
+            http_requests_total{method=\"post\",code=\"200\"} 1027 1395066363000\n\
+            \n\
+            # A comment.\n\
+            msdos_file_access_time_seconds{path=\"C:\\\\DIR\",error=\"\\\"FILE\\\"\\n\",} 1.458255915e9\n\
+            something_weird{problem=\"division by zero\"} +Inf -3982045\n\
+            foo_total{a=\"b\"} 17.0 1520879607.789 # {trace_id=\"KOO5S4vxi0o\"} 0.67\n\
+            # EOF\n";
+        assert_eq!(validate(valid.as_bytes()), Ok(()));
+        assert_eq!(validate(b""), Ok(()));
+
+        // The line and column of each error, and the text it spans.
+        let cases = [
+            (
+                "0invalid 1",
+                (1, 1),
+                "0invalid",
+                "invalid metric name \"0invalid\"",
+            ),
+            ("a 1\r\nb{c=\"d\"} x", (2, 10), "x", "invalid value \"x\""),
+            ("up", (1, 3), "", "expected whitespace before the value"),
+            ("up ", (1, 4), "", "missing value"),
+            ("up 1 now", (1, 6), "now", "invalid timestamp \"now\""),
+            (
+                "up 1 2 3",
+                (1, 8),
+                "3",
+                "unexpected text after the timestamp",
+            ),
+            (
+                "up{code=200} 1",
+                (1, 9),
+                "2",
+                "expected a quoted label value",
+            ),
+            (
+                "up{0code=\"a\"} 1",
+                (1, 4),
+                "0code",
+                "invalid label name \"0code\"",
+            ),
+            (
+                "up{code} 1",
+                (1, 8),
+                "}",
+                "expected '=' after the label name",
+            ),
+            (
+                "up{a=\"b\" c=\"d\"} 1",
+                (1, 10),
+                "c",
+                "expected ',' or '}' after the label value",
+            ),
+            ("up{a=\"\\t\"} 1", (1, 7), "\\t", "invalid escape sequence"),
+            ("up{a=\"b} 1", (1, 6), "\"b} 1", "unterminated label value"),
+            ("up{a=\"b\",", (1, 10), "", "unterminated label set"),
+            ("up{a=\"ü\"} x", (1, 11), "x", "invalid value \"x\""),
+            (
+                "# TYPE up count",
+                (1, 11),
+                "count",
+                "invalid metric type \"count\"",
+            ),
+            (
+                "# HELP 0up Help.",
+                (1, 8),
+                "0up",
+                "invalid metric name \"0up\"",
+            ),
+        ];
+        for (data, (line, column), text, message) in cases {
+            let err = validate(data.as_bytes()).unwrap_err();
+            assert_eq!((err.line(), err.column()), (line, column), "{data:?}");
+            assert_eq!(&data[err.span()], text, "{data:?}");
+            assert_eq!(err.message(), message, "{data:?}");
+        }
+
+        let err = validate(b"up 1\nup{a=\"\xff\"} 1").unwrap_err();
+        assert_eq!(err.to_string(), "line 2, column 7: invalid UTF-8");
+        assert_eq!(err.span(), 11..12);
+    }
+
+    proptest! {
+        #[test]
+        fn prop_validate_spans_are_in_bounds(data in "[a-z_{}=\",.#\\\\ 0-9\n\u{fc}]{0,48}") {
+            if let Err(err) = validate(data.as_bytes()) {
+                prop_assert!(err.span().end <= data.len());
+                prop_assert!(data.get(err.span()).is_some());
+            }
+        }
+    }
+
+    #[test]
+    fn test_format_number() {
+        assert_eq!(format_number(10.0), "10");
+        assert_eq!(format_number(0.25), "0.25");
+        assert_eq!(format_number(f64::INFINITY), "+Inf");
+        assert_eq!(format_number(f64::NEG_INFINITY), "-Inf");
+        assert_eq!(format_number(f64::NAN), "NaN");
+        assert_eq!("+Inf".parse::<f64>().unwrap(), f64::INFINITY);
+    }
+
+    #[test]
+    fn test_metadata_family() {
+        assert_eq!(
+            metadata_family("# HELP http_requests_total The total number of HTTP requests."),
+            Some("http_requests_total")
+        );
+        assert_eq!(
+            metadata_family("# TYPE http_requests_total counter"),
+            Some("http_requests_total")
+        );
+        assert_eq!(metadata_family("# EOF"), None);
+        assert_eq!(metadata_family("# Just a comment"), None);
+        assert_eq!(metadata_family("http_requests_total 1"), None);
+    }
+
+    #[test]
+    fn test_write_sample() {
+        let line = r#"msdos_file_access_time_seconds{path="C:\\DIR\\FILE.TXT",error="Cannot find file:\n\"FILE.TXT\""} 1.458255915e9"#;
+        let Line::Sample(sample) = Line::parse(line) else {
+            panic!("expected sample");
+        };
+
+        let mut out = String::new();
+        sample.write(&mut out);
+        assert_eq!(out, format!("{line}\n"));
+    }
+}
@@ -0,0 +1,383 @@
+use std::collections::HashMap;
+
+use log::debug;
+use regex::Regex;
+use sha2::{Digest, Sha256};
+
+use crate::error::ServerError;
+use crate::exposition::{self, Line, Sample};
+use crate::page;
+
+/// How the value of a sensitive label is redacted before metrics are served.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Redaction {
+    /// Replaces the value with `[REDACTED]`.
+    Mask,
+    /// Replaces the value with a truncated SHA-256 hash, keeping distinct values distinct so
+    /// series can still be told apart.
+    Hash,
+}
+
+impl Redaction {
+    fn apply(self, value: &str) -> String {
+        match self {
+            Redaction::Mask => "[REDACTED]".to_string(),
+            Redaction::Hash => {
+                let hash = Sha256::digest(value.as_bytes());
+                hash[..8].iter().map(|b| format!("{b:02x}")).collect()
+            }
+        }
+    }
+}
+
+/// A rule that keeps or drops samples based on their metric name or a label value, similar to
+/// Prometheus `metric_relabel_configs`.
+///
+/// Patterns are anchored, so must match the whole name or value.
+#[derive(Clone, Debug)]
+pub struct Rule {
+    keep: bool,
+    label: Option<String>,
+    regex: Regex,
+}
+
+impl Rule {
+    /// Keeps only samples matching the given pattern, dropping all others.
+    pub fn keep(pattern: &str) -> Result<Self, ServerError> {
+        Rule::new(true, pattern)
+    }
+
+    /// Drops samples matching the given pattern.
+    pub fn drop(pattern: &str) -> Result<Self, ServerError> {
+        Rule::new(false, pattern)
+    }
+
+    fn new(keep: bool, pattern: &str) -> Result<Self, ServerError> {
+        let regex = Regex::new(&format!("^(?:{pattern})$"))
+            .map_err(|e| ServerError::Create(format!("invalid rule pattern: {e}")))?;
+        Ok(Rule {
+            keep,
+            label: None,
+            regex,
+        })
+    }
+
+    /// Matches the value of the given label rather than the metric name.
+    ///
+    /// Samples without the label are matched against an empty value.
+    pub fn label(mut self, name: &str) -> Self {
+        self.label = Some(name.to_string());
+        self
+    }
+
+    // Returns whether the sample should be kept.
+    fn retains(&self, sample: &Sample) -> bool {
+        let value = match &self.label {
+            Some(label) => sample
+                .labels
+                .iter()
+                .find(|(name, _)| name == label)
+                .map_or("", |(_, value)| value),
+            None => sample.name,
+        };
+        self.regex.is_match(value) == self.keep
+    }
+}
+
+/// An aggregation that sums samples over the given labels, removing them to reduce cardinality,
+/// e.g. collapsing a `shard` label.
+///
+/// Samples that only differ by the removed labels are replaced by a single sample with the sum
+/// of their values and no timestamp. Summary quantiles can't be summed, so samples with a
+/// `quantile` label are left unchanged.
+#[derive(Clone, Debug)]
+pub struct Aggregation {
+    labels: Vec<String>,
+    metrics: Option<Regex>,
+}
+
+impl Aggregation {
+    /// Sums samples over the given labels.
+    pub fn sum_without(labels: &[&str]) -> Self {
+        Aggregation {
+            labels: labels.iter().map(|l| l.to_string()).collect(),
+            metrics: None,
+        }
+    }
+
+    /// Only aggregates samples whose metric name matches the given pattern.
+    ///
+    /// The pattern is anchored, so must match the whole metric name.
+    pub fn metrics(mut self, pattern: &str) -> Result<Self, ServerError> {
+        let regex = Regex::new(&format!("^(?:{pattern})$"))
+            .map_err(|e| ServerError::Create(format!("invalid aggregation pattern: {e}")))?;
+        self.metrics = Some(regex);
+        Ok(self)
+    }
+
+    // Returns whether the aggregation applies to the sample.
+    fn applies(&self, sample: &Sample) -> bool {
+        self.metrics
+            .as_ref()
+            .map_or(true, |r| r.is_match(sample.name))
+            && sample.labels.iter().any(|(name, _)| self.removes(name))
+            && !sample.labels.iter().any(|(name, _)| *name == "quantile")
+    }
+
+    fn removes(&self, label: &str) -> bool {
+        self.labels.iter().any(|l| l == label)
+    }
+}
+
+/// Filters applied to the Prometheus text exposition format when metrics are updated.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Filters {
+    pub(crate) redactions: HashMap<String, Redaction>,
+    pub(crate) rules: Vec<Rule>,
+    pub(crate) aggregations: Vec<Aggregation>,
+}
+
+// A line of filtered output.
+enum Output<'a> {
+    Line(&'a str),
+    Sample(Sample<'a>),
+    Sum(Sample<'a>, f64),
+}
+
+impl Filters {
+    /// Returns true if no filters are configured.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.redactions.is_empty() && self.rules.is_empty() && self.aggregations.is_empty()
+    }
+
+    /// Applies the filters to the given payload.
+    ///
+    /// Payloads that aren't valid UTF-8 can't be in the text format, so are returned unchanged.
+    pub(crate) fn apply(&self, data: Vec<u8>) -> Vec<u8> {
+        let Ok(text) = std::str::from_utf8(&data) else {
+            debug!("skipping filters for non UTF-8 metrics payload");
+            return data;
+        };
+
+        // Metadata is held back until a sample of its family is kept, so that dropping a whole
+        // family doesn't leave its HELP and TYPE lines behind.
+        let mut metadata: Vec<&str> = Vec::new();
+        let mut output = Vec::new();
+        let mut sums: HashMap<String, usize> = HashMap::new();
+        for line in text.lines() {
+            match Line::parse(line) {
+                Line::Sample(sample) => {
+                    if !self.rules.iter().all(|r| r.retains(&sample)) {
+                        continue;
+                    }
+
+                    // Metadata of another family, whose samples were all dropped, is discarded.
+                    let family = metadata
+                        .first()
+                        .and_then(|l| exposition::metadata_family(l));
+                    if family.is_some_and(|f| page::belongs(sample.name, f)) {
+                        output.extend(metadata.drain(..).map(Output::Line));
+                    } else {
+                        metadata.clear();
+                    }
+
+                    let sample = self.redact(sample);
+                    match self.aggregate(sample) {
+                        Ok((key, sample, value)) => match sums.get(&key) {
+                            Some(&i) => {
+                                if let Output::Sum(_, sum) = &mut output[i] {
+                                    *sum += value;
+                                }
+                            }
+                            None => {
+                                sums.insert(key, output.len());
+                                output.push(Output::Sum(sample, value));
+                            }
+                        },
+                        Err(sample) => output.push(Output::Sample(sample)),
+                    }
+                }
+                Line::Other(line) => match exposition::metadata_family(line) {
+                    Some(family) => {
+                        if metadata
+                            .first()
+                            .and_then(|l| exposition::metadata_family(l))
+                            .is_some_and(|f| f != family)
+                        {
+                            metadata.clear();
+                        }
+                        metadata.push(line);
+                    }
+                    None => output.push(Output::Line(line)),
+                },
+            }
+        }
+
+        let mut out = String::with_capacity(text.len());
+        for o in output {
+            match o {
+                Output::Line(line) => {
+                    out.push_str(line);
+                    out.push('\n');
+                }
+                Output::Sample(sample) => sample.write(&mut out),
+                Output::Sum(sample, sum) => {
+                    let value = exposition::format_number(sum);
+                    Sample {
+                        value: &value,
+                        ..sample
+                    }
+                    .write(&mut out);
+                }
+            }
+        }
+        out.into_bytes()
+    }
+
+    // Removes aggregated labels from the sample, returning the key identifying its series and
+    // its value, or the unchanged sample if it isn't aggregated.
+    fn aggregate<'a>(
+        &self,
+        mut sample: Sample<'a>,
+    ) -> Result<(String, Sample<'a>, f64), Sample<'a>> {
+        let Some(aggregation) = self.aggregations.iter().find(|a| a.applies(&sample)) else {
+            return Err(sample);
+        };
+        let Some(value) = sample.number() else {
+            return Err(sample);
+        };
+
+        sample.labels.retain(|(name, _)| !aggregation.removes(name));
+        let mut key = String::new();
+        Sample {
+            value: "",
+            ..sample.clone()
+        }
+        .write(&mut key);
+        Ok((key, sample, value))
+    }
+
+    fn redact<'a>(&self, mut sample: Sample<'a>) -> Sample<'a> {
+        for (name, value) in sample.labels.iter_mut() {
+            if let Some(redaction) = self.redactions.get(*name) {
+                *value = redaction.apply(value).into();
+            }
+        }
+        sample
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact() {
+        let filters = Filters {
+            redactions: HashMap::from([
+                ("user_email".to_string(), Redaction::Mask),
+                ("ip".to_string(), Redaction::Hash),
+            ]),
+            ..Default::default()
+        };
+
+        let data = "# TYPE logins counter\n\
+            logins{user_email=\"a@example.com\",ip=\"10.0.0.1\",code=\"200\"} 1\n\
+            logins{user_email=\"b@example.com\",ip=\"10.0.0.2\",code=\"200\"} 2\n";
+        let out = String::from_utf8(filters.apply(data.into())).unwrap();
+
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines[0], "# TYPE logins counter");
+        assert!(!out.contains("example.com"));
+        assert!(!out.contains("10.0.0"));
+        assert!(lines[1].starts_with("logins{user_email=\"[REDACTED]\",ip=\""));
+        assert!(lines[1].ends_with("\",code=\"200\"} 1"));
+
+        // Hashes are stable and keep distinct values distinct.
+        let ip = |line: &str| line.split("ip=\"").nth(1).unwrap()[..16].to_string();
+        assert_eq!(ip(lines[1]), Redaction::Hash.apply("10.0.0.1"));
+        assert_ne!(ip(lines[1]), ip(lines[2]));
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let filters = Filters {
+            aggregations: vec![Aggregation::sum_without(&["shard"])],
+            ..Default::default()
+        };
+
+        let data = "# TYPE requests counter\n\
+            requests{shard=\"0\",code=\"200\"} 1\n\
+            requests{shard=\"0\",code=\"500\"} 2\n\
+            requests{shard=\"1\",code=\"200\"} 3 1395066363000\n\
+            latency{quantile=\"0.5\",shard=\"0\"} 0.1\n\
+            other 1\n";
+        let out = String::from_utf8(filters.apply(data.into())).unwrap();
+        assert_eq!(
+            out,
+            "# TYPE requests counter\n\
+            requests{code=\"200\"} 4\n\
+            requests{code=\"500\"} 2\n\
+            latency{quantile=\"0.5\",shard=\"0\"} 0.1\n\
+            other 1\n"
+        );
+
+        // Aggregations can be limited to specific metrics.
+        let filters = Filters {
+            aggregations: vec![Aggregation::sum_without(&["shard"])
+                .metrics("requests")
+                .unwrap()],
+            ..Default::default()
+        };
+        let data = "requests{shard=\"0\"} 1\nrequests{shard=\"1\"} 1\nerrors{shard=\"0\"} 1\n";
+        let out = String::from_utf8(filters.apply(data.into())).unwrap();
+        assert_eq!(out, "requests 2\nerrors{shard=\"0\"} 1\n");
+    }
+
+    #[test]
+    fn test_rules() {
+        let filters = Filters {
+            rules: vec![
+                Rule::drop("go_gc_.*").unwrap(),
+                Rule::drop("5..").unwrap().label("code"),
+            ],
+            ..Default::default()
+        };
+
+        let data = "# HELP go_gc_duration_seconds A summary of GC pauses.\n\
+            # TYPE go_gc_duration_seconds summary\n\
+            go_gc_duration_seconds{quantile=\"0\"} 0.001\n\
+            go_gc_duration_seconds_sum 0.1\n\
+            # HELP http_requests_total Total requests.\n\
+            # TYPE http_requests_total counter\n\
+            http_requests_total{code=\"200\"} 10\n\
+            http_requests_total{code=\"500\"} 1\n\
+            http_requests_total 3\n";
+        let out = String::from_utf8(filters.apply(data.into())).unwrap();
+        assert_eq!(
+            out,
+            "# HELP http_requests_total Total requests.\n\
+            # TYPE http_requests_total counter\n\
+            http_requests_total{code=\"200\"} 10\n\
+            http_requests_total 3\n"
+        );
+
+        // Keep rules drop everything that doesn't match, and patterns are anchored.
+        let filters = Filters {
+            rules: vec![Rule::keep("http_.*").unwrap()],
+            ..Default::default()
+        };
+        let out = filters.apply("http_requests_total 1\nmy_http_requests 2\n".into());
+        assert_eq!(out, b"http_requests_total 1\n");
+
+        // Assert metadata isn't left behind by a dropped family followed by an untyped one.
+        let filters = Filters {
+            rules: vec![Rule::drop("a").unwrap()],
+            ..Default::default()
+        };
+        let out = filters.apply("# HELP a A.\n# TYPE a counter\na 1\nb 2\n".into());
+        assert_eq!(out, b"b 2\n");
+
+        assert!(matches!(Rule::keep("("), Err(ServerError::Create(_))));
+    }
+}
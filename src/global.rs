@@ -0,0 +1,56 @@
+//! A process-global `MetricsServer` for publishing metrics without passing a handle around.
+//!
+//! The global server is initialized once, typically early in `main`, and lives for the rest of
+//! the process. Libraries and deeply nested code can then publish metrics with [`update`].
+//!
+//! ```rust
+//! use metrics_server::global;
+//!
+//! // Create the global server and start listening for requests in the background.
+//! global::init("localhost:8001").unwrap();
+//!
+//! // Publish your application metrics from anywhere.
+//! let bytes = global::update("my_awesome_metric = 10".into());
+//! assert_eq!(Some(22), bytes);
+//! ```
+use std::net::ToSocketAddrs;
+use std::sync::OnceLock;
+
+use crate::error::ServerError;
+use crate::server::MetricsServer;
+
+static SERVER: OnceLock<MetricsServer> = OnceLock::new();
+
+/// Creates the global HTTP server at the given address and starts serving the /metrics URL path.
+///
+/// Returns an error if the server could not be created or the global server is already set.
+pub fn init<A>(addr: A) -> Result<(), ServerError>
+where
+    A: ToSocketAddrs,
+{
+    let mut server = MetricsServer::new(addr, None, None)?;
+    server.serve();
+    set(server)
+}
+
+/// Sets an existing `MetricsServer` as the global server.
+///
+/// This allows the global server to be configured with a `Builder`. The caller is responsible for
+/// calling `serve` or `serve_uri` on it. Returns an error if the global server is already set.
+pub fn set(server: MetricsServer) -> Result<(), ServerError> {
+    SERVER
+        .set(server)
+        .map_err(|_| ServerError::Create("global metrics server already set".to_string()))
+}
+
+/// Returns the global server, if it has been set.
+pub fn get() -> Option<&'static MetricsServer> {
+    SERVER.get()
+}
+
+/// Updates the data in the global server, returning the number of bytes written.
+///
+/// Returns None if the global server has not been set.
+pub fn update(data: Vec<u8>) -> Option<usize> {
+    get().map(|server| server.update(data))
+}
@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::exposition::{self, Line, Sample};
+
+// Suffixes of samples belonging to a metric family with a shorter name, e.g. histograms.
+const FAMILY_SUFFIXES: [&str; 5] = ["_total", "_count", "_sum", "_bucket", "_created"];
+
+// The help text and type declared for a metric family.
+#[derive(Default)]
+struct Metadata<'a> {
+    help: Option<&'a str>,
+    kind: Option<&'a str>,
+}
+
+/// Renders a payload in the text exposition format as a JSON document.
+///
+/// Every sample becomes an object with its name, labels, value and optional timestamp, along
+/// with the help text and type of its metric family if declared. Values that JSON numbers can't
+/// represent, such as `NaN` and `+Inf`, are rendered as strings. Lines that can't be parsed
+/// are skipped.
+pub(crate) fn render(data: &[u8]) -> Vec<u8> {
+    let data = String::from_utf8_lossy(data);
+    let lines: Vec<Line> = data.lines().map(Line::parse).collect();
+
+    let mut metadata: HashMap<&str, Metadata> = HashMap::new();
+    for line in &lines {
+        let Line::Other(line) = line else { continue };
+        let Some(family) = exposition::metadata_family(line) else {
+            continue;
+        };
+        let mut parts = line[1..].trim_start().splitn(3, ' ');
+        let keyword = parts.next().unwrap_or_default();
+        let value = parts.nth(1).map(str::trim);
+        let entry = metadata.entry(family).or_default();
+        match keyword {
+            "HELP" => entry.help = value,
+            "TYPE" => entry.kind = value,
+            _ => {}
+        }
+    }
+
+    let mut out = String::from("{\"metrics\":[");
+    let samples = lines.iter().filter_map(|line| match line {
+        Line::Sample(sample) => Some((sample, sample.number()?)),
+        Line::Other(_) => None,
+    });
+    for (i, (sample, value)) in samples.enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let metadata = family_metadata(&metadata, sample.name);
+        write_sample(sample, value, metadata, &mut out);
+    }
+    out.push_str("]}");
+    out.into_bytes()
+}
+
+// Returns the metadata of the family a sample belongs to, if declared.
+fn family_metadata<'m, 'a>(
+    metadata: &'m HashMap<&str, Metadata<'a>>,
+    name: &str,
+) -> Option<&'m Metadata<'a>> {
+    metadata.get(name).or_else(|| {
+        FAMILY_SUFFIXES
+            .iter()
+            .find_map(|suffix| metadata.get(name.strip_suffix(suffix)?))
+    })
+}
+
+// Writes a sample as a JSON object.
+fn write_sample(sample: &Sample, value: f64, metadata: Option<&Metadata>, out: &mut String) {
+    out.push_str("{\"name\":");
+    write_string(sample.name, out);
+    if let Some(help) = metadata.and_then(|m| m.help) {
+        out.push_str(",\"help\":");
+        write_string(help, out);
+    }
+    if let Some(kind) = metadata.and_then(|m| m.kind) {
+        out.push_str(",\"type\":");
+        write_string(kind, out);
+    }
+
+    out.push_str(",\"labels\":{");
+    for (i, (name, value)) in sample.labels.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        write_string(name, out);
+        out.push(':');
+        write_string(value, out);
+    }
+    out.push('}');
+
+    out.push_str(",\"value\":");
+    if value.is_finite() {
+        let _ = write!(out, "{value}");
+    } else {
+        write_string(&exposition::format_number(value), out);
+    }
+    let timestamp = sample.value.split_ascii_whitespace().nth(1);
+    if let Some(timestamp) = timestamp.and_then(|t| t.parse::<i64>().ok()) {
+        let _ = write!(out, ",\"timestamp\":{timestamp}");
+    }
+    out.push('}');
+}
+
+/// Writes a string as a quoted and escaped JSON string.
+pub(crate) fn write_string(value: &str, out: &mut String) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let data = b"# HELP http_requests_total The total number of \"HTTP\" requests.\n\
+            # TYPE http_requests_total counter\n\
+            http_requests_total{method=\"post\",code=\"200\"} 1027 1395066363000\n\
+            # TYPE rpc_duration_seconds summary\n\
+            rpc_duration_seconds_sum 1.7560473e+07\n\
+            temperature -Inf\n\
+            invalid value\n";
+
+        let json = String::from_utf8(render(data)).unwrap();
+        assert_eq!(
+            json,
+            "{\"metrics\":[\
+            {\"name\":\"http_requests_total\",\"help\":\"The total number of \\\"HTTP\\\" requests.\",\
+            \"type\":\"counter\",\"labels\":{\"method\":\"post\",\"code\":\"200\"},\
+            \"value\":1027,\"timestamp\":1395066363000},\
+            {\"name\":\"rpc_duration_seconds_sum\",\"type\":\"summary\",\"labels\":{},\"value\":17560473},\
+            {\"name\":\"temperature\",\"labels\":{},\"value\":\"-Inf\"}\
+            ]}"
+        );
+
+        assert_eq!(render(b""), b"{\"metrics\":[]}");
+    }
+}
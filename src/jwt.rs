@@ -0,0 +1,162 @@
+use std::fmt;
+
+use http::HeaderValue;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::de::IgnoredAny;
+
+use crate::auth::{AuthDecision, Authenticator, RequestMeta};
+use crate::error::ServerError;
+
+/// Bearer authentication using JSON Web Tokens, such as service account tokens presented by
+/// Prometheus agents.
+///
+/// The token signature and `exp` claim are always validated, as well as the `aud` claim if an
+/// audience is configured. Requests without a valid token are challenged with a 401 response.
+#[derive(Clone)]
+pub struct JwtAuth {
+    key: DecodingKey,
+    validation: Validation,
+}
+
+impl JwtAuth {
+    /// Validates tokens signed with HS256 using the given shared secret.
+    pub fn hs256(secret: &[u8]) -> Self {
+        JwtAuth::new(DecodingKey::from_secret(secret), Algorithm::HS256)
+    }
+
+    /// Validates tokens signed with RS256 using the given PEM encoded RSA public key.
+    pub fn rs256(public_key: &[u8]) -> Result<Self, ServerError> {
+        let key = DecodingKey::from_rsa_pem(public_key)
+            .map_err(|e| ServerError::Create(format!("invalid JWT public key: {e}")))?;
+        Ok(JwtAuth::new(key, Algorithm::RS256))
+    }
+
+    fn new(key: DecodingKey, algorithm: Algorithm) -> Self {
+        let mut validation = Validation::new(algorithm);
+        validation.validate_aud = false;
+        JwtAuth { key, validation }
+    }
+
+    /// Only accepts tokens whose `aud` claim contains one of the given audiences.
+    pub fn audience<T: ToString>(mut self, audience: &[T]) -> Self {
+        self.validation.set_audience(audience);
+        self.validation.validate_aud = true;
+        self
+    }
+
+    /// Sets the clock skew allowed when validating the `exp` claim.
+    ///
+    /// Defaults to 60 seconds.
+    pub fn leeway(mut self, seconds: u64) -> Self {
+        self.validation.leeway = seconds;
+        self
+    }
+}
+
+impl Authenticator for JwtAuth {
+    fn authenticate(&self, req: &RequestMeta) -> AuthDecision {
+        let Some(token) = req.credentials("Bearer") else {
+            return AuthDecision::Unauthorized(vec![HeaderValue::from_static(
+                "Bearer realm=\"metrics\"",
+            )]);
+        };
+
+        // Claims are validated by the decoder, so their contents can be discarded.
+        match jsonwebtoken::decode::<IgnoredAny>(token, &self.key, &self.validation) {
+            Ok(_) => AuthDecision::Allow,
+            Err(_) => AuthDecision::Unauthorized(vec![HeaderValue::from_static(
+                "Bearer realm=\"metrics\", error=\"invalid_token\"",
+            )]),
+        }
+    }
+
+    fn scheme(&self) -> &str {
+        "jwt"
+    }
+}
+
+impl fmt::Debug for JwtAuth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Never print keys.
+        f.debug_struct("JwtAuth")
+            .field("algorithms", &self.validation.algorithms)
+            .field("audience", &self.validation.aud)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use jsonwebtoken::{EncodingKey, Header};
+
+    use super::*;
+    use crate::request::{Limits, Request};
+
+    fn authenticate(auth: &JwtAuth, token: Option<&str>) -> AuthDecision {
+        let mut raw = "GET /metrics HTTP/1.1\r\n".to_string();
+        if let Some(token) = token {
+            raw.push_str(&format!("Authorization: Bearer {token}\r\n"));
+        }
+        raw.push_str("\r\n");
+        let req = Request::read(&mut raw.as_bytes(), None, &Limits::default()).unwrap();
+        auth.authenticate(&RequestMeta::new(&req, SystemTime::now()))
+    }
+
+    fn token(algorithm: Algorithm, key: &EncodingKey, exp_offset: i64, aud: &str) -> String {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        let claims = serde_json::json!({
+            "sub": "prometheus",
+            "aud": aud,
+            "exp": now + exp_offset,
+        });
+        jsonwebtoken::encode(&Header::new(algorithm), &claims, key).unwrap()
+    }
+
+    #[test]
+    fn test_hs256() {
+        let auth = JwtAuth::hs256(b"secret").audience(&["metrics"]);
+        let key = EncodingKey::from_secret(b"secret");
+
+        let valid = token(Algorithm::HS256, &key, 60, "metrics");
+        assert_eq!(authenticate(&auth, Some(&valid)), AuthDecision::Allow);
+
+        // Missing, expired, wrong audience and wrong signature.
+        assert!(matches!(
+            authenticate(&auth, None),
+            AuthDecision::Unauthorized(_)
+        ));
+        let expired = token(Algorithm::HS256, &key, -3600, "metrics");
+        assert_ne!(authenticate(&auth, Some(&expired)), AuthDecision::Allow);
+        let audience = token(Algorithm::HS256, &key, 60, "other");
+        assert_ne!(authenticate(&auth, Some(&audience)), AuthDecision::Allow);
+        let forged = token(
+            Algorithm::HS256,
+            &EncodingKey::from_secret(b"x"),
+            60,
+            "metrics",
+        );
+        assert_ne!(authenticate(&auth, Some(&forged)), AuthDecision::Allow);
+    }
+
+    #[test]
+    fn test_rs256() {
+        let public_key = include_bytes!("../tests/certs/jwt_public_key.pem");
+        let private_key = include_bytes!("../tests/certs/jwt_private_key.pem");
+        let auth = JwtAuth::rs256(public_key).unwrap();
+        let key = EncodingKey::from_rsa_pem(private_key).unwrap();
+
+        let valid = token(Algorithm::RS256, &key, 60, "any");
+        assert_eq!(authenticate(&auth, Some(&valid)), AuthDecision::Allow);
+
+        // Tokens signed with a different algorithm are rejected.
+        let hs256 = token(Algorithm::HS256, &EncodingKey::from_secret(b"x"), 60, "any");
+        assert_ne!(authenticate(&auth, Some(&hs256)), AuthDecision::Allow);
+
+        assert!(JwtAuth::rs256(b"invalid").is_err());
+    }
+}
@@ -15,7 +15,7 @@
 //! use metrics_server::MetricsServer;
 //!
 //! // Create a new HTTP server and start listening for requests in the background.
-//! let server = MetricsServer::http("localhost:8001");
+//! let mut server = MetricsServer::http("localhost:8001");
 //!
 //! // Publish your application metrics.
 //! let bytes = server.update("my_awesome_metric = 10".into());
@@ -35,7 +35,7 @@
 //! let key = include_bytes!("/path/to/key.pem").to_vec();
 //!
 //! // Create a new HTTPS server and start listening for requests in the background.
-//! let server = MetricsServer::https("localhost:8443", cert, key);
+//! let mut server = MetricsServer::https("localhost:8443", cert, key);
 //!
 //! // Publish your application metrics.
 //! let bytes = server.update("my_awesome_metric = 10".into());
@@ -48,11 +48,11 @@
 //! ## Serve a custom URL
 //!
 //! ```rust
-//! use metrics_server::MetricsServer;
+//! use metrics_server::{MetricsPath, MetricsServer};
 //!
-//! // Create a new server and specify the URL path to serve.
+//! // Create a new server and specify the URL path to serve, rejecting invalid paths.
 //! let mut server = MetricsServer::new("localhost:8001", None, None);
-//! server.serve_uri("/path/to/metrics");
+//! server.serve_uri(MetricsPath::new("/path/to/metrics").unwrap());
 //!
 //! // Publish your application metrics.
 //! let bytes = server.update("my_awesome_metric = 10".into());
@@ -61,8 +61,78 @@
 //! // Stop the server.
 //! server.stop().unwrap();
 //! ```
+//!
+//! ## Configure the underlying socket
+//!
+//! ```rust
+//! use std::time::Duration;
+//!
+//! use metrics_server::MetricsServer;
+//!
+//! // Create a new server with a larger connection backlog that resets closed connections.
+//! let mut server = MetricsServer::builder()
+//!     .address("localhost:8001")
+//!     .backlog(1024)
+//!     .linger(Some(Duration::ZERO))
+//!     .build()
+//!     .unwrap();
+//! server.serve();
+//!
+//! // Stop the server.
+//! server.stop().unwrap();
+//! ```
+mod acl;
+mod addr;
+#[cfg(feature = "tokio")]
+mod asynchronous;
+mod audit;
+mod auth;
+mod builder;
+mod clock;
 mod error;
+mod exposition;
+mod filter;
+pub mod global;
+mod json;
+#[cfg(feature = "jwt")]
+mod jwt;
+mod limit;
+mod listener;
+#[cfg(unix)]
+mod mmap;
+mod normalize;
+mod page;
+mod path;
+mod probe;
+mod request;
+mod response;
+#[cfg(feature = "tokio")]
+mod runtime;
+mod secret;
+mod section;
 mod server;
+#[cfg(feature = "tower")]
+mod service;
+mod stats;
+#[cfg(feature = "tls")]
+mod tls;
+mod vars;
 
+pub use addr::ScopedAddr;
+pub use audit::{AuditEvent, AuditKind};
+pub use auth::{AuthDecision, Authenticator, BasicAuth, BearerAuth, DigestAuth, RequestMeta};
+pub use builder::{Builder, ListenerConfig};
+pub use clock::{Clock, MockClock, SystemClock};
 pub use error::ServerError;
-pub use server::{MetricsServer, DEFAULT_METRICS_PATH};
+pub use exposition::{validate, ParseError};
+pub use filter::{Aggregation, Redaction, Rule};
+#[cfg(feature = "jwt")]
+pub use jwt::JwtAuth;
+pub use path::MetricsPath;
+pub use probe::{probe, ProbeResponse};
+pub use server::{MetricsHandle, MetricsServer, DEFAULT_CONTENT_TYPE, DEFAULT_METRICS_PATH};
+#[cfg(feature = "tower")]
+pub use service::MetricsService;
+#[cfg(feature = "tls")]
+pub use tls::TlsVersion;
+pub use vars::Var;
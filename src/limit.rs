@@ -0,0 +1,70 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Limits the rate of requests with a token bucket.
+///
+/// The bucket holds up to `burst` tokens and is refilled at `rate` tokens per second. Each
+/// request takes a token, and is rejected if none are left.
+pub(crate) struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    // The tokens left, and when they were last refilled.
+    state: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    /// Creates a bucket, full at the given time, allowing `rate` requests per second, and bursts
+    /// of up to `burst`.
+    pub(crate) fn new(rate: u32, burst: u32, now: Instant) -> Self {
+        let burst = f64::from(burst.max(1));
+        RateLimiter {
+            rate: f64::from(rate.max(1)),
+            burst,
+            state: Mutex::new((burst, now)),
+        }
+    }
+
+    /// Takes a token if one is available at the given time, otherwise returns how long until one
+    /// will be.
+    pub(crate) fn acquire(&self, now: Instant) -> Result<(), Duration> {
+        let mut state = self.state.lock().unwrap();
+        let (tokens, last) = &mut *state;
+
+        let elapsed = now.saturating_duration_since(*last).as_secs_f64();
+        *tokens = (*tokens + elapsed * self.rate).min(self.burst);
+        *last = now;
+
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - *tokens) / self.rate))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let start = Instant::now();
+        let limiter = RateLimiter::new(2, 3, start);
+
+        // Assert a full bucket allows a burst.
+        for _ in 0..3 {
+            assert!(limiter.acquire(start).is_ok());
+        }
+        assert_eq!(limiter.acquire(start), Err(Duration::from_millis(500)));
+
+        // Assert tokens are refilled over time, up to the burst size.
+        assert!(limiter.acquire(start + Duration::from_millis(500)).is_ok());
+        assert!(limiter.acquire(start + Duration::from_millis(500)).is_err());
+        let later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(limiter.acquire(later).is_ok());
+        }
+        assert!(limiter.acquire(later).is_err());
+    }
+}
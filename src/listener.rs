@@ -0,0 +1,458 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+
+use crate::auth::Authenticator;
+use crate::probe::{self, ProbeResponse};
+use crate::response::SendFile;
+
+// How long to wait for a whole request to arrive on a newly accepted connection.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+// How long to wait for a client to accept each write of a response.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Low-level socket options used when binding the listener and accepting connections.
+#[derive(Clone, Debug)]
+pub(crate) struct SocketConfig {
+    /// The maximum length of the queue of pending connections.
+    pub(crate) backlog: i32,
+    /// Whether the listener should accept connections in nonblocking mode.
+    pub(crate) nonblocking: bool,
+    /// The `SO_LINGER` value applied to accepted connections.
+    pub(crate) linger: Option<Duration>,
+    /// Whether `SO_REUSEADDR` is set on the listener.
+    pub(crate) reuse_address: bool,
+    /// Whether `SO_REUSEPORT` is set on the listener.
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    pub(crate) reuse_port: bool,
+    /// The time allowed to read a whole request from an accepted connection.
+    pub(crate) read_timeout: Duration,
+    /// The time allowed for each write to an accepted connection, if limited.
+    pub(crate) write_timeout: Option<Duration>,
+}
+
+impl Default for SocketConfig {
+    fn default() -> Self {
+        SocketConfig {
+            backlog: 128,
+            nonblocking: false,
+            linger: None,
+            // Match the behaviour of the standard library listener.
+            reuse_address: cfg!(not(windows)),
+            #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+            reuse_port: false,
+            read_timeout: READ_TIMEOUT,
+            write_timeout: Some(WRITE_TIMEOUT),
+        }
+    }
+}
+
+/// A stream that a request can be read from and a response written to.
+pub(crate) trait Stream: Read + Write + SendFile + Send {
+    /// Gracefully closes the write half of the stream once a response has been sent.
+    ///
+    /// Clients that read until EOF, such as HTTP/1.0 clients, rely on this to receive the
+    /// entire response body before the connection is torn down.
+    fn close(&mut self) -> io::Result<()>;
+
+    /// Returns the underlying socket, e.g. to set its options.
+    fn socket(&self) -> &TcpStream;
+}
+
+impl Stream for TcpStream {
+    fn close(&mut self) -> io::Result<()> {
+        self.flush()?;
+        self.shutdown(Shutdown::Write)
+    }
+
+    fn socket(&self) -> &TcpStream {
+        self
+    }
+}
+
+impl SendFile for Box<dyn Stream> {
+    fn send_file(&mut self, file: &mut File, len: u64) -> io::Result<()> {
+        (**self).send_file(file, len)
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl SendFile for TcpStream {
+    fn send_file(&mut self, file: &mut File, len: u64) -> io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        let mut remaining = len;
+        while remaining > 0 {
+            // sendfile transfers at most 0x7ffff000 bytes per call.
+            let count = remaining.min(0x7fff_f000) as usize;
+            // SAFETY: both file descriptors remain open for the duration of the call, and a
+            // null offset reads from, and advances, the file's current position.
+            let n = unsafe {
+                libc::sendfile(
+                    self.as_raw_fd(),
+                    file.as_raw_fd(),
+                    std::ptr::null_mut(),
+                    count,
+                )
+            };
+            match n {
+                -1 => {
+                    let e = io::Error::last_os_error();
+                    match e.raw_os_error() {
+                        Some(libc::EINTR) => continue,
+                        // Some files, such as those in procfs, can't be used with sendfile.
+                        Some(libc::EINVAL | libc::ENOSYS) => {
+                            return crate::response::copy_file(self, file, remaining)
+                        }
+                        _ => return Err(e),
+                    }
+                }
+                0 => return Err(crate::response::truncated()),
+                n => remaining -= n as u64,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl SendFile for TcpStream {}
+
+#[cfg(feature = "tls")]
+impl SendFile for rustls::StreamOwned<rustls::ServerConnection, TcpStream> {}
+
+#[cfg(feature = "tls")]
+impl Stream for rustls::StreamOwned<rustls::ServerConnection, TcpStream> {
+    fn close(&mut self) -> io::Result<()> {
+        self.conn.send_close_notify();
+        self.flush()?;
+        self.sock.shutdown(Shutdown::Write)
+    }
+
+    fn socket(&self) -> &TcpStream {
+        &self.sock
+    }
+}
+
+/// A stream that must finish sending its request by a deadline, so clients sending requests
+/// slowly, a few bytes at a time, can't hold a connection open indefinitely.
+pub(crate) struct Deadline<S> {
+    inner: S,
+    deadline: Instant,
+}
+
+impl<S> Deadline<S> {
+    /// Wraps a stream, allowing `timeout` from now for every read.
+    pub(crate) fn new(inner: S, timeout: Duration) -> Self {
+        Deadline {
+            inner,
+            deadline: Instant::now() + timeout,
+        }
+    }
+}
+
+impl<S: Stream> Read for Deadline<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Zero timeouts are rejected by the socket, and would otherwise block forever.
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        self.inner.socket().set_read_timeout(Some(remaining))?;
+        self.inner.read(buf)
+    }
+}
+
+impl<S: Stream> Write for Deadline<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: Stream> SendFile for Deadline<S> {
+    fn send_file(&mut self, file: &mut File, len: u64) -> io::Result<()> {
+        self.inner.send_file(file, len)
+    }
+}
+
+impl<S: Stream> Stream for Deadline<S> {
+    fn close(&mut self) -> io::Result<()> {
+        self.inner.close()
+    }
+
+    fn socket(&self) -> &TcpStream {
+        self.inner.socket()
+    }
+}
+
+/// An accepted client connection.
+pub(crate) struct Connection {
+    pub(crate) stream: Box<dyn Stream>,
+    pub(crate) remote_addr: Option<SocketAddr>,
+    /// A handle to the underlying socket, used to abort the connection when stopping.
+    pub(crate) socket: TcpStream,
+}
+
+/// A TCP listener bound with the configured socket options.
+pub(crate) struct Listener {
+    inner: TcpListener,
+    addr: SocketAddr,
+    config: SocketConfig,
+    path: Option<String>,
+    auth: Option<Option<Arc<dyn Authenticator>>>,
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<rustls::ServerConfig>>,
+    // Replaces the certificate, if it was given as PEM data.
+    #[cfg(feature = "tls")]
+    pem: Option<Arc<crate::tls::PemResolver>>,
+    // Whether TLS clients must present a certificate.
+    #[cfg(feature = "tls")]
+    client_auth: bool,
+}
+
+impl Listener {
+    /// Binds a listener to the first of the given addresses that succeeds.
+    pub(crate) fn bind(addrs: &[SocketAddr], config: SocketConfig) -> io::Result<Self> {
+        let mut last_err = None;
+        for addr in addrs {
+            match bind_socket(addr, &config) {
+                Ok(inner) => return Listener::from_std(inner, config),
+                Err(e) => last_err = Some(e),
+            }
+        }
+
+        Err(last_err.unwrap_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "could not resolve to any addresses",
+            )
+        }))
+    }
+
+    /// Wraps a listener that is already bound and listening.
+    ///
+    /// The backlog is fixed once a socket is listening, so only the remaining options are applied.
+    pub(crate) fn from_std(inner: TcpListener, config: SocketConfig) -> io::Result<Self> {
+        inner.set_nonblocking(config.nonblocking)?;
+        Ok(Listener {
+            addr: inner.local_addr()?,
+            inner,
+            config,
+            path: None,
+            auth: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+            #[cfg(feature = "tls")]
+            pem: None,
+            #[cfg(feature = "tls")]
+            client_auth: false,
+        })
+    }
+
+    /// Overrides the server's metrics path for requests to this listener.
+    pub(crate) fn with_path(mut self, path: Option<String>) -> Self {
+        self.path = path;
+        self
+    }
+
+    /// Overrides the server's authentication for requests to this listener.
+    ///
+    /// `Some(None)` disables authentication even if it is configured for the server.
+    pub(crate) fn with_auth(mut self, auth: Option<Option<Arc<dyn Authenticator>>>) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    /// Terminates TLS on all connections accepted by this listener, which requires clients to
+    /// present a certificate if `client_auth` is true.
+    #[cfg(feature = "tls")]
+    pub(crate) fn with_tls(mut self, tls: crate::tls::ServerTls, client_auth: bool) -> Self {
+        self.tls = Some(tls.config);
+        self.pem = tls.pem;
+        self.client_auth = client_auth;
+        self
+    }
+
+    /// Returns a handle to replace the listener's certificate, if it was given as PEM data.
+    #[cfg(feature = "tls")]
+    pub(crate) fn pem(&self) -> Option<&crate::tls::PemResolver> {
+        self.pem.as_deref()
+    }
+
+    /// Returns a tokio listener accepting connections from the same socket.
+    ///
+    /// The socket is switched to nonblocking mode, so it can't also be served synchronously.
+    /// TLS isn't supported asynchronously, so listeners terminating TLS are an error.
+    #[cfg(feature = "tokio")]
+    pub(crate) fn to_async(&self) -> io::Result<tokio::net::TcpListener> {
+        #[cfg(feature = "tls")]
+        if self.tls.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "TLS listeners can't be served asynchronously",
+            ));
+        }
+
+        let inner = self.inner.try_clone()?;
+        inner.set_nonblocking(true)?;
+        tokio::net::TcpListener::from_std(inner)
+    }
+
+    /// Returns the socket options used for accepted connections.
+    #[cfg(feature = "tokio")]
+    pub(crate) fn socket_config(&self) -> &SocketConfig {
+        &self.config
+    }
+
+    /// Applies the configured socket options to a connection accepted asynchronously.
+    #[cfg(feature = "tokio")]
+    pub(crate) fn configure_async(&self, stream: &tokio::net::TcpStream) -> io::Result<()> {
+        SockRef::from(stream).set_linger(self.config.linger)
+    }
+
+    /// Accepts a new connection, returning None if none are pending in nonblocking mode.
+    pub(crate) fn accept(&self) -> io::Result<Option<Connection>> {
+        let (stream, remote_addr) = match self.inner.accept() {
+            Ok(conn) => conn,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        // Accepted sockets may inherit nonblocking mode from the listener on some platforms.
+        stream.set_nonblocking(false)?;
+        stream.set_write_timeout(self.config.write_timeout)?;
+        SockRef::from(&stream).set_linger(self.config.linger)?;
+        let socket = stream.try_clone()?;
+        let timeout = self.config.read_timeout;
+
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &self.tls {
+            let conn = rustls::ServerConnection::new(Arc::clone(tls))
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            let stream = rustls::StreamOwned::new(conn, stream);
+            return Ok(Some(Connection {
+                stream: Box::new(Deadline::new(stream, timeout)),
+                remote_addr: Some(remote_addr),
+                socket,
+            }));
+        }
+
+        Ok(Some(Connection {
+            stream: Box::new(Deadline::new(stream, timeout)),
+            remote_addr: Some(remote_addr),
+            socket,
+        }))
+    }
+
+    /// Returns the local address that this listener is bound to.
+    pub(crate) fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns the metrics path served by this listener, if overridden.
+    pub(crate) fn path(&self) -> Option<&str> {
+        self.path.as_deref()
+    }
+
+    /// Returns the authentication required by this listener, if overridden.
+    pub(crate) fn auth(&self) -> Option<Option<&dyn Authenticator>> {
+        self.auth.as_ref().map(|a| a.as_deref())
+    }
+
+    /// Returns true if the listener terminates TLS.
+    pub(crate) fn is_tls(&self) -> bool {
+        #[cfg(feature = "tls")]
+        return self.tls.is_some();
+        #[cfg(not(feature = "tls"))]
+        false
+    }
+
+    /// Returns true if the listener accepts connections in nonblocking mode.
+    pub(crate) fn is_nonblocking(&self) -> bool {
+        self.config.nonblocking
+    }
+
+    /// Wakes up a thread blocked in `accept` by connecting to the listener.
+    pub(crate) fn unblock(&self) {
+        if self.config.nonblocking {
+            return;
+        }
+
+        let _ = TcpStream::connect_timeout(&self.connect_addr(), Duration::from_secs(1));
+    }
+
+    /// Requests the given path from this listener over a loopback connection, completing a TLS
+    /// handshake first if the listener terminates TLS.
+    ///
+    /// Returns None if the listener rejected the connection for lacking a client certificate,
+    /// after its own certificate was verified.
+    pub(crate) fn self_check(&self, path: &str) -> io::Result<Option<ProbeResponse>> {
+        let addr = self.connect_addr();
+        let stream = probe::connect(addr)?;
+
+        #[cfg(feature = "tls")]
+        if self.tls.is_some() {
+            let name = rustls::pki_types::ServerName::from(addr.ip());
+            let mut conn = rustls::ClientConnection::new(crate::tls::self_check_config(), name)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            let mut stream = stream;
+            // Complete the handshake up front, so a failure isn't reported as a failed request.
+            let res = (|| {
+                while conn.is_handshaking() {
+                    conn.complete_io(&mut stream)?;
+                }
+                let stream = rustls::StreamOwned::new(conn, stream);
+                probe::request(stream, &addr.to_string(), path)
+            })();
+            return match res {
+                Err(e) if self.client_auth && crate::tls::is_certificate_required(&e) => Ok(None),
+                res => res.map(Some),
+            };
+        }
+
+        probe::request(stream, &addr.to_string(), path).map(Some)
+    }
+
+    // Returns the address to connect to this listener at. Unspecified addresses can't be
+    // connected to, so loopback is used instead.
+    fn connect_addr(&self) -> SocketAddr {
+        let mut addr = self.addr;
+        match addr {
+            SocketAddr::V4(ref mut a) if a.ip().is_unspecified() => a.set_ip(Ipv4Addr::LOCALHOST),
+            SocketAddr::V6(ref mut a) if a.ip().is_unspecified() => a.set_ip(Ipv6Addr::LOCALHOST),
+            _ => {}
+        }
+        addr
+    }
+}
+
+// Creates a listening socket with the given options applied.
+fn bind_socket(addr: &SocketAddr, config: &SocketConfig) -> io::Result<TcpListener> {
+    let socket = Socket::new(
+        Domain::for_address(*addr),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+
+    if config.reuse_address {
+        socket.set_reuse_address(true)?;
+    }
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    if config.reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+
+    socket.bind(&(*addr).into())?;
+    socket.listen(config.backlog)?;
+    socket.set_nonblocking(config.nonblocking)?;
+
+    Ok(socket.into())
+}
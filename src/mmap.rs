@@ -0,0 +1,110 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::ops::Deref;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Distinguishes the files backing mappings created by this process.
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// A read-only, memory-mapped copy of a payload.
+///
+/// Every mapping is backed by its own unlinked file, so a mapping is never written to once
+/// created and can be shared between threads without further synchronization. The file is
+/// removed by the OS once the mapping is dropped.
+pub(crate) struct Mmap {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// SAFETY: the mapping is read-only and owned exclusively by this value.
+unsafe impl Send for Mmap {}
+unsafe impl Sync for Mmap {}
+
+impl Mmap {
+    /// Writes the data to a new file in the given directory and maps it into memory.
+    ///
+    /// Empty data can't be mapped, so is an error.
+    pub(crate) fn new(dir: &Path, data: &[u8]) -> io::Result<Self> {
+        if data.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "empty payloads can't be memory-mapped",
+            ));
+        }
+
+        let path = dir.join(format!(
+            ".metrics_server.{}.{}",
+            std::process::id(),
+            NEXT_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        // Unlink the file straight away so it can't outlive the mapping.
+        fs::remove_file(&path)?;
+        file.write_all(data)?;
+
+        // SAFETY: the file is open for reading and at least `data.len()` bytes long.
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                data.len(),
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Mmap {
+            ptr: ptr.cast(),
+            len: data.len(),
+        })
+    }
+}
+
+impl Deref for Mmap {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: the mapping is `len` bytes long and valid until dropped.
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        // SAFETY: the mapping was created by mmap with this length and is no longer borrowed.
+        unsafe {
+            libc::munmap(self.ptr.cast(), self.len);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mmap() {
+        let dir = std::env::temp_dir();
+        let data = b"my_awesome_metric 10\n";
+        let mmap = Mmap::new(&dir, data).unwrap();
+        assert_eq!(&*mmap, data);
+
+        // Assert mappings are independent of each other.
+        let other = Mmap::new(&dir, b"other 1\n").unwrap();
+        drop(mmap);
+        assert_eq!(&*other, b"other 1\n");
+
+        assert!(Mmap::new(&dir, b"").is_err());
+        assert!(Mmap::new(Path::new("/nonexistent"), data).is_err());
+    }
+}
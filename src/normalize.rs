@@ -0,0 +1,137 @@
+use std::borrow::Cow;
+
+/// How the case of a URL path is treated when it is normalized.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum CaseFolding {
+    /// Paths are case sensitive, and kept as given.
+    #[default]
+    Preserve,
+    /// Paths are converted to lowercase.
+    Lowercase,
+}
+
+/// Normalizes a URL path, so that equivalent paths compare equal.
+///
+/// Duplicate slashes are collapsed and `.` and `..` segments are removed, as described in
+/// RFC 3986 section 5.2.4, before the case folding policy is applied. The result always starts
+/// with a `/`, and only ends with one if the original path did. Percent-encoded characters are
+/// left as they are.
+pub(crate) fn normalize_path(path: &str, case: CaseFolding) -> Cow<'_, str> {
+    let folded = match case {
+        CaseFolding::Preserve => false,
+        CaseFolding::Lowercase => path.bytes().any(|b| b.is_ascii_uppercase()),
+    };
+    if is_normalized(path) && !folded {
+        return Cow::Borrowed(path);
+    }
+
+    let mut segments: Vec<&str> = Vec::new();
+    let mut trailing = false;
+    for segment in path.split('/') {
+        trailing = matches!(segment, "" | "." | "..");
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+
+    let mut out = String::with_capacity(path.len() + 1);
+    for segment in &segments {
+        out.push('/');
+        out.push_str(segment);
+    }
+    if trailing || segments.is_empty() {
+        out.push('/');
+    }
+    if folded {
+        out.make_ascii_lowercase();
+    }
+    Cow::Owned(out)
+}
+
+// Returns true if the path is already in normal form, which most request paths are.
+fn is_normalized(path: &str) -> bool {
+    match path.strip_prefix('/') {
+        None => false,
+        Some("") => true,
+        // Only the final segment may be empty, i.e. the path ends with a slash.
+        Some(rest) => rest
+            .strip_suffix('/')
+            .unwrap_or(rest)
+            .split('/')
+            .all(|segment| !matches!(segment, "" | "." | "..")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_normalize_path() {
+        let cases = [
+            ("/metrics", "/metrics"),
+            ("metrics", "/metrics"),
+            ("", "/"),
+            ("/", "/"),
+            ("//metrics", "/metrics"),
+            ("/debug//metrics/", "/debug/metrics/"),
+            ("/debug/./metrics", "/debug/metrics"),
+            ("/debug/../metrics", "/metrics"),
+            ("/../../metrics", "/metrics"),
+            ("/debug/metrics/..", "/debug/"),
+            ("/debug/metrics/.", "/debug/metrics/"),
+            ("/debug/..metrics", "/debug/..metrics"),
+            ("/Debug/Metrics", "/Debug/Metrics"),
+            ("/m%C3%A9trics", "/m%C3%A9trics"),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(normalize_path(path, CaseFolding::Preserve), expected);
+        }
+
+        assert_eq!(
+            normalize_path("/Debug//Metrics", CaseFolding::Lowercase),
+            "/debug/metrics"
+        );
+
+        // Assert paths that are already normalized aren't copied.
+        assert!(matches!(
+            normalize_path("/debug/metrics/", CaseFolding::Preserve),
+            Cow::Borrowed(_)
+        ));
+        assert!(matches!(
+            normalize_path("/debug/metrics", CaseFolding::Lowercase),
+            Cow::Borrowed(_)
+        ));
+    }
+
+    proptest! {
+        #[test]
+        fn prop_normalize_path_is_idempotent(path in "[a-zA-Z./%_-]{0,32}") {
+            for case in [CaseFolding::Preserve, CaseFolding::Lowercase] {
+                let once = normalize_path(&path, case);
+                prop_assert_eq!(normalize_path(&once, case), once.as_ref());
+            }
+        }
+
+        #[test]
+        fn prop_normalize_path_has_no_dot_segments(path in "[a-zA-Z./]{0,32}") {
+            let normalized = normalize_path(&path, CaseFolding::Preserve);
+            prop_assert!(normalized.starts_with('/'));
+            prop_assert!(!normalized.contains("//"));
+            prop_assert!(normalized.split('/').all(|s| s != "." && s != ".."));
+        }
+
+        #[test]
+        fn prop_normalize_path_folds_case(path in "/[a-zA-Z/]{0,32}") {
+            let lower = normalize_path(&path, CaseFolding::Lowercase);
+            let preserved = normalize_path(&path, CaseFolding::Preserve);
+            prop_assert_eq!(lower.as_ref(), preserved.to_ascii_lowercase());
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use std::fmt::Write;
+use std::ops::Range;
+
+use crate::exposition::{self, Line};
+
+// Suffixes of samples belonging to a metric family, e.g. the buckets of a histogram.
+const FAMILY_SUFFIXES: [&str; 8] = [
+    "_bucket", "_count", "_created", "_gcount", "_gsum", "_info", "_sum", "_total",
+];
+
+/// A page of the metrics payload, containing one or more whole metric families.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Page {
+    pub(crate) range: Range<usize>,
+    pub(crate) families: Vec<String>,
+}
+
+/// Splits a payload in the text exposition format into pages of at most `max_size` bytes.
+///
+/// Metric families are never split across pages, so a family larger than `max_size` is served
+/// on a page of its own. Families are assigned to pages in the order they appear, so pages only
+/// change when the families before them do. Payloads that aren't valid UTF-8 are served as a
+/// single page.
+pub(crate) fn paginate(data: &[u8], max_size: usize) -> Vec<Page> {
+    let Ok(text) = std::str::from_utf8(data) else {
+        return vec![Page {
+            range: 0..data.len(),
+            families: Vec::new(),
+        }];
+    };
+
+    let mut pages: Vec<Page> = Vec::new();
+    for (family, range) in families(text) {
+        match pages.last_mut() {
+            Some(page) if range.end - page.range.start <= max_size => {
+                page.range.end = range.end;
+                page.families.extend(family.map(str::to_string));
+            }
+            _ => pages.push(Page {
+                range,
+                families: family.map(str::to_string).into_iter().collect(),
+            }),
+        }
+    }
+    pages
+}
+
+/// Writes a plain text index describing each page, e.g. `page=1 bytes=1024 families=a,b`.
+pub(crate) fn index(pages: &[Page]) -> Vec<u8> {
+    let mut out = String::new();
+    for (i, page) in pages.iter().enumerate() {
+        let _ = writeln!(
+            out,
+            "page={} bytes={} families={}",
+            i + 1,
+            page.range.len(),
+            page.families.join(",")
+        );
+    }
+    out.into_bytes()
+}
+
+// Returns the byte range of each metric family in the payload, along with its name.
+fn families(text: &str) -> Vec<(Option<&str>, Range<usize>)> {
+    let mut families: Vec<(Option<&str>, Range<usize>)> = Vec::new();
+    let mut start = 0;
+    for line in text.split_inclusive('\n') {
+        let end = start + line.len();
+        let name = match Line::parse(line.trim_end()) {
+            Line::Sample(sample) => Some(sample.name),
+            Line::Other(line) => exposition::metadata_family(line),
+        };
+
+        match (families.last_mut(), name) {
+            // Lines continuing the current family, or comments, extend it.
+            (Some((current, range)), name)
+                if name.is_none() || name.zip(*current).is_some_and(|(n, f)| belongs(n, f)) =>
+            {
+                range.end = end;
+                if current.is_none() {
+                    *current = name;
+                }
+            }
+            _ => families.push((name, start..end)),
+        }
+        start = end;
+    }
+    families
+}
+
+// Returns whether a sample or metadata name belongs to the given family.
+pub(crate) fn belongs(name: &str, family: &str) -> bool {
+    match name.strip_prefix(family) {
+        Some("") => true,
+        Some(suffix) => FAMILY_SUFFIXES.contains(&suffix),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATA: &str = "# TYPE a counter\n\
+        a_total 1\n\
+        # TYPE b histogram\n\
+        b_bucket{le=\"+Inf\"} 1\n\
+        b_sum 1\n\
+        b_count 1\n\
+        c 1\n\
+        c 2\n";
+
+    #[test]
+    fn test_paginate() {
+        let pages = paginate(DATA.as_bytes(), 40);
+        let families: Vec<_> = pages.iter().map(|p| p.families.join(",")).collect();
+        assert_eq!(families, ["a", "b", "c"]);
+
+        // Assert every byte is served exactly once, in order.
+        let served: String = pages.iter().map(|p| &DATA[p.range.clone()]).collect();
+        assert_eq!(served, DATA);
+        assert!(pages[1].range.len() > 40);
+
+        // Assert small families are packed together.
+        let pages = paginate(DATA.as_bytes(), 1024);
+        assert_eq!(pages.len(), 1);
+        assert_eq!(pages[0].families, ["a", "b", "c"]);
+
+        // Assert non text payloads are served as a single page.
+        let pages = paginate(&[0xff, 0xfe], 1);
+        assert_eq!(
+            pages,
+            [Page {
+                range: 0..2,
+                families: Vec::new()
+            }]
+        );
+    }
+
+    #[test]
+    fn test_index() {
+        let pages = paginate(DATA.as_bytes(), 40);
+        let index = String::from_utf8(index(&pages)).unwrap();
+        let lines: Vec<_> = index.lines().collect();
+        assert_eq!(lines[0], "page=1 bytes=27 families=a");
+        assert_eq!(lines.len(), 3);
+    }
+}
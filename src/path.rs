@@ -0,0 +1,155 @@
+use std::fmt;
+use std::str::FromStr;
+
+use http::uri::PathAndQuery;
+use log::error;
+
+use crate::error::ServerError;
+use crate::normalize::{normalize_path, CaseFolding};
+use crate::server::DEFAULT_METRICS_PATH;
+
+/// A validated URL path that metrics are served at.
+///
+/// Paths created with `MetricsPath::new` are used exactly as given, so mistakes are caught when
+/// the path is constructed rather than silently changing which URL is served. Plain strings
+/// passed to `MetricsServer::serve_uri` or `ListenerConfig::path` are converted with
+/// `MetricsPath::legacy` instead.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct MetricsPath(String);
+
+impl MetricsPath {
+    /// Validates a URL path, which must be an absolute ASCII path without a query or fragment.
+    ///
+    /// The path keeps its case, and is matched exactly against the path of each request unless
+    /// `Builder::case_insensitive_paths` is enabled.
+    pub fn new(path: &str) -> Result<Self, ServerError> {
+        let invalid = |reason| ServerError::InvalidPath(format!("{path:?}: {reason}"));
+        if !path.starts_with('/') {
+            return Err(invalid("must start with '/'"));
+        }
+        if !path.is_ascii() {
+            return Err(invalid("must only contain ASCII characters"));
+        }
+        if path.contains(['?', '#']) {
+            return Err(invalid("must not contain a query or fragment"));
+        }
+        if normalize_path(path, CaseFolding::Preserve) != path {
+            return Err(invalid("must not contain empty, `.` or `..` segments"));
+        }
+
+        match PathAndQuery::from_str(path) {
+            Ok(pq) if pq.path() == path => Ok(MetricsPath(path.to_string())),
+            _ => Err(invalid("contains invalid characters")),
+        }
+    }
+
+    /// Converts a URL path the way versions before `MetricsPath` did.
+    ///
+    /// The path is normalized and lowercased. Invalid paths are logged, and replaced with the
+    /// default metrics path.
+    pub fn legacy(path: &str) -> Self {
+        let uri = normalize_path(path, CaseFolding::Lowercase);
+        match PathAndQuery::from_str(&uri) {
+            Ok(pq) if pq.path().is_ascii() => MetricsPath(pq.path().to_string()),
+            _ => {
+                error!("invalid uri, defaulting to {DEFAULT_METRICS_PATH}");
+                MetricsPath::default()
+            }
+        }
+    }
+
+    /// Returns the path as a string slice.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub(crate) fn into_string(self) -> String {
+        self.0
+    }
+}
+
+impl Default for MetricsPath {
+    fn default() -> Self {
+        MetricsPath(DEFAULT_METRICS_PATH.to_string())
+    }
+}
+
+impl FromStr for MetricsPath {
+    type Err = ServerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        MetricsPath::new(s)
+    }
+}
+
+impl From<&str> for MetricsPath {
+    fn from(path: &str) -> Self {
+        MetricsPath::legacy(path)
+    }
+}
+
+impl From<String> for MetricsPath {
+    fn from(path: String) -> Self {
+        MetricsPath::legacy(&path)
+    }
+}
+
+impl AsRef<str> for MetricsPath {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for MetricsPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new() {
+        assert_eq!(MetricsPath::new("/metrics").unwrap().as_str(), "/metrics");
+        assert_eq!(
+            MetricsPath::new("/Debug/Metrics").unwrap().as_str(),
+            "/Debug/Metrics"
+        );
+
+        for path in [
+            "",
+            "metrics",
+            "/metr ics",
+            "/mëtrîcs",
+            "/metrics?page=1",
+            "/metrics#top",
+            "/metrics/../metrics",
+            "//metrics",
+        ] {
+            assert!(
+                matches!(MetricsPath::new(path), Err(ServerError::InvalidPath(_))),
+                "{path:?} should be invalid"
+            );
+        }
+    }
+
+    #[test]
+    fn test_legacy() {
+        let expected_default = MetricsPath::default();
+        let expected_valid = MetricsPath::new("/debug/metrics").unwrap();
+
+        // Invalid.
+        assert_eq!(MetricsPath::legacy("Hello, World!"), expected_default);
+        // Whitespace.
+        assert_eq!(MetricsPath::legacy(" metr ics  "), expected_default);
+        // Non-ASCII.
+        assert_eq!(MetricsPath::legacy("mëtrîcs"), expected_default);
+        // Valid.
+        assert_eq!(MetricsPath::legacy("/debug/metrics"), expected_valid);
+        assert_eq!(MetricsPath::legacy("debug/metrics"), expected_valid);
+        assert_eq!(MetricsPath::legacy("DEBUG/METRICS"), expected_valid);
+        assert_eq!(MetricsPath::legacy("/debug//./metrics"), expected_valid);
+    }
+}
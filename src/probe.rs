@@ -0,0 +1,158 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+// How long a probe waits to connect, and for each read or write, before failing.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The response to a request sent by `probe`.
+#[derive(Clone, Debug)]
+pub struct ProbeResponse {
+    /// The HTTP status code.
+    pub status: u16,
+    /// The response body, e.g. the metrics payload.
+    pub body: Vec<u8>,
+}
+
+impl ProbeResponse {
+    /// Returns true if the status code is in the 2xx range.
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+}
+
+/// Sends a plain HTTP `GET` request for the given path and returns the response.
+///
+/// This is a minimal blocking client for self-checks and readiness probes, e.g. verifying that
+/// a `MetricsServer` in the same binary is serving, without depending on a full HTTP client.
+/// Each of the resolved addresses is tried in turn, and connecting, writing and reading each
+/// time out after 5 seconds. HTTPS isn't supported, so probe a plain HTTP listener, such as one
+/// added on localhost with `Builder::listener`.
+///
+/// ```rust
+/// let server = metrics_server::MetricsServer::http("127.0.0.1:0");
+/// let res = metrics_server::probe(server.local_addr(), "/metrics")?;
+/// assert!(res.is_success());
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn probe<A>(addr: A, path: &str) -> io::Result<ProbeResponse>
+where
+    A: ToSocketAddrs,
+{
+    let mut last_err = None;
+    for addr in addr.to_socket_addrs()? {
+        match get(addr, path) {
+            Ok(res) => return Ok(res),
+            Err(e) => last_err = Some(e),
+        }
+    }
+
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "could not resolve to any addresses",
+        )
+    }))
+}
+
+// Sends a single request to the given address.
+fn get(addr: SocketAddr, path: &str) -> io::Result<ProbeResponse> {
+    request(connect(addr)?, &addr.to_string(), path)
+}
+
+// Connects to the given address, applying the probe timeouts.
+pub(crate) fn connect(addr: SocketAddr) -> io::Result<TcpStream> {
+    let stream = TcpStream::connect_timeout(&addr, PROBE_TIMEOUT)?;
+    stream.set_read_timeout(Some(PROBE_TIMEOUT))?;
+    stream.set_write_timeout(Some(PROBE_TIMEOUT))?;
+    Ok(stream)
+}
+
+// Sends a `GET` request for the given path over an established connection.
+pub(crate) fn request<S>(mut stream: S, host: &str, path: &str) -> io::Result<ProbeResponse>
+where
+    S: Read + Write,
+{
+    write!(
+        stream,
+        "GET {path} HTTP/1.1\r\nHost: {host}\r\nConnection: close\r\n\r\n"
+    )?;
+    stream.flush()?;
+
+    read_response(BufReader::new(stream))
+}
+
+// Parses a response, reading the body until the connection is closed if it has no length.
+fn read_response<R>(mut reader: R) -> io::Result<ProbeResponse>
+where
+    R: BufRead,
+{
+    let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let status = line
+        .strip_prefix("HTTP/1.")
+        .and_then(|l| l.split_ascii_whitespace().nth(1))
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| invalid("invalid status line"))?;
+
+    let mut content_length = None;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(invalid("incomplete response head"));
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                let len = value.trim().parse::<u64>();
+                content_length = Some(len.map_err(|_| invalid("invalid content length"))?);
+            }
+        }
+    }
+
+    let mut body = Vec::new();
+    match content_length {
+        Some(len) => {
+            reader.take(len).read_to_end(&mut body)?;
+            if (body.len() as u64) < len {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+        None => {
+            reader.read_to_end(&mut body)?;
+        }
+    }
+    Ok(ProbeResponse { status, body })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_response() {
+        let raw = "HTTP/1.1 200 OK\r\nContent-Length: 4\r\nconnection: close\r\n\r\na 1\nextra";
+        let res = read_response(raw.as_bytes()).unwrap();
+        assert_eq!(res.status, 200);
+        assert_eq!(res.body, b"a 1\n");
+        assert!(res.is_success());
+
+        let res = read_response("HTTP/1.0 404 Not Found\r\n\r\n".as_bytes()).unwrap();
+        assert_eq!(res.status, 404);
+        assert!(res.body.is_empty());
+        assert!(!res.is_success());
+
+        let res = read_response("HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\na 1\n".as_bytes());
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+        for raw in ["", "SSH-2.0-OpenSSH\r\n", "HTTP/1.1 200 OK\r\n"] {
+            let res = read_response(raw.as_bytes());
+            assert_eq!(res.unwrap_err().kind(), io::ErrorKind::InvalidData, "{raw}");
+        }
+    }
+}
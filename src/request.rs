@@ -0,0 +1,532 @@
+use std::io::{self, BufRead, Read};
+use std::net::SocketAddr;
+
+#[cfg(feature = "gzip")]
+use http::header::ACCEPT_ENCODING;
+use http::header::{ACCEPT, CONTENT_LENGTH, EXPECT, TRANSFER_ENCODING};
+use http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode, Version};
+
+use crate::addr;
+
+// The maximum size in bytes of a single header line.
+const MAX_HEADER_LINE_SIZE: usize = 8 * 1024;
+
+// Allowance for the method and version surrounding the URL in the request line.
+const REQUEST_LINE_OVERHEAD: usize = 32;
+
+/// Limits applied while parsing a request head.
+#[derive(Clone, Debug)]
+pub(crate) struct Limits {
+    /// The maximum length of the request URL, above which 414 is returned.
+    pub(crate) max_url_length: usize,
+    /// The maximum number of request headers, above which 431 is returned.
+    pub(crate) max_header_count: usize,
+    /// The maximum total size in bytes of the request headers, above which 431 is returned.
+    pub(crate) max_header_size: usize,
+    /// The maximum size in bytes of a request body, above which 413 is returned.
+    pub(crate) max_body_size: u64,
+}
+
+#[cfg(feature = "tokio")]
+impl Limits {
+    /// Returns the maximum size in bytes of a request head within these limits.
+    pub(crate) fn max_head_size(&self) -> usize {
+        let headers = MAX_HEADER_LINE_SIZE * (self.max_header_count + 1);
+        // Allow for the line terminators, which don't count towards the header size.
+        let terminators = 2 * (self.max_header_count + 1);
+        self.max_url_length
+            + REQUEST_LINE_OVERHEAD
+            + headers.min(self.max_header_size + terminators)
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_url_length: 2048,
+            max_header_count: 64,
+            max_header_size: 64 * 1024,
+            max_body_size: 64 * 1024,
+        }
+    }
+}
+
+/// A parsed HTTP/1.x request head.
+pub(crate) struct Request {
+    method: Method,
+    url: String,
+    version: Version,
+    headers: HeaderMap,
+    remote_addr: Option<SocketAddr>,
+}
+
+impl Request {
+    /// Reads a request head from the given reader.
+    ///
+    /// On error, returns the status code that should be sent to the client, if any.
+    pub(crate) fn read<R>(
+        reader: &mut R,
+        remote_addr: Option<SocketAddr>,
+        limits: &Limits,
+    ) -> Result<Request, Option<StatusCode>>
+    where
+        R: BufRead,
+    {
+        // Parse the request line, e.g. "GET /metrics HTTP/1.1".
+        let max = limits.max_url_length + REQUEST_LINE_OVERHEAD;
+        let line = read_line(reader, max, StatusCode::URI_TOO_LONG)?.ok_or(None)?;
+        let mut parts = line.split(' ');
+        let (method, url, version) = match (parts.next(), parts.next(), parts.next(), parts.next())
+        {
+            (Some(m), Some(u), Some(v), None) if !u.is_empty() => (m, u, v),
+            _ => return Err(Some(StatusCode::BAD_REQUEST)),
+        };
+        if url.len() > limits.max_url_length {
+            return Err(Some(StatusCode::URI_TOO_LONG));
+        }
+        let method =
+            Method::from_bytes(method.as_bytes()).map_err(|_| Some(StatusCode::BAD_REQUEST))?;
+        let version = match version {
+            "HTTP/1.1" => Version::HTTP_11,
+            "HTTP/1.0" => Version::HTTP_10,
+            v if v.starts_with("HTTP/") => {
+                return Err(Some(StatusCode::HTTP_VERSION_NOT_SUPPORTED))
+            }
+            _ => return Err(Some(StatusCode::BAD_REQUEST)),
+        };
+
+        // Parse headers until an empty line is found.
+        let mut headers = HeaderMap::new();
+        let mut size = 0;
+        loop {
+            let too_large = StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE;
+            let max = MAX_HEADER_LINE_SIZE.min(limits.max_header_size);
+            let line = read_line(reader, max, too_large)?.ok_or(Some(StatusCode::BAD_REQUEST))?;
+            if line.is_empty() {
+                break;
+            }
+            size += line.len();
+            if headers.len() >= limits.max_header_count || size > limits.max_header_size {
+                return Err(Some(too_large));
+            }
+
+            let (name, value) = line.split_once(':').ok_or(Some(StatusCode::BAD_REQUEST))?;
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| Some(StatusCode::BAD_REQUEST))?;
+            let value =
+                HeaderValue::from_str(value.trim()).map_err(|_| Some(StatusCode::BAD_REQUEST))?;
+            headers.append(name, value);
+        }
+
+        // Bodies are only discarded, so chunked bodies aren't supported, and large bodies are
+        // rejected before they're read.
+        if headers.contains_key(TRANSFER_ENCODING) {
+            return Err(Some(StatusCode::NOT_IMPLEMENTED));
+        }
+        let length = content_length(&headers).ok_or(Some(StatusCode::BAD_REQUEST))?;
+        if length > limits.max_body_size {
+            return Err(Some(StatusCode::PAYLOAD_TOO_LARGE));
+        }
+
+        Ok(Request {
+            method,
+            url: url.to_string(),
+            version,
+            headers,
+            remote_addr: remote_addr.map(addr::canonical),
+        })
+    }
+
+    /// Creates a request from the head of a request received by another HTTP server.
+    #[cfg(feature = "tower")]
+    pub(crate) fn from_http<B>(req: &http::Request<B>) -> Self {
+        Request {
+            method: req.method().clone(),
+            url: req
+                .uri()
+                .path_and_query()
+                .map_or("/", |pq| pq.as_str())
+                .to_string(),
+            version: req.version(),
+            headers: req.headers().clone(),
+            remote_addr: None,
+        }
+    }
+
+    /// Returns the request method.
+    pub(crate) fn method(&self) -> &Method {
+        &self.method
+    }
+
+    /// Returns the raw request target, including any query string.
+    pub(crate) fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Returns the path of the request target, without any query string.
+    pub(crate) fn path(&self) -> &str {
+        self.url.split_once('?').map_or(&self.url, |(path, _)| path)
+    }
+
+    /// Returns the value of the first query parameter with the given name, if present.
+    pub(crate) fn query_param(&self, name: &str) -> Option<&str> {
+        self.query_params(name).next()
+    }
+
+    /// Returns the values of every query parameter with the given name, in order.
+    pub(crate) fn query_params<'a, 'n>(
+        &'a self,
+        name: &'n str,
+    ) -> impl Iterator<Item = &'a str> + 'n
+    where
+        'a: 'n,
+    {
+        let query = self.url.split_once('?').map_or("", |(_, query)| query);
+        query
+            .split('&')
+            .filter(|p| !p.is_empty())
+            .map(|p| p.split_once('=').unwrap_or((p, "")))
+            .filter_map(move |(k, v)| (k == name).then_some(v))
+    }
+
+    /// Returns the HTTP version of the request.
+    pub(crate) fn http_version(&self) -> Version {
+        self.version
+    }
+
+    /// Returns the request headers.
+    pub(crate) fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    /// Returns true if the client accepts responses with the given content coding.
+    ///
+    /// Codings listed with `q=0` are refused, and an explicit coding takes precedence over `*`.
+    #[cfg(feature = "gzip")]
+    pub(crate) fn accepts_encoding(&self, encoding: &str) -> bool {
+        let (mut explicit, mut wildcard) = (None, None);
+        for (name, quality) in self.qualities(ACCEPT_ENCODING) {
+            if name.eq_ignore_ascii_case(encoding) {
+                explicit = Some(quality);
+            } else if name == "*" {
+                wildcard = Some(quality);
+            }
+        }
+        explicit.or(wildcard).is_some_and(|q| q > 0.0)
+    }
+
+    /// Returns the quality the client assigns to responses of the given media type, if listed
+    /// in its `Accept` header.
+    ///
+    /// The most specific matching range applies, e.g. `text/plain` takes precedence over
+    /// `text/*`, which takes precedence over `*/*`. Parameters other than `q` are ignored.
+    pub(crate) fn accept_quality(&self, media_type: &str) -> Option<f32> {
+        let (main_type, _) = media_type.split_once('/')?;
+        let mut best: Option<(u8, f32)> = None;
+        for (range, quality) in self.qualities(ACCEPT) {
+            let specificity = match range.split_once('/') {
+                _ if range.eq_ignore_ascii_case(media_type) => 2,
+                Some((t, "*")) if t.eq_ignore_ascii_case(main_type) => 1,
+                Some(("*", "*")) => 0,
+                _ => continue,
+            };
+            best = match best {
+                Some((s, q)) if s > specificity || (s == specificity && q >= quality) => best,
+                _ => Some((specificity, quality)),
+            };
+        }
+        best.map(|(_, quality)| quality)
+    }
+
+    // Returns each value listed in a header with its `q` parameter, which defaults to 1.
+    fn qualities(&self, name: HeaderName) -> impl Iterator<Item = (&str, f32)> {
+        self.headers
+            .get_all(name)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .filter_map(|value| {
+                let mut params = value.split(';');
+                let name = params.next().unwrap_or_default().trim();
+                let quality = params
+                    .find_map(|p| p.trim().strip_prefix("q="))
+                    .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())?;
+                Some((name, quality))
+            })
+    }
+
+    /// Returns the value of the `Expect` header, if present.
+    ///
+    /// HTTP/1.0 requests never carry an expectation, so the header is ignored for them.
+    pub(crate) fn expect(&self) -> Option<&HeaderValue> {
+        match self.version {
+            Version::HTTP_10 => None,
+            _ => self.headers.get(EXPECT),
+        }
+    }
+
+    /// Reads and discards the request body so the client doesn't see a reset connection.
+    pub(crate) fn discard_body<R>(&self, reader: &mut R) -> io::Result<()>
+    where
+        R: Read,
+    {
+        io::copy(&mut reader.take(self.discard_length()), &mut io::sink())?;
+        Ok(())
+    }
+
+    /// Returns the number of request body bytes to read and discard before responding.
+    pub(crate) fn discard_length(&self) -> u64 {
+        content_length(&self.headers).unwrap_or(0)
+    }
+
+    /// Returns the address of the client, if known, with IPv4-mapped addresses converted to IPv4.
+    pub(crate) fn remote_addr(&self) -> Option<SocketAddr> {
+        self.remote_addr
+    }
+}
+
+// Returns the length of the request body, zero if not given, or None if the length is invalid
+// or repeated with different values.
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+    let mut length = None;
+    for value in headers.get_all(CONTENT_LENGTH) {
+        let value = value.to_str().ok()?;
+        if !value.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let n = value.parse::<u64>().ok()?;
+        if length.is_some_and(|l| l != n) {
+            return None;
+        }
+        length = Some(n);
+    }
+    Some(length.unwrap_or(0))
+}
+
+// Reads a single CRLF (or LF) terminated line of at most `max` bytes, returning None on EOF.
+//
+// Lines exceeding the maximum length result in the given status code.
+fn read_line<R>(
+    reader: &mut R,
+    max: usize,
+    too_long: StatusCode,
+) -> Result<Option<String>, Option<StatusCode>>
+where
+    R: BufRead,
+{
+    let mut buf = Vec::new();
+    let n = reader
+        .take(max as u64 + 1)
+        .read_until(b'\n', &mut buf)
+        .map_err(|_| None)?;
+    if n == 0 {
+        return Ok(None);
+    }
+
+    // A line without a terminator means it was either too long or the connection closed.
+    if !buf.ends_with(b"\n") {
+        return Err((n > max).then_some(too_long));
+    }
+    buf.pop();
+    if buf.ends_with(b"\r") {
+        buf.pop();
+    }
+
+    String::from_utf8(buf)
+        .map(Some)
+        .map_err(|_| Some(StatusCode::BAD_REQUEST))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(raw: &str) -> Result<Request, Option<StatusCode>> {
+        Request::read(&mut raw.as_bytes(), None, &Limits::default())
+    }
+
+    #[test]
+    fn test_read_request() {
+        let req = parse("GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        assert_eq!(req.method(), &Method::GET);
+        assert_eq!(req.url(), "/metrics");
+        assert_eq!(req.http_version(), Version::HTTP_11);
+        assert_eq!(req.headers.get("host").unwrap(), "localhost");
+        assert_eq!(req.path(), "/metrics");
+        assert_eq!(req.query_param("page"), None);
+
+        // Query parameters.
+        let req = parse("GET /metrics?debug&page=2&page=3 HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(req.path(), "/metrics");
+        assert_eq!(req.query_param("page"), Some("2"));
+        assert_eq!(req.query_param("debug"), Some(""));
+        assert_eq!(req.query_params("page").collect::<Vec<_>>(), ["2", "3"]);
+        assert_eq!(req.query_params("missing").count(), 0);
+
+        // Bare LF line endings.
+        let req = parse("POST /metrics HTTP/1.0\nContent-Length: 2\n\nhi").unwrap();
+        assert_eq!(req.method(), &Method::POST);
+        assert_eq!(req.http_version(), Version::HTTP_10);
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_accepts_encoding() {
+        let req = parse("GET / HTTP/1.1\r\nAccept-Encoding: deflate, GZIP;q=0.5\r\n\r\n").unwrap();
+        assert!(req.accepts_encoding("gzip"));
+        assert!(!req.accepts_encoding("br"));
+
+        let req = parse("GET / HTTP/1.1\r\nAccept-Encoding: gzip;q=0, *\r\n\r\n").unwrap();
+        assert!(!req.accepts_encoding("gzip"));
+        assert!(req.accepts_encoding("br"));
+
+        let req = parse("GET / HTTP/1.1\r\n\r\n").unwrap();
+        assert!(!req.accepts_encoding("gzip"));
+    }
+
+    #[test]
+    fn test_accept_quality() {
+        let req = parse(
+            "GET / HTTP/1.1\r\nAccept: application/openmetrics-text;version=1.0.0;q=0.5,\
+            application/openmetrics-text;version=0.0.1;q=0.4,text/plain;version=0.0.4;q=0.3,\
+            */*;q=0.2\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(
+            req.accept_quality("application/openmetrics-text"),
+            Some(0.5)
+        );
+        assert_eq!(req.accept_quality("text/plain"), Some(0.3));
+        assert_eq!(req.accept_quality("application/json"), Some(0.2));
+
+        let req = parse("GET / HTTP/1.1\r\nAccept: text/*;q=0.1, TEXT/PLAIN\r\n\r\n").unwrap();
+        assert_eq!(req.accept_quality("text/plain"), Some(1.0));
+        assert_eq!(req.accept_quality("text/html"), Some(0.1));
+        assert_eq!(req.accept_quality("application/json"), None);
+
+        let req = parse("GET / HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(req.accept_quality("text/plain"), None);
+    }
+
+    #[test]
+    fn test_expect() {
+        let req = parse("POST / HTTP/1.1\r\nExpect: 100-continue\r\n\r\n").unwrap();
+        assert_eq!(req.expect().unwrap(), "100-continue");
+
+        // Ignored for HTTP/1.0 requests.
+        let req = parse("POST / HTTP/1.0\r\nExpect: 100-continue\r\n\r\n").unwrap();
+        assert!(req.expect().is_none());
+    }
+
+    #[test]
+    fn test_discard_body() {
+        let mut raw = "POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloGET".as_bytes();
+        let req = Request::read(&mut raw, None, &Limits::default()).unwrap();
+        req.discard_body(&mut raw).unwrap();
+        assert_eq!(raw, b"GET");
+    }
+
+    #[test]
+    fn test_read_request_invalid() {
+        // Connection closed before sending anything.
+        assert!(matches!(parse(""), Err(None)));
+        // Malformed request line.
+        assert!(matches!(
+            parse("GET\r\n\r\n"),
+            Err(Some(StatusCode::BAD_REQUEST))
+        ));
+        // Malformed header.
+        assert!(matches!(
+            parse("GET / HTTP/1.1\r\nHost\r\n\r\n"),
+            Err(Some(StatusCode::BAD_REQUEST))
+        ));
+        // Unsupported version.
+        assert!(matches!(
+            parse("GET / HTTP/2.0\r\n\r\n"),
+            Err(Some(StatusCode::HTTP_VERSION_NOT_SUPPORTED))
+        ));
+        // Incomplete head.
+        assert!(matches!(parse("GET / HTTP/1.1\r\nHost: local"), Err(None)));
+    }
+
+    #[test]
+    fn test_read_request_limits() {
+        let limits = Limits {
+            max_url_length: 16,
+            max_header_count: 2,
+            max_header_size: 32,
+            max_body_size: 4,
+        };
+        let parse = |raw: &str| Request::read(&mut raw.as_bytes(), None, &limits);
+
+        // URL within limits.
+        assert!(parse("GET /0123456789abcde HTTP/1.1\r\n\r\n").is_ok());
+        // URL too long.
+        assert!(matches!(
+            parse("GET /0123456789abcdef HTTP/1.1\r\n\r\n"),
+            Err(Some(StatusCode::URI_TOO_LONG))
+        ));
+        // Request line too long to buffer.
+        let raw = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(10_000));
+        assert!(matches!(parse(&raw), Err(Some(StatusCode::URI_TOO_LONG))));
+
+        // Header count within limits.
+        assert!(parse("GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\n\r\n").is_ok());
+        // Too many headers.
+        assert!(matches!(
+            parse("GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n"),
+            Err(Some(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE))
+        ));
+        // Header line too long.
+        let raw = format!("GET / HTTP/1.1\r\nA: {}\r\n\r\n", "a".repeat(10_000));
+        assert!(matches!(
+            parse(&raw),
+            Err(Some(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE))
+        ));
+
+        // Header size within limits.
+        let raw = format!("GET / HTTP/1.1\r\nA: {}\r\nB: 2\r\n\r\n", "a".repeat(24));
+        assert!(parse(&raw).is_ok());
+        // Headers too large in total.
+        let raw = format!("GET / HTTP/1.1\r\nA: {}\r\nB: 222\r\n\r\n", "a".repeat(24));
+        assert!(matches!(
+            parse(&raw),
+            Err(Some(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE))
+        ));
+
+        // Body size within limits.
+        assert!(parse("POST / HTTP/1.1\r\nContent-Length: 4\r\n\r\n").is_ok());
+        // Body too large.
+        assert!(matches!(
+            parse("POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\n"),
+            Err(Some(StatusCode::PAYLOAD_TOO_LARGE))
+        ));
+        // Invalid lengths.
+        for length in ["", "abc", "+4", "-1", "4, 4"] {
+            let raw = format!("POST / HTTP/1.1\r\nContent-Length: {length}\r\n\r\n");
+            assert!(
+                matches!(parse(&raw), Err(Some(StatusCode::BAD_REQUEST))),
+                "{length}"
+            );
+        }
+
+        // Lengths that overflow, and repeated lengths that don't agree.
+        let parse = |raw: &str| Request::read(&mut raw.as_bytes(), None, &Limits::default());
+        assert!(matches!(
+            parse("POST / HTTP/1.1\r\nContent-Length: 99999999999999999999\r\n\r\n"),
+            Err(Some(StatusCode::BAD_REQUEST))
+        ));
+        let raw = "POST / HTTP/1.1\r\nContent-Length: 4\r\nContent-Length: 4\r\n\r\n";
+        assert_eq!(parse(raw).unwrap().discard_length(), 4);
+        assert!(matches!(
+            parse("POST / HTTP/1.1\r\nContent-Length: 4\r\nContent-Length: 3\r\n\r\n"),
+            Err(Some(StatusCode::BAD_REQUEST))
+        ));
+
+        // Bodies without a fixed length.
+        assert!(matches!(
+            parse("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n"),
+            Err(Some(StatusCode::NOT_IMPLEMENTED))
+        ));
+    }
+}
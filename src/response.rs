@@ -0,0 +1,493 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::ops::{Deref, DerefMut, Range};
+use std::sync::{Arc, Mutex};
+
+use http::header::{CONNECTION, CONTENT_LENGTH};
+use http::{HeaderMap, HeaderName, HeaderValue, StatusCode, Version};
+
+/// Options controlling how responses are written to the connection.
+#[derive(Clone, Debug)]
+pub(crate) struct WriteConfig {
+    /// The capacity of the buffer used to coalesce writes to the connection.
+    pub(crate) buffer_size: usize,
+    /// The maximum number of body bytes passed to the connection in a single write.
+    pub(crate) chunk_size: Option<usize>,
+    /// Buffers reused to assemble responses.
+    pub(crate) pool: Arc<BufferPool>,
+}
+
+impl Default for WriteConfig {
+    fn default() -> Self {
+        WriteConfig {
+            buffer_size: 8 * 1024,
+            chunk_size: None,
+            pool: Arc::default(),
+        }
+    }
+}
+
+/// A writer that the contents of a file can be sent to.
+///
+/// By default files are copied through a userspace buffer, but sockets can override this to
+/// use zero-copy system calls such as `sendfile(2)`.
+pub(crate) trait SendFile: Write {
+    /// Writes `len` bytes from the current position of the file.
+    fn send_file(&mut self, file: &mut File, len: u64) -> io::Result<()> {
+        copy_file(self, file, len)
+    }
+}
+
+impl SendFile for Vec<u8> {}
+
+/// Copies `len` bytes from the current position of the file to the writer.
+pub(crate) fn copy_file<W>(writer: &mut W, file: &mut File, len: u64) -> io::Result<()>
+where
+    W: Write + ?Sized,
+{
+    if io::copy(&mut file.take(len), writer)? < len {
+        return Err(truncated());
+    }
+    Ok(())
+}
+
+/// The error returned when a file is truncated while it is being sent.
+pub(crate) fn truncated() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "file truncated while sending response",
+    )
+}
+
+// Data shared between responses without copying.
+pub(crate) type SharedBody = Arc<dyn AsRef<[u8]> + Send + Sync>;
+
+// The body of a response.
+enum Body {
+    Data(Vec<u8>),
+    // A range of data shared with other responses.
+    Shared(SharedBody, Range<usize>),
+    // An open file and the number of bytes to send from it.
+    File(File, u64),
+}
+
+/// A HTTP response with a buffered body, or a body streamed from a file.
+pub(crate) struct Response {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Body,
+    // Whether the body is omitted when writing, e.g. in response to a HEAD request.
+    omit_body: bool,
+}
+
+impl Response {
+    /// Creates a response with the given status code and an empty body.
+    pub(crate) fn empty(status: StatusCode) -> Self {
+        Response::from_data(Vec::new()).with_status_code(status)
+    }
+
+    /// Creates a 200 response containing the given data.
+    pub(crate) fn from_data(body: Vec<u8>) -> Self {
+        Response {
+            status: StatusCode::OK,
+            headers: HeaderMap::new(),
+            body: Body::Data(body),
+            omit_body: false,
+        }
+    }
+
+    /// Creates a 200 response containing a range of the given data, without copying it.
+    pub(crate) fn from_shared(data: SharedBody, range: Range<usize>) -> Self {
+        Response {
+            status: StatusCode::OK,
+            headers: HeaderMap::new(),
+            body: Body::Shared(data, range),
+            omit_body: false,
+        }
+    }
+
+    /// Creates a 200 response that sends `len` bytes from the given file.
+    pub(crate) fn from_file(file: File, len: u64) -> Self {
+        Response {
+            status: StatusCode::OK,
+            headers: HeaderMap::new(),
+            body: Body::File(file, len),
+            omit_body: false,
+        }
+    }
+
+    /// Sets the status code of the response.
+    pub(crate) fn with_status_code(mut self, status: StatusCode) -> Self {
+        self.status = status;
+        self
+    }
+
+    /// Adds a header to the response, replacing any existing value.
+    pub(crate) fn with_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.insert(name, value);
+        self
+    }
+
+    /// Adds a header to the response, keeping any existing values.
+    pub(crate) fn append_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.append(name, value);
+        self
+    }
+
+    /// Omits the body when writing the response, while still sending its `Content-Length`.
+    pub(crate) fn without_body(mut self) -> Self {
+        self.omit_body = true;
+        self
+    }
+
+    /// Returns the status code of the response.
+    pub(crate) fn status_code(&self) -> StatusCode {
+        self.status
+    }
+
+    // Returns the length of the body, even if it is omitted.
+    fn len(&self) -> u64 {
+        match &self.body {
+            Body::Data(data) => data.len() as u64,
+            Body::Shared(_, range) => range.len() as u64,
+            Body::File(_, len) => *len,
+        }
+    }
+
+    /// Serializes the response to the given writer.
+    ///
+    /// Connections are not kept alive, so every response is sent with `Connection: close`.
+    pub(crate) fn write_to<W>(
+        self,
+        version: Version,
+        writer: &mut W,
+        config: &WriteConfig,
+    ) -> io::Result<()>
+    where
+        W: SendFile + ?Sized,
+    {
+        let len = self.len();
+
+        // Assemble the head in a pooled buffer, which the body is then coalesced into.
+        let mut buf = config.pool.get();
+        let version = match version {
+            Version::HTTP_10 => "HTTP/1.0",
+            _ => "HTTP/1.1",
+        };
+        write!(
+            buf,
+            "{} {} {}\r\n",
+            version,
+            self.status.as_str(),
+            self.status.canonical_reason().unwrap_or("Unknown")
+        )?;
+        for (name, value) in self.headers.iter() {
+            if name == CONTENT_LENGTH || name == CONNECTION {
+                continue;
+            }
+            buf.extend_from_slice(name.as_str().as_bytes());
+            buf.extend_from_slice(b": ");
+            buf.extend_from_slice(value.as_bytes());
+            buf.extend_from_slice(b"\r\n");
+        }
+        write!(buf, "content-length: {len}\r\nconnection: close\r\n\r\n")?;
+
+        let chunk_size = config.chunk_size.unwrap_or(usize::MAX).max(1);
+        // Limited chunks are written as they are, rather than coalesced into larger writes.
+        let buffer_size = match config.chunk_size {
+            Some(_) => 0,
+            None => config.buffer_size,
+        };
+        let mut write = |chunk: &[u8]| -> io::Result<()> {
+            // Like a BufWriter, chunks that don't fit are written directly.
+            if buf.len() + chunk.len() > buffer_size && !buf.is_empty() {
+                writer.write_all(&buf)?;
+                buf.clear();
+            }
+            if chunk.len() >= buffer_size {
+                writer.write_all(chunk)
+            } else {
+                buf.extend_from_slice(chunk);
+                Ok(())
+            }
+        };
+        match self.body {
+            _ if self.omit_body => {}
+            Body::Data(data) => data.chunks(chunk_size).try_for_each(&mut write)?,
+            Body::Shared(data, range) => (*data).as_ref()[range]
+                .chunks(chunk_size)
+                .try_for_each(&mut write)?,
+            // Files bypass the buffer, so the head must be sent first.
+            Body::File(mut file, len) => {
+                writer.write_all(&buf)?;
+                buf.clear();
+                writer.send_file(&mut file, len)?;
+            }
+        }
+        writer.write_all(&buf)?;
+        writer.flush()
+    }
+}
+
+#[cfg(feature = "gzip")]
+impl Response {
+    /// Compresses the body with gzip, adding a `Content-Encoding` header.
+    ///
+    /// Responses streamed from files are returned unchanged, so they can still be sent without
+    /// being read into memory.
+    pub(crate) fn gzip(mut self) -> io::Result<Self> {
+        use http::header::CONTENT_ENCODING;
+
+        let data = match &self.body {
+            Body::Data(data) => &data[..],
+            Body::Shared(data, range) => &(**data).as_ref()[range.clone()],
+            Body::File(..) => return Ok(self),
+        };
+        self.body = Body::Data(gzip(data)?);
+        Ok(self.with_header(CONTENT_ENCODING, HeaderValue::from_static("gzip")))
+    }
+}
+
+/// Compresses data with gzip, favouring speed over size.
+#[cfg(feature = "gzip")]
+pub(crate) fn gzip(data: &[u8]) -> io::Result<Vec<u8>> {
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    let mut encoder = GzEncoder::new(Vec::with_capacity(data.len() / 4), Compression::fast());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+impl Response {
+    /// Converts the response for another HTTP server, sharing the payload rather than copying it.
+    ///
+    /// Files are read into memory, failing if they are truncated.
+    #[cfg(feature = "tower")]
+    pub(crate) fn into_http(mut self) -> io::Result<http::Response<bytes::Bytes>> {
+        let body = match self.body {
+            _ if self.omit_body => {
+                let len = HeaderValue::from(self.len());
+                self.headers.insert(CONTENT_LENGTH, len);
+                bytes::Bytes::new()
+            }
+            Body::Data(data) => bytes::Bytes::from(data),
+            Body::Shared(data, range) => bytes::Bytes::from_owner(SharedRange(data, range)),
+            Body::File(file, len) => {
+                let mut data = Vec::new();
+                if file.take(len).read_to_end(&mut data)? < len as usize {
+                    return Err(truncated());
+                }
+                bytes::Bytes::from(data)
+            }
+        };
+
+        let mut res = http::Response::new(body);
+        *res.status_mut() = self.status;
+        *res.headers_mut() = self.headers;
+        Ok(res)
+    }
+}
+
+// A range of shared data, owned by a `Bytes`.
+#[cfg(feature = "tower")]
+struct SharedRange(SharedBody, Range<usize>);
+
+#[cfg(feature = "tower")]
+impl AsRef<[u8]> for SharedRange {
+    fn as_ref(&self) -> &[u8] {
+        &(*self.0).as_ref()[self.1.clone()]
+    }
+}
+
+/// A pool of buffers reused across responses, avoiding allocations on every request.
+#[derive(Debug, Default)]
+pub(crate) struct BufferPool {
+    buffers: Mutex<Vec<Vec<u8>>>,
+}
+
+// The maximum number of idle buffers kept in the pool.
+const MAX_POOLED_BUFFERS: usize = 16;
+
+impl BufferPool {
+    /// Takes an empty buffer from the pool, or allocates one if none are idle.
+    pub(crate) fn get(&self) -> PooledBuffer<'_> {
+        let buf = self.buffers.lock().unwrap().pop().unwrap_or_default();
+        PooledBuffer { pool: self, buf }
+    }
+}
+
+/// A buffer that is cleared and returned to its pool when dropped.
+pub(crate) struct PooledBuffer<'a> {
+    pool: &'a BufferPool,
+    buf: Vec<u8>,
+}
+
+impl Deref for PooledBuffer<'_> {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.buf
+    }
+}
+
+impl DerefMut for PooledBuffer<'_> {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.buf
+    }
+}
+
+impl Drop for PooledBuffer<'_> {
+    fn drop(&mut self) {
+        let mut buf = std::mem::take(&mut self.buf);
+        buf.clear();
+        let mut buffers = self.pool.buffers.lock().unwrap();
+        if buffers.len() < MAX_POOLED_BUFFERS {
+            buffers.push(buf);
+        }
+    }
+}
+
+/// Writes an interim `100 Continue` response, telling the client to send the request body.
+pub(crate) fn write_continue<W>(writer: &mut W) -> io::Result<()>
+where
+    W: Write,
+{
+    writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_response() {
+        let mut buf = Vec::new();
+        Response::from_data(b"hello".to_vec())
+            .write_to(Version::HTTP_11, &mut buf, &WriteConfig::default())
+            .unwrap();
+
+        let raw = String::from_utf8(buf).unwrap();
+        assert!(raw.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(raw.contains("content-length: 5\r\n"));
+        assert!(raw.contains("connection: close\r\n"));
+        assert!(raw.ends_with("\r\n\r\nhello"));
+    }
+
+    #[test]
+    fn test_write_without_body() {
+        let mut buf = Vec::new();
+        Response::from_data(b"hello".to_vec())
+            .without_body()
+            .write_to(Version::HTTP_11, &mut buf, &WriteConfig::default())
+            .unwrap();
+
+        // Assert the length of the omitted body is still sent.
+        let raw = String::from_utf8(buf).unwrap();
+        assert!(raw.contains("content-length: 5\r\n"));
+        assert!(raw.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_gzip() {
+        let data = b"a 1\n".repeat(100);
+        let res = Response::from_data(data.clone()).gzip().unwrap();
+        let mut out = Vec::new();
+        res.write_to(Version::HTTP_11, &mut out, &WriteConfig::default())
+            .unwrap();
+
+        let split = out.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        let head = std::str::from_utf8(&out[..split]).unwrap();
+        assert!(head.contains("content-encoding: gzip\r\n"));
+
+        let mut decoded = Vec::new();
+        flate2::read::GzDecoder::new(&out[split..])
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, data);
+    }
+
+    #[test]
+    fn test_write_shared() {
+        let data: SharedBody = Arc::new(b"hello world".to_vec());
+        let mut buf = Vec::new();
+        Response::from_shared(Arc::clone(&data), 6..11)
+            .write_to(Version::HTTP_11, &mut buf, &WriteConfig::default())
+            .unwrap();
+
+        let raw = String::from_utf8(buf).unwrap();
+        assert!(raw.contains("content-length: 5\r\n"));
+        assert!(raw.ends_with("\r\n\r\nworld"));
+    }
+
+    #[test]
+    fn test_write_chunks() {
+        // Records the size of every write.
+        struct Writes(Vec<usize>);
+
+        impl SendFile for Writes {}
+
+        impl Write for Writes {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.push(buf.len());
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let config = WriteConfig {
+            buffer_size: 0,
+            chunk_size: Some(4),
+            ..Default::default()
+        };
+        let mut writes = Writes(Vec::new());
+        Response::from_data(b"0123456789".to_vec())
+            .write_to(Version::HTTP_11, &mut writes, &config)
+            .unwrap();
+
+        // The head is followed by the body split into chunks.
+        assert_eq!(writes.0[1..], [4, 4, 2]);
+
+        // Assert chunks aren't coalesced by the default write buffer.
+        let config = WriteConfig {
+            chunk_size: Some(1024),
+            ..Default::default()
+        };
+        let mut writes = Writes(Vec::new());
+        Response::from_data(vec![b'a'; 4000])
+            .write_to(Version::HTTP_11, &mut writes, &config)
+            .unwrap();
+        assert_eq!(writes.0[1..], [1024, 1024, 1024, 928]);
+    }
+
+    #[test]
+    fn test_write_file() {
+        let path = std::env::temp_dir().join("metrics_server_test_write_file");
+        std::fs::write(&path, b"hello world").unwrap();
+
+        // Assert only the given length is sent.
+        let mut buf = Vec::new();
+        Response::from_file(File::open(&path).unwrap(), 5)
+            .write_to(Version::HTTP_11, &mut buf, &WriteConfig::default())
+            .unwrap();
+        let raw = String::from_utf8(buf).unwrap();
+        assert!(raw.contains("content-length: 5\r\n"));
+        assert!(raw.ends_with("\r\n\r\nhello"));
+
+        // Assert files truncated after the response is created are an error.
+        let mut buf = Vec::new();
+        let res = Response::from_file(File::open(&path).unwrap(), 20).write_to(
+            Version::HTTP_11,
+            &mut buf,
+            &WriteConfig::default(),
+        );
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,121 @@
+use std::fmt::Write;
+
+use tokio::runtime::{Handle, RuntimeMetrics};
+
+/// Appends metrics describing the given tokio runtime to the payload.
+///
+/// Metrics only available with `--cfg tokio_unstable` are included when this crate is built
+/// with it too.
+pub(crate) fn append_metrics(handle: &Handle, mut data: Vec<u8>) -> Vec<u8> {
+    let mut out = String::new();
+    write_metrics(&handle.metrics(), &mut out);
+    data.extend_from_slice(out.as_bytes());
+    data
+}
+
+// Writes the runtime metrics in the text exposition format.
+fn write_metrics(metrics: &RuntimeMetrics, out: &mut String) {
+    gauge(
+        out,
+        "tokio_runtime_workers",
+        "The number of worker threads used by the runtime.",
+        metrics.num_workers(),
+    );
+    gauge(
+        out,
+        "tokio_runtime_alive_tasks",
+        "The number of tasks currently alive in the runtime.",
+        metrics.num_alive_tasks(),
+    );
+    gauge(
+        out,
+        "tokio_runtime_global_queue_depth",
+        "The number of tasks currently scheduled in the runtime's global queue.",
+        metrics.global_queue_depth(),
+    );
+
+    #[cfg(target_has_atomic = "64")]
+    {
+        let workers = 0..metrics.num_workers();
+        per_worker(
+            out,
+            "tokio_runtime_worker_park_total",
+            "counter",
+            "The number of times each worker thread has parked.",
+            workers
+                .clone()
+                .map(|w| metrics.worker_park_count(w).to_string()),
+        );
+        per_worker(
+            out,
+            "tokio_runtime_worker_busy_seconds_total",
+            "counter",
+            "The time each worker thread has spent busy.",
+            workers.map(|w| {
+                let busy = metrics.worker_total_busy_duration(w);
+                crate::exposition::format_number(busy.as_secs_f64())
+            }),
+        );
+    }
+
+    #[cfg(tokio_unstable)]
+    {
+        let _ = writeln!(
+            out,
+            "# HELP tokio_runtime_budget_forced_yield_total The number of times tasks were forced to yield after exhausting their budget.\n\
+            # TYPE tokio_runtime_budget_forced_yield_total counter\n\
+            tokio_runtime_budget_forced_yield_total {}",
+            metrics.budget_forced_yield_count()
+        );
+        gauge(
+            out,
+            "tokio_runtime_blocking_queue_depth",
+            "The number of tasks waiting for a blocking thread.",
+            metrics.blocking_queue_depth(),
+        );
+        per_worker(
+            out,
+            "tokio_runtime_worker_local_queue_depth",
+            "gauge",
+            "The number of tasks currently scheduled in each worker's local queue.",
+            (0..metrics.num_workers()).map(|w| metrics.worker_local_queue_depth(w).to_string()),
+        );
+    }
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: usize) {
+    let _ = writeln!(
+        out,
+        "# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}"
+    );
+}
+
+#[cfg_attr(not(any(target_has_atomic = "64", tokio_unstable)), allow(dead_code))]
+fn per_worker<I>(out: &mut String, name: &str, kind: &str, help: &str, values: I)
+where
+    I: Iterator<Item = String>,
+{
+    let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}");
+    for (worker, value) in values.enumerate() {
+        let _ = writeln!(out, "{name}{{worker=\"{worker}\"}} {value}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_runtime_metrics() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let data = append_metrics(runtime.handle(), b"a 1\n".to_vec());
+        let out = String::from_utf8(data).unwrap();
+
+        assert!(out.starts_with("a 1\n# HELP tokio_runtime_workers "));
+        assert!(out.contains("\ntokio_runtime_workers 1\n"));
+        assert!(out.contains("\ntokio_runtime_alive_tasks 0\n"));
+        assert!(out.contains("\ntokio_runtime_global_queue_depth 0\n"));
+    }
+}
@@ -0,0 +1,136 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use log::error;
+
+use crate::error::ServerError;
+
+/// A secret value, either fixed at construction or read from a file that may be rotated.
+#[derive(Clone, Debug)]
+pub(crate) enum Secret {
+    Static(Arc<[u8]>),
+    File(Arc<WatchedFile>),
+}
+
+impl Secret {
+    /// Creates a secret from a file, ignoring any trailing newline.
+    pub(crate) fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, ServerError> {
+        let file = WatchedFile::open(path.as_ref(), true).map_err(|e| {
+            ServerError::Create(format!("error reading {}: {e}", path.as_ref().display()))
+        })?;
+        Ok(Secret::File(Arc::new(file)))
+    }
+
+    /// Returns the current value of the secret.
+    pub(crate) fn value(&self) -> Arc<[u8]> {
+        match self {
+            Secret::Static(value) => Arc::clone(value),
+            Secret::File(file) => file.contents(),
+        }
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(value: &str) -> Self {
+        Secret::Static(value.as_bytes().into())
+    }
+}
+
+/// A file whose contents are re-read whenever its modification time or size changes.
+///
+/// Secret mounts, e.g. in Kubernetes, are rotated by atomically swapping a symlink, which is
+/// followed when checking for changes.
+pub(crate) struct WatchedFile {
+    path: PathBuf,
+    trim: bool,
+    state: Mutex<FileState>,
+}
+
+struct FileState {
+    modified: Option<SystemTime>,
+    len: u64,
+    contents: Arc<[u8]>,
+}
+
+impl fmt::Debug for WatchedFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Never print the file contents, which may be secret.
+        f.debug_struct("WatchedFile")
+            .field("path", &self.path)
+            .finish_non_exhaustive()
+    }
+}
+
+impl WatchedFile {
+    /// Reads the file at the given path, optionally trimming trailing newlines.
+    pub(crate) fn open(path: &Path, trim: bool) -> io::Result<Self> {
+        Ok(WatchedFile {
+            path: path.to_path_buf(),
+            trim,
+            state: Mutex::new(read(path, trim)?),
+        })
+    }
+
+    /// Returns the contents of the file, re-reading it if it has changed.
+    ///
+    /// If the file can no longer be read, the last known contents are returned so that a
+    /// rotation in progress doesn't lock out clients.
+    pub(crate) fn contents(&self) -> Arc<[u8]> {
+        let mut state = self.state.lock().unwrap();
+        let changed = match fs::metadata(&self.path) {
+            Ok(meta) => meta.modified().ok() != state.modified || meta.len() != state.len,
+            Err(_) => false,
+        };
+
+        if changed {
+            match read(&self.path, self.trim) {
+                Ok(new) => *state = new,
+                Err(e) => error!("error reloading {}: {e}", self.path.display()),
+            }
+        }
+        Arc::clone(&state.contents)
+    }
+}
+
+// Reads the contents of a file along with the metadata used to detect changes.
+fn read(path: &Path, trim: bool) -> io::Result<FileState> {
+    let meta = fs::metadata(path)?;
+    let mut contents = fs::read(path)?;
+    while trim && contents.last().is_some_and(u8::is_ascii_whitespace) {
+        contents.pop();
+    }
+
+    Ok(FileState {
+        modified: meta.modified().ok(),
+        len: meta.len(),
+        contents: contents.into(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secret_file_reload() {
+        let path = std::env::temp_dir().join(format!("metrics_server_{}", std::process::id()));
+        fs::write(&path, "first\n").unwrap();
+
+        let secret = Secret::from_file(&path).unwrap();
+        assert_eq!(&*secret.value(), b"first");
+
+        // Assert changes are picked up, even if the modification time is unchanged.
+        fs::write(&path, "second\n").unwrap();
+        assert_eq!(&*secret.value(), b"second");
+
+        // Assert the last value is kept if the file is removed.
+        fs::remove_file(&path).unwrap();
+        assert_eq!(&*secret.value(), b"second");
+
+        assert!(Secret::from_file(&path).is_err());
+    }
+}
@@ -0,0 +1,171 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::exposition;
+
+// The metric family reporting when each section was last updated.
+const TIMESTAMP_METRIC: &str = "metrics_section_last_update_timestamp_seconds";
+
+/// How named sections are merged into a single payload.
+#[derive(Clone, Default)]
+pub(crate) struct SectionConfig {
+    /// Sections served first, in this order. Any others follow in name order.
+    pub(crate) order: Vec<String>,
+    /// Whether each section is preceded by a `# --- section: name ---` comment.
+    pub(crate) headers: bool,
+    /// Whether the time each section was last updated is appended to the payload.
+    pub(crate) timestamps: bool,
+}
+
+/// The latest data published by each producer of a sectioned payload.
+#[derive(Default)]
+pub(crate) struct Sections {
+    data: BTreeMap<String, Section>,
+}
+
+struct Section {
+    data: Vec<u8>,
+    updated: SystemTime,
+}
+
+impl Sections {
+    /// Replaces the data of a single section, updated at the given time.
+    pub(crate) fn insert(&mut self, name: &str, data: Vec<u8>, updated: SystemTime) {
+        self.data
+            .insert(name.to_string(), Section { data, updated });
+    }
+
+    /// Removes every section, e.g. when the whole payload is replaced.
+    pub(crate) fn clear(&mut self) {
+        self.data.clear();
+    }
+
+    /// Returns true if no sections have been published.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Concatenates the sections in the configured order, each ending with a newline.
+    pub(crate) fn merge(&self, config: &SectionConfig) -> Vec<u8> {
+        self.merge_where(config, |_| true)
+    }
+
+    /// Concatenates only the named sections, in the configured order.
+    pub(crate) fn merge_selected(&self, config: &SectionConfig, names: &[&str]) -> Vec<u8> {
+        self.merge_where(config, |name| names.contains(&name))
+    }
+
+    fn merge_where<F>(&self, config: &SectionConfig, selected: F) -> Vec<u8>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let ordered = config
+            .order
+            .iter()
+            .filter(|name| self.data.contains_key(*name));
+        let rest = self.data.keys().filter(|name| !config.order.contains(name));
+
+        let names: Vec<&String> = ordered.chain(rest).filter(|name| selected(name)).collect();
+
+        let mut payload = Vec::with_capacity(self.data.values().map(|s| s.data.len() + 1).sum());
+        for name in &names {
+            let data = &self.data[*name].data;
+            if config.headers {
+                payload.extend_from_slice(format!("# --- section: {name} ---\n").as_bytes());
+            }
+            payload.extend_from_slice(data);
+            if !data.is_empty() && !data.ends_with(b"\n") {
+                payload.push(b'\n');
+            }
+        }
+
+        // Operators can alert on the age of each section, e.g. `time() - metric > 300`, to find
+        // the producer that stopped publishing. A timestamp stays correct between updates,
+        // unlike an age computed when the payload is merged.
+        if config.timestamps && !names.is_empty() {
+            let mut out = format!(
+                "# HELP {TIMESTAMP_METRIC} The time each section was last updated, in seconds since the Unix epoch.\n\
+                # TYPE {TIMESTAMP_METRIC} gauge\n"
+            );
+            for name in &names {
+                let updated = self.data[*name].updated.duration_since(UNIX_EPOCH);
+                let secs = updated.map_or(0.0, |d| d.as_secs_f64());
+                out.push_str(TIMESTAMP_METRIC);
+                out.push_str("{section=\"");
+                exposition::escape(name, &mut out);
+                let _ = writeln!(out, "\"}} {}", exposition::format_number(secs));
+            }
+            payload.extend_from_slice(out.as_bytes());
+        }
+        payload
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge() {
+        let mut sections = Sections::default();
+        sections.insert("http", b"http_requests 1".to_vec(), SystemTime::now());
+        sections.insert("cache", b"cache_hits 2\n".to_vec(), SystemTime::now());
+        sections.insert("db", b"db_queries 3\n".to_vec(), SystemTime::now());
+
+        // Assert sections are merged in name order by default.
+        let config = SectionConfig::default();
+        assert_eq!(
+            sections.merge(&config),
+            b"cache_hits 2\ndb_queries 3\nhttp_requests 1\n"
+        );
+
+        // Assert ordered sections come first, followed by any others.
+        let config = SectionConfig {
+            order: vec!["http".to_string(), "missing".to_string(), "db".to_string()],
+            headers: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            String::from_utf8(sections.merge(&config)).unwrap(),
+            "# --- section: http ---\nhttp_requests 1\n\
+            # --- section: db ---\ndb_queries 3\n\
+            # --- section: cache ---\ncache_hits 2\n"
+        );
+
+        // Assert replacing a section keeps its position.
+        sections.insert("http", b"http_requests 4\n".to_vec(), SystemTime::now());
+        assert!(sections
+            .merge(&config)
+            .starts_with(b"# --- section: http ---\nhttp_requests 4\n"));
+
+        // Assert only selected sections are merged, in the configured order.
+        assert_eq!(
+            sections.merge_selected(&config, &["cache", "http", "unknown"]),
+            b"# --- section: http ---\nhttp_requests 4\n# --- section: cache ---\ncache_hits 2\n"
+        );
+
+        // Assert the last update time of each section is appended if enabled.
+        let config = SectionConfig {
+            timestamps: true,
+            ..Default::default()
+        };
+        let out = String::from_utf8(sections.merge_selected(&config, &["http"])).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(
+            lines[2],
+            "# TYPE metrics_section_last_update_timestamp_seconds gauge"
+        );
+        let (sample, value) = lines[3].split_once(' ').unwrap();
+        assert_eq!(
+            sample,
+            r#"metrics_section_last_update_timestamp_seconds{section="http"}"#
+        );
+        assert!(value.parse::<f64>().unwrap() > 1e9);
+
+        sections.clear();
+        assert!(sections.is_empty());
+        assert!(sections.merge(&config).is_empty());
+    }
+}
@@ -1,29 +1,648 @@
-use std::net::ToSocketAddrs;
-use std::str::FromStr;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+#[cfg(feature = "gzip")]
+use std::sync::OnceLock;
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
-use http::uri::PathAndQuery;
-use log::{debug, error};
+use arc_swap::{ArcSwap, ArcSwapOption};
+use http::header::{CONTENT_TYPE, LAST_MODIFIED, RETRY_AFTER, VARY, WWW_AUTHENTICATE};
+use http::{HeaderName, HeaderValue, Method, StatusCode, Version};
+use log::{debug, error, info, warn};
 use time::{format_description, OffsetDateTime};
-use tiny_http::{ConfigListenAddr, Method, Response, Server};
 
+use crate::acl::AccessList;
+use crate::addr;
+use crate::audit::{AuditEvent, AuditKind, AuditLog, AUDIT_PATH};
+use crate::auth::{AuthDecision, Authenticator, RequestMeta};
+use crate::builder::Builder;
+use crate::clock::{Clock, SystemClock};
 use crate::error::ServerError;
+use crate::json;
+use crate::limit::RateLimiter;
+use crate::listener::{Connection, Listener, Stream};
+#[cfg(unix)]
+use crate::mmap::Mmap;
+use crate::normalize::{normalize_path, CaseFolding};
+use crate::page::{self, Page};
+use crate::path::MetricsPath;
+use crate::request::{Limits, Request};
+use crate::response::{self, Response, WriteConfig};
+use crate::section::{SectionConfig, Sections};
+use crate::stats::{Overload, RequestStats};
+use crate::vars::{Var, Vars, VARS_PATH};
 
 /// The default metrics URL path of the server.
 pub const DEFAULT_METRICS_PATH: &str = "/metrics";
 
+/// The default Content-Type of metrics responses, the Prometheus text exposition format.
+pub const DEFAULT_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+// The Content-Type of the OpenMetrics variant of the payload.
+const OPENMETRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+// The media type and Content-Type of the protobuf variant of the payload.
+#[cfg(feature = "protobuf")]
+const PROTOBUF_MEDIA_TYPE: &str = "application/vnd.google.protobuf";
+#[cfg(feature = "protobuf")]
+const PROTOBUF_CONTENT_TYPE: &str =
+    "application/vnd.google.protobuf; proto=io.prometheus.client.MetricFamily; encoding=delimited";
+
+// The line terminating an OpenMetrics exposition.
+const OPENMETRICS_EOF: &[u8] = b"# EOF\n";
+
+// The response header containing the checksum of the metrics payload.
+const CHECKSUM_HEADER: HeaderName = HeaderName::from_static("x-content-checksum");
+
+// The response header containing the number of pages, if pagination is enabled.
+const PAGE_COUNT_HEADER: HeaderName = HeaderName::from_static("x-metrics-page-count");
+
+// The query parameter selecting sections to serve, as used by mysqld_exporter. Clients may
+// percent-encode the brackets.
+const COLLECT_PARAMS: [&str; 3] = ["collect[]", "collect%5B%5D", "collect%5b%5d"];
+
+// How often a nonblocking listener is polled for new connections.
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// How long to wait before accepting again after an error, e.g. running out of file descriptors.
+pub(crate) const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+// How long connections rejected by the connection limit are told to wait before retrying.
+const CONNECTION_RETRY_AFTER: Duration = Duration::from_secs(1);
+
 /// A thread-safe datastore for serving metrics via a HTTP/S server.
+///
+/// Dropping the server stops it, as if calling `stop`, so its threads never outlive it.
 pub struct MetricsServer {
     shared: Arc<SharedData>,
-    thread: Option<thread::JoinHandle<()>>,
+    threads: Vec<thread::JoinHandle<()>>,
+    // The path passed to `serve_uri`, until the server is stopped.
+    path: Option<String>,
 }
 
-struct SharedData {
-    data: Mutex<Vec<u8>>,
-    server: Server,
+pub(crate) struct SharedData {
+    data: ArcSwap<Payload>,
+    // The OpenMetrics and protobuf variants of the payload, served to scrapers preferring them,
+    // if published.
+    openmetrics: ArcSwapOption<Payload>,
+    #[cfg(feature = "protobuf")]
+    protobuf: ArcSwapOption<Payload>,
+    pub(crate) listeners: Vec<Listener>,
+    pub(crate) config: Config,
     stop: AtomicBool,
+    // The number of worker threads that haven't exited.
+    running: AtomicUsize,
+    // The data published by each producer of a sectioned payload.
+    sections: Mutex<Sections>,
+    // When the server was created, and the time since then that metrics were last scraped,
+    // in nanoseconds. Zero if never scraped.
+    created: Instant,
+    last_scrape: AtomicU64,
+    // Sockets of the connections currently being handled, keyed by a unique id.
+    in_flight: Mutex<HashMap<u64, TcpStream>>,
+    next_id: AtomicU64,
+    // The number of responses sent by status code, if self-metrics are enabled.
+    requests: RequestStats,
+    // The variables served at /debug/vars, if enabled.
+    vars: Vars,
+    // Rejects requests over the rate limit, if set.
+    limiter: Option<RateLimiter>,
+    // The number of open connections, and whether the connection limit was reached since a
+    // connection was last admitted.
+    connections: AtomicUsize,
+    connection_limited: AtomicBool,
+}
+
+impl SharedData {
+    // Replaces the whole payload, discarding any sections.
+    fn update(&self, data: Vec<u8>) -> usize {
+        let mut sections = self.sections.lock().unwrap();
+        sections.clear();
+        self.publish(data)
+    }
+
+    // Replaces a single section and publishes the merged payload.
+    fn update_section(&self, name: &str, data: Vec<u8>) -> usize {
+        let mut sections = self.sections.lock().unwrap();
+        sections.insert(name, data, self.config.clock.now());
+        self.publish(sections.merge(&self.config.sections))
+    }
+
+    // Applies any configured transforms to the data and publishes it.
+    fn publish(&self, data: Vec<u8>) -> usize {
+        let current = self.data.load();
+        match self.prepare(data, &current, Format::Text) {
+            Some(payload) => {
+                let len = payload.data.len();
+                // Swap in the new payload without blocking requests reading the previous one.
+                self.data.store(Arc::new(payload));
+                len
+            }
+            None => current.data.len(),
+        }
+    }
+
+    // Publishes the OpenMetrics variant of the payload.
+    fn update_openmetrics(&self, data: Vec<u8>) -> usize {
+        self.publish_variant(&self.openmetrics, data, Format::OpenMetrics)
+    }
+
+    // Publishes the protobuf variant of the payload.
+    #[cfg(feature = "protobuf")]
+    fn update_protobuf(&self, data: Vec<u8>) -> usize {
+        self.publish_variant(&self.protobuf, data, Format::Protobuf)
+    }
+
+    // Publishes a variant of the payload served to scrapers preferring its format.
+    fn publish_variant(
+        &self,
+        variant: &ArcSwapOption<Payload>,
+        data: Vec<u8>,
+        format: Format,
+    ) -> usize {
+        let current = variant.load();
+        let empty = Payload::default();
+        match self.prepare(data, current.as_deref().unwrap_or(&empty), format) {
+            Some(payload) => {
+                let len = payload.data.len();
+                variant.store(Some(Arc::new(payload)));
+                len
+            }
+            None => current.as_ref().map_or(0, |p| p.data.len()),
+        }
+    }
+
+    // Builds a payload from the data, or returns None if it is identical to the current
+    // payload's and updates are deduplicated.
+    //
+    // OpenMetrics expositions must end with `# EOF`, so it is moved after any data appended by
+    // transforms. Transforms and pagination only understand text, so protobuf payloads are
+    // served as given.
+    fn prepare(&self, data: Vec<u8>, current: &Payload, format: Format) -> Option<Payload> {
+        let config = &self.config;
+
+        // Skip republishing data identical to the current payload's.
+        let digest = config
+            .deduplicate
+            .then(|| xxhash_rust::xxh3::xxh3_64(&data));
+        if digest.is_some() && current.digest == digest {
+            return None;
+        }
+
+        let data = match format {
+            Format::Text => self.transform(data),
+            Format::OpenMetrics => {
+                let mut data = self.transform(strip_eof(data));
+                if !data.is_empty() && !data.ends_with(b"\n") {
+                    data.push(b'\n');
+                }
+                data.extend_from_slice(OPENMETRICS_EOF);
+                data
+            }
+            #[cfg(feature = "protobuf")]
+            Format::Protobuf => data,
+        };
+        let pages = match config.page_size {
+            #[cfg(feature = "protobuf")]
+            Some(_) if format == Format::Protobuf => Vec::new(),
+            Some(size) => page::paginate(&data, size)
+                .into_iter()
+                .map(|p| {
+                    let sum = config.checksum.then(|| checksum(&data[p.range.clone()]));
+                    (p, sum)
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        let checksum = config.checksum.then(|| checksum(&data));
+
+        #[cfg(feature = "gzip")]
+        let compressed = (0..=pages.len()).map(|_| OnceLock::new()).collect();
+        Some(Payload {
+            data: Storage::new(data, config),
+            checksum,
+            pages,
+            file: None,
+            updated: Some(config.clock.now()),
+            digest,
+            #[cfg(feature = "gzip")]
+            compressed,
+        })
+    }
+
+    // Returns true if any worker threads are serving requests.
+    fn is_running(&self) -> bool {
+        self.running.load(Ordering::Relaxed) > 0
+    }
+
+    // Returns true if the server has been asked to stop, but its workers haven't all exited.
+    fn is_stopping(&self) -> bool {
+        self.stop.load(Ordering::Relaxed)
+    }
+
+    // Records an administrative action, if auditing is enabled.
+    fn audit<F>(&self, kind: AuditKind, detail: F)
+    where
+        F: FnOnce() -> String,
+    {
+        if let Some(audit) = &self.config.audit {
+            audit.record(kind, detail());
+        }
+    }
+
+    // Returns the recorded administrative actions, oldest first.
+    fn audit_events(&self) -> Vec<AuditEvent> {
+        self.config
+            .audit
+            .as_ref()
+            .map_or(Vec::new(), |a| a.events())
+    }
+
+    // Records that the metrics are being scraped.
+    fn scraped(&self) {
+        // Never store zero, so the first scrape is recorded even if immediate.
+        let elapsed = self
+            .config
+            .clock
+            .instant()
+            .saturating_duration_since(self.created);
+        let nanos = elapsed.as_nanos().max(1);
+        let nanos = u64::try_from(nanos).unwrap_or(u64::MAX);
+        self.last_scrape.store(nanos, Ordering::Relaxed);
+    }
+
+    // Returns when the metrics were last scraped, if ever.
+    fn last_scrape(&self) -> Option<Instant> {
+        match self.last_scrape.load(Ordering::Relaxed) {
+            0 => None,
+            nanos => Some(self.created + Duration::from_nanos(nanos)),
+        }
+    }
+
+    // Applies any configured transforms to the data, followed by the self-metrics if enabled.
+    fn transform(&self, data: Vec<u8>) -> Vec<u8> {
+        let data = self
+            .config
+            .transforms
+            .iter()
+            .fold(data, |data, transform| transform(data));
+        match self.config.self_metrics {
+            true => self.requests.append_metrics(data),
+            false => data,
+        }
+    }
+
+    // Records a response sent with the given status, if self-metrics are enabled.
+    pub(crate) fn responded(&self, status: StatusCode) {
+        if self.config.self_metrics {
+            self.requests.record(status);
+        }
+    }
+
+    // Records the HTTP version of a parsed request, if self-metrics are enabled.
+    fn requested(&self, version: Version) {
+        if self.config.self_metrics {
+            self.requests.record_version(version);
+        }
+    }
+
+    // Replaces the certificate of every listener serving one given as PEM data.
+    #[cfg(feature = "tls")]
+    fn reload_tls(&self, certificate: &[u8], private_key: &[u8]) -> Result<(), ServerError> {
+        let resolvers: Vec<_> = self.listeners.iter().filter_map(|l| l.pem()).collect();
+        if resolvers.is_empty() {
+            return Err(ServerError::Create(
+                "no listeners serve a certificate given as PEM data".to_string(),
+            ));
+        }
+        resolvers
+            .iter()
+            .try_for_each(|r| r.reload(certificate, private_key))
+    }
+
+    // Returns a response rejecting the request if it's over the rate limit.
+    fn shed(&self) -> Option<Response> {
+        let now = self.config.clock.instant();
+        let wait = self.limiter.as_ref()?.acquire(now).err()?;
+        if self.config.self_metrics {
+            self.requests.record_overload(Overload::RateLimit);
+        }
+        Some(overloaded(wait))
+    }
+
+    // Counts a newly accepted connection as open until the returned permit is dropped, or returns
+    // None if the connection limit is reached, in which case it should be sent `rejected()`.
+    pub(crate) fn admit(self: &Arc<Self>) -> Option<ConnectionPermit> {
+        let open = self.connections.fetch_add(1, Ordering::Relaxed);
+        let permit = ConnectionPermit(Arc::clone(self));
+        let Some(max) = self.config.max_connections else {
+            return Some(permit);
+        };
+
+        if open < max {
+            if self.connection_limited.swap(false, Ordering::Relaxed) {
+                info!("metrics server accepting connections again");
+            }
+            return Some(permit);
+        }
+
+        drop(permit);
+        if !self.connection_limited.swap(true, Ordering::Relaxed) {
+            warn!("metrics server connection limit of {max} reached, rejecting connections");
+        }
+        if self.config.self_metrics {
+            self.requests.record_overload(Overload::Connections);
+        }
+        None
+    }
+
+    // Builds a response containing only the selected sections, or None if the payload
+    // isn't sectioned.
+    fn collect(&self, names: &[&str]) -> Option<Response> {
+        let data = {
+            let sections = self.sections.lock().unwrap();
+            if sections.is_empty() {
+                return None;
+            }
+            sections.merge_selected(&self.config.sections, names)
+        };
+
+        let data = self.transform(data);
+        let checksum = self.config.checksum.then(|| checksum(&data));
+        let res = Response::from_data(data);
+        Some(match checksum {
+            Some(sum) => res.with_header(CHECKSUM_HEADER, sum),
+            None => res,
+        })
+    }
+
+    // Publishes a file to be served in place of the data, returning its current size.
+    fn update_file(&self, path: &Path) -> io::Result<u64> {
+        let metadata = fs::metadata(path)?;
+        if !metadata.is_file() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a file", path.display()),
+            ));
+        }
+
+        let mut sections = self.sections.lock().unwrap();
+        sections.clear();
+        self.openmetrics.store(None);
+        #[cfg(feature = "protobuf")]
+        self.protobuf.store(None);
+        self.data.store(Arc::new(Payload {
+            file: Some(path.to_path_buf()),
+            updated: Some(self.config.clock.now()),
+            ..Default::default()
+        }));
+        Ok(metadata.len())
+    }
+}
+
+/// A cloneable handle used to update the data in a `MetricsServer`.
+///
+/// Handles share the server's data, so updates are served by the server while it is running.
+/// The server's sockets are only closed once the server and all of its handles are dropped.
+#[derive(Clone)]
+pub struct MetricsHandle {
+    pub(crate) shared: Arc<SharedData>,
+}
+
+impl MetricsHandle {
+    /// Thread safe method for updating the data in the `MetricsServer`, returning the number of
+    /// bytes written.
+    pub fn update(&self, data: Vec<u8>) -> usize {
+        self.shared.update(data)
+    }
+
+    /// Replaces the data of a single named section, returning the number of bytes written.
+    ///
+    /// See `MetricsServer::update_section`.
+    pub fn update_section(&self, name: &str, data: Vec<u8>) -> usize {
+        self.shared.update_section(name, data)
+    }
+
+    /// Replaces the OpenMetrics variant of the data, returning the number of bytes written.
+    ///
+    /// See `MetricsServer::update_openmetrics`.
+    pub fn update_openmetrics(&self, data: Vec<u8>) -> usize {
+        self.shared.update_openmetrics(data)
+    }
+
+    /// Replaces the protobuf variant of the data, returning the number of bytes written.
+    ///
+    /// See `MetricsServer::update_protobuf`.
+    #[cfg(feature = "protobuf")]
+    pub fn update_protobuf(&self, data: Vec<u8>) -> usize {
+        self.shared.update_protobuf(data)
+    }
+
+    /// Sets a variable served at `/debug/vars`.
+    ///
+    /// See `MetricsServer::set_var`.
+    pub fn set_var<V>(&self, name: &str, value: V)
+    where
+        V: Into<Var>,
+    {
+        self.shared.vars.set(name, value.into())
+    }
+
+    /// Replaces the certificate served by HTTPS listeners.
+    ///
+    /// See `MetricsServer::reload_tls`.
+    #[cfg(feature = "tls")]
+    pub fn reload_tls(
+        &self,
+        certificate: Vec<u8>,
+        private_key: Vec<u8>,
+    ) -> Result<(), ServerError> {
+        self.shared.reload_tls(&certificate, &private_key)
+    }
+
+    /// Returns when the data was last updated, or None if it never has been.
+    ///
+    /// See `MetricsServer::last_updated`.
+    pub fn last_updated(&self) -> Option<SystemTime> {
+        self.shared.data.load().updated
+    }
+
+    /// Returns when the metrics were last scraped, or None if they never have been.
+    ///
+    /// See `MetricsServer::last_scrape`.
+    pub fn last_scrape(&self) -> Option<Instant> {
+        self.shared.last_scrape()
+    }
+
+    /// Returns the administrative actions recorded in the audit log, oldest first.
+    ///
+    /// See `MetricsServer::audit_events`.
+    pub fn audit_events(&self) -> Vec<AuditEvent> {
+        self.shared.audit_events()
+    }
+
+    /// Returns true if the server has worker threads serving requests.
+    ///
+    /// See `MetricsServer::is_running`.
+    pub fn is_running(&self) -> bool {
+        self.shared.is_running()
+    }
+
+    /// Returns true while the server is stopping.
+    ///
+    /// See `MetricsServer::is_stopping`.
+    pub fn is_stopping(&self) -> bool {
+        self.shared.is_stopping()
+    }
+
+    /// Serves the contents of a file instead of the data, returning its current size.
+    ///
+    /// See `MetricsServer::update_file`.
+    pub fn update_file<P: AsRef<Path>>(&self, path: P) -> io::Result<u64> {
+        self.shared.update_file(path.as_ref())
+    }
+}
+
+// The current metrics and any values derived from them at update time.
+#[derive(Default)]
+struct Payload {
+    data: Storage,
+    checksum: Option<HeaderValue>,
+    pages: Vec<(Page, Option<HeaderValue>)>,
+    // A file served in place of the data, read on every request.
+    file: Option<PathBuf>,
+    // When the payload was published, if ever.
+    updated: Option<SystemTime>,
+    // The hash of the data before transforms, if deduplicating updates.
+    digest: Option<u64>,
+    // The gzip encoding of the whole payload followed by each page, compressed on demand.
+    #[cfg(feature = "gzip")]
+    compressed: Vec<OnceLock<Vec<u8>>>,
+}
+
+// The exposition format of a payload.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Text,
+    OpenMetrics,
+    #[cfg(feature = "protobuf")]
+    Protobuf,
+}
+
+// The memory backing a payload's data.
+enum Storage {
+    Heap(Vec<u8>),
+    #[cfg(unix)]
+    Mapped(Mmap),
+}
+
+impl Storage {
+    // Moves the data into a memory-mapped file if configured, falling back to the heap.
+    #[cfg_attr(not(unix), allow(unused_variables))]
+    fn new(data: Vec<u8>, config: &Config) -> Self {
+        #[cfg(unix)]
+        if let Some(dir) = config.mmap_dir.as_deref().filter(|_| !data.is_empty()) {
+            match Mmap::new(dir, &data) {
+                Ok(mmap) => return Storage::Mapped(mmap),
+                Err(e) => error!("error memory-mapping metrics in {}: {e}", dir.display()),
+            }
+        }
+        Storage::Heap(data)
+    }
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Storage::Heap(Vec::new())
+    }
+}
+
+impl Deref for Storage {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Storage::Heap(data) => data,
+            #[cfg(unix)]
+            Storage::Mapped(mmap) => mmap,
+        }
+    }
+}
+
+// A transformation applied to the payload before it is served.
+pub(crate) type Transform = Arc<dyn Fn(Vec<u8>) -> Vec<u8> + Send + Sync>;
+
+// Server-wide settings applied to requests on every listener.
+#[derive(Clone)]
+pub(crate) struct Config {
+    pub(crate) limits: Limits,
+    pub(crate) write: WriteConfig,
+    pub(crate) expect_continue: bool,
+    pub(crate) checksum: bool,
+    pub(crate) content_type: HeaderValue,
+    pub(crate) deduplicate: bool,
+    pub(crate) page_size: Option<usize>,
+    pub(crate) sections: SectionConfig,
+    pub(crate) workers: usize,
+    #[cfg(unix)]
+    pub(crate) mmap_dir: Option<PathBuf>,
+    pub(crate) auth: Option<Arc<dyn Authenticator>>,
+    pub(crate) audit: Option<Arc<AuditLog>>,
+    pub(crate) audit_endpoint: bool,
+    pub(crate) debug_vars: bool,
+    pub(crate) rate_limit: Option<(u32, u32)>,
+    pub(crate) max_connections: Option<usize>,
+    pub(crate) access: AccessList,
+    pub(crate) path_case: CaseFolding,
+    pub(crate) refuse_http10: bool,
+    pub(crate) clock: Arc<dyn Clock>,
+    pub(crate) self_metrics: bool,
+    pub(crate) self_check: bool,
+    pub(crate) json: bool,
+    pub(crate) transforms: Vec<Transform>,
+    #[cfg(feature = "tokio")]
+    pub(crate) runtimes: Vec<tokio::runtime::Handle>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            limits: Limits::default(),
+            write: WriteConfig::default(),
+            expect_continue: true,
+            checksum: false,
+            content_type: HeaderValue::from_static(DEFAULT_CONTENT_TYPE),
+            deduplicate: false,
+            page_size: None,
+            sections: SectionConfig::default(),
+            workers: 1,
+            #[cfg(unix)]
+            mmap_dir: None,
+            auth: None,
+            audit: None,
+            audit_endpoint: false,
+            debug_vars: false,
+            rate_limit: None,
+            max_connections: None,
+            access: AccessList::default(),
+            path_case: CaseFolding::Preserve,
+            refuse_http10: false,
+            clock: Arc::new(SystemClock),
+            self_metrics: false,
+            self_check: false,
+            json: false,
+            transforms: Vec::new(),
+            #[cfg(feature = "tokio")]
+            runtimes: Vec::new(),
+        }
+    }
 }
 
 impl MetricsServer {
@@ -36,41 +655,66 @@ impl MetricsServer {
     where
         A: ToSocketAddrs,
     {
-        // Construct listener from address.
-        let listener = ConfigListenAddr::from_socket_addrs(addr)
-            .map_err(|e| ServerError::Create(e.to_string()))?;
+        let builder = MetricsServer::builder().address(addr);
 
         // Parse TLS config.
-        let config = match (certificate, private_key) {
+        let builder = match (certificate, private_key) {
             #[cfg(feature = "tls")]
-            (Some(certificate), Some(private_key)) => tiny_http::ServerConfig {
-                addr: listener,
-                ssl: Some(tiny_http::SslConfig {
-                    certificate,
-                    private_key,
-                }),
-            },
+            (Some(certificate), Some(private_key)) => builder.tls(certificate, private_key),
             // Default to no TLS.
-            _ => tiny_http::ServerConfig {
-                addr: listener,
-                ssl: None,
-            },
+            _ => builder,
         };
 
-        // Attempt to create a new server.
-        let server = Server::new(config).map_err(|e| ServerError::Create(e.to_string()))?;
+        builder.build()
+    }
+
+    /// Creates an empty `MetricsServer` serving HTTP on a listener the caller has already bound.
+    ///
+    /// This allows custom socket options to be set, or privileged ports to be bound before
+    /// dropping privileges.
+    pub fn from_listener(listener: TcpListener) -> Result<Self, ServerError> {
+        MetricsServer::builder().tcp_listener(listener).build()
+    }
+
+    /// Returns a `Builder` used to configure the server and its underlying socket.
+    pub fn builder() -> Builder {
+        Builder::new()
+    }
+
+    // Creates an empty `MetricsServer` from a set of bound listeners.
+    pub(crate) fn from_parts(listeners: Vec<Listener>, config: Config) -> Self {
+        let now = config.clock.instant();
+        let limiter = config
+            .rate_limit
+            .map(|(rate, burst)| RateLimiter::new(rate, burst, now));
 
         // Create an Arc of the shared data.
         let shared = Arc::new(SharedData {
-            data: Mutex::new(Vec::new()),
-            server,
+            data: ArcSwap::from_pointee(Payload::default()),
+            openmetrics: ArcSwapOption::empty(),
+            #[cfg(feature = "protobuf")]
+            protobuf: ArcSwapOption::empty(),
+            listeners,
+            config,
             stop: AtomicBool::new(false),
+            running: AtomicUsize::new(0),
+            sections: Mutex::new(Sections::default()),
+            created: now,
+            last_scrape: AtomicU64::new(0),
+            in_flight: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+            requests: RequestStats::default(),
+            vars: Vars::default(),
+            limiter,
+            connections: AtomicUsize::new(0),
+            connection_limited: AtomicBool::new(false),
         });
 
-        Ok(MetricsServer {
+        MetricsServer {
             shared,
-            thread: None,
-        })
+            threads: Vec::new(),
+            path: None,
+        }
     }
 
     /// Shortcut for creating an empty `MetricsServer` and starting a HTTP server on a new thread at the given address.
@@ -84,16 +728,45 @@ impl MetricsServer {
     where
         A: ToSocketAddrs,
     {
-        let mut server = MetricsServer::new(addr, None, None).unwrap();
+        MetricsServer::try_http(addr).unwrap()
+    }
+
+    /// Creates an empty `MetricsServer` and starts a HTTP server on a new thread at the given address.
+    ///
+    /// This is the non-panicking version of `http`, returning an error if given an invalid address.
+    pub fn try_http<A>(addr: A) -> Result<Self, ServerError>
+    where
+        A: ToSocketAddrs,
+    {
+        let mut server = MetricsServer::new(addr, None, None)?;
         server.serve();
-        server
+        Ok(server)
+    }
+
+    /// Creates an empty `MetricsServer` and starts a HTTP server on a new thread at a port on
+    /// localhost chosen by the OS, for use in tests.
+    ///
+    /// Unlike a fixed port, the chosen port is never in use, so tests running in parallel don't
+    /// collide. Send requests to the address returned by `local_addr`.
+    ///
+    /// ```rust
+    /// let server = metrics_server::MetricsServer::spawn_for_tests();
+    /// let url = format!("http://{}/metrics", server.local_addr());
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if no port can be bound.
+    pub fn spawn_for_tests() -> Self {
+        MetricsServer::http("127.0.0.1:0")
     }
 
     /// Shortcut for creating an empty `MetricsServer` and starting a HTTPS server on a new thread at the given address.
     ///
     /// The server will only respond synchronously as it blocks until receiving new requests.
     ///
-    /// Note: there is currently no option to skip TLS cert verification.
+    /// Note: there is currently no option to skip TLS cert verification. Encrypted private keys
+    /// can be used with `Builder::tls_key_passphrase`.
     ///
     /// # Panics
     ///
@@ -103,142 +776,1132 @@ impl MetricsServer {
     where
         A: ToSocketAddrs,
     {
-        let mut server = MetricsServer::new(addr, Some(certificate), Some(private_key)).unwrap();
+        MetricsServer::try_https(addr, certificate, private_key).unwrap()
+    }
+
+    /// Creates an empty `MetricsServer` and starts a HTTPS server on a new thread at the given address.
+    ///
+    /// This is the non-panicking version of `https`, returning an error if given an invalid address
+    /// or incorrect TLS credentials.
+    #[cfg(feature = "tls")]
+    pub fn try_https<A>(
+        addr: A,
+        certificate: Vec<u8>,
+        private_key: Vec<u8>,
+    ) -> Result<Self, ServerError>
+    where
+        A: ToSocketAddrs,
+    {
+        let mut server = MetricsServer::new(addr, Some(certificate), Some(private_key))?;
         server.serve();
-        server
+        Ok(server)
     }
 
-    /// Thread safe method for updating the data in a `MetricsServer`, returning the number of bytes written.
-    pub fn update(&self, data: Vec<u8>) -> usize {
-        let mut buf = self.shared.data.lock().unwrap();
-        *buf = data;
-        buf.len()
+    /// Shortcut for creating an empty `MetricsServer` and starting a HTTPS server on a new thread
+    /// at the given address, using the PEM encoded certificate chain and private key at the
+    /// given paths.
+    ///
+    /// The files are re-read whenever they change, see `Builder::tls_files`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if given an invalid address, or the files can't be read or contain incorrect TLS
+    /// credentials.
+    #[cfg(feature = "tls")]
+    pub fn https_files<A, P>(addr: A, certificate: P, private_key: P) -> Self
+    where
+        A: ToSocketAddrs,
+        P: AsRef<Path>,
+    {
+        MetricsServer::try_https_files(addr, certificate, private_key).unwrap()
     }
 
-    /// Start serving requests to the /metrics URL path on the underlying server.
+    /// Creates an empty `MetricsServer` and starts a HTTPS server on a new thread at the given
+    /// address, using the PEM encoded certificate chain and private key at the given paths.
     ///
-    /// The server will only respond synchronously as it blocks until receiving new requests.
-    /// Suqsequent calls to this method will return a no-op and not affect the underlying server.
-    pub fn serve(&mut self) {
-        self.serve_uri(DEFAULT_METRICS_PATH.to_string())
+    /// This is the non-panicking version of `https_files`.
+    #[cfg(feature = "tls")]
+    pub fn try_https_files<A, P>(
+        addr: A,
+        certificate: P,
+        private_key: P,
+    ) -> Result<Self, ServerError>
+    where
+        A: ToSocketAddrs,
+        P: AsRef<Path>,
+    {
+        let mut server = MetricsServer::builder()
+            .address(addr)
+            .tls_files(certificate, private_key)
+            .build()?;
+        server.serve();
+        Ok(server)
     }
 
-    /// Start serving requests to a specific URL path on the underlying server.
+    /// Returns the address the server is listening on.
     ///
-    /// The server will only respond synchronously as it blocks until receiving new requests.
-    /// Suqsequent calls to this method will return a no-op and not affect the underlying server.
-    pub fn serve_uri(&mut self, path: String) {
-        // Check if we already have a thread running.
-        if let Some(thread) = &self.thread {
-            if !thread.is_finished() {
-                debug!("metrics server already running, continuing");
-                return;
-            }
-        }
+    /// This is useful when binding to port 0, as it returns the port chosen by the OS. If the
+    /// server has additional listeners, the address of the primary listener is returned.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.shared.listeners[0].local_addr()
+    }
 
-        // Ensure path is valid.
-        let path = parse_path(&path);
+    /// Returns the administrative actions recorded in the audit log, oldest first.
+    ///
+    /// Actions are only recorded if enabled with `Builder::audit`, and the oldest are discarded
+    /// once the log is full.
+    pub fn audit_events(&self) -> Vec<AuditEvent> {
+        self.shared.audit_events()
+    }
 
-        // Invoking clone on Arc produces a new Arc instance, which points to the
-        // same allocation on the heap as the source Arc, while increasing a reference count.
-        let s = Arc::clone(&self.shared);
+    /// Returns true if the server has worker threads serving requests.
+    ///
+    /// This is false before `serve` is called, after the server is stopped, and if every worker
+    /// has exited unexpectedly, e.g. by panicking, making it suitable for health checks.
+    /// Servers only served asynchronously are never reported as running.
+    pub fn is_running(&self) -> bool {
+        self.shared.is_running()
+    }
 
-        // Handle requests in a new thread so we can process in the background.
-        self.thread = Some(thread::spawn({
-            move || {
-                // Blocks until the next request is received.
-                for req in s.server.incoming_requests() {
-                    // Check to see if we should stop handling requests.
-                    if s.stop.load(Ordering::Relaxed) {
-                        debug!("metrics server stopping");
-                        return;
-                    }
+    /// Returns true while the server is stopping, i.e. a call to `stop` or `stop_graceful` is
+    /// waiting for its workers to exit.
+    ///
+    /// As stopping requires exclusive access to the server, this is mostly useful via a
+    /// `MetricsHandle` held by supervision code.
+    pub fn is_stopping(&self) -> bool {
+        self.shared.is_stopping()
+    }
 
-                    // Only serve the specified URI path.
-                    if req.url() != path {
-                        let res = Response::empty(404);
-                        respond(req, res);
-                        continue;
-                    }
+    /// Returns the URL path passed to `serve` or `serve_uri`, or None if the server hasn't been
+    /// served or has since been stopped.
+    ///
+    /// Listeners configured with their own path via `ListenerConfig::path` serve that path instead.
+    pub fn path(&self) -> Option<&str> {
+        self.path.as_deref()
+    }
 
-                    // Only respond to GET requests.
-                    if req.method() != &Method::Get {
-                        let res = Response::empty(405);
-                        respond(req, res);
-                        continue;
-                    }
+    /// Returns the addresses of all listeners, starting with the primary listener.
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.shared
+            .listeners
+            .iter()
+            .map(Listener::local_addr)
+            .collect()
+    }
 
-                    // Write the metrics to the response buffer.
-                    let metrics = s.data.lock().unwrap();
-                    let res = Response::from_data(metrics.as_slice());
-                    respond(req, res);
-                }
-            }
-        }));
+    /// Thread safe method for updating the data in a `MetricsServer`, returning the number of bytes written.
+    ///
+    /// Any configured transforms, such as label redaction, and the payload checksum if enabled are
+    /// applied here rather than on every request.
+    pub fn update(&self, data: Vec<u8>) -> usize {
+        self.shared.update(data)
     }
 
-    /// Stop serving requests and free thread resources.
-    pub fn stop(mut self) -> Result<(), ServerError> {
-        // Signal that we should stop handling requests and unblock the server.
-        self.shared.stop.store(true, Ordering::Relaxed);
-        self.shared.server.unblock();
+    /// Replaces the data of a single named section, returning the number of bytes written.
+    ///
+    /// Sections let several producers, e.g. each holding a `MetricsHandle`, publish their
+    /// metrics independently. The latest data of every section is merged into one payload,
+    /// ordered by `Builder::section_order`, before transforms are applied. Calling `update` or
+    /// `update_file` replaces the whole payload and discards all sections.
+    ///
+    /// Scrapers can request a subset of the sections with `collect[]` query parameters, e.g.
+    /// `/metrics?collect[]=db&collect[]=http`, following mysqld_exporter's convention. Such
+    /// requests are merged and transformed on demand, and aren't paginated.
+    pub fn update_section(&self, name: &str, data: Vec<u8>) -> usize {
+        self.shared.update_section(name, data)
+    }
 
-        // Because join takes ownership of the thread, we need to call the take method
-        // on the Option to move the value out of the Some variant and leave a None
-        // variant in its place.
-        match self.thread.take() {
-            Some(thread) => thread.join().map_err(|e| {
-                let err = match e.downcast_ref::<String>() {
-                    Some(s) => s,
-                    None => "unknown",
-                };
+    /// Replaces the OpenMetrics variant of the data, returning the number of bytes written.
+    ///
+    /// Once published, scrapers whose `Accept` header prefers `application/openmetrics-text`
+    /// over `text/plain`, as Prometheus does by default, are served this variant with the
+    /// OpenMetrics content type, while other clients continue to receive the data passed to
+    /// `update`. A trailing `# EOF` line is optional, as one is appended after transforms are
+    /// applied. Calling `update_file` discards this variant.
+    pub fn update_openmetrics(&self, data: Vec<u8>) -> usize {
+        self.shared.update_openmetrics(data)
+    }
 
-                ServerError::Stop(err.to_string())
-            }),
-            None => Ok(()),
-        }
+    /// Replaces the protobuf variant of the data, returning the number of bytes written.
+    /// Requires the `protobuf` feature.
+    ///
+    /// The data must be length-delimited `io.prometheus.client.MetricFamily` messages, as
+    /// encoded by the client library. Once published, scrapers whose `Accept` header prefers
+    /// `application/vnd.google.protobuf` over the text formats are served this variant. Unlike
+    /// text, it isn't transformed or paginated. Calling `update_file` discards this variant.
+    #[cfg(feature = "protobuf")]
+    pub fn update_protobuf(&self, data: Vec<u8>) -> usize {
+        self.shared.update_protobuf(data)
     }
-}
 
-// Validate the provided URL path, or return the default path on error.
-fn parse_path(uri: &str) -> String {
-    match PathAndQuery::from_str(uri) {
-        Ok(pq) => {
-            let mut path = pq.path().to_lowercase();
-            if !path.starts_with('/') {
-                path.insert(0, '/');
-            }
-            path
-        }
-        Err(_) => {
-            error!("invalid uri, defaulting to {DEFAULT_METRICS_PATH}");
-            DEFAULT_METRICS_PATH.to_string()
+    /// Sets a variable served at `/debug/vars`, replacing any previous value.
+    ///
+    /// Once enabled with `Builder::debug_vars`, the variables are served as a JSON object, as
+    /// Go's `expvar` package does, for tooling that expects it. Variables are independent of
+    /// the metrics, so setting one doesn't change the data.
+    pub fn set_var<V>(&self, name: &str, value: V)
+    where
+        V: Into<Var>,
+    {
+        self.shared.vars.set(name, value.into())
+    }
+
+    /// Replaces the PEM encoded certificate chain and private key served by every HTTPS
+    /// listener configured with `Builder::tls` or `tls_pkcs12`, e.g. once a renewed certificate
+    /// is issued.
+    ///
+    /// New connections are served the new certificate, while connections already established
+    /// keep the previous one, so scrapes aren't interrupted. Returns an error, and keeps serving
+    /// the previous certificate, if the new one is invalid or no such listener was configured.
+    /// Listeners configured with `Builder::tls_files` reload their certificate automatically.
+    #[cfg(feature = "tls")]
+    pub fn reload_tls(
+        &self,
+        certificate: Vec<u8>,
+        private_key: Vec<u8>,
+    ) -> Result<(), ServerError> {
+        self.shared.reload_tls(&certificate, &private_key)
+    }
+
+    /// Serves the contents of a file instead of the data, returning its current size.
+    ///
+    /// The file is opened on every request and sent directly from the page cache, using
+    /// `sendfile(2)` on Linux for plain HTTP connections, so large payloads, such as those
+    /// written by a textfile collector, are never read into memory. Files should be replaced
+    /// atomically, e.g. by renaming a new file over the old one, so requests never see a
+    /// partially written payload.
+    ///
+    /// Transforms, pagination and checksums are not applied to files. The file is served until
+    /// the next call to `update` or `update_file`.
+    pub fn update_file<P: AsRef<Path>>(&self, path: P) -> io::Result<u64> {
+        self.shared.update_file(path.as_ref())
+    }
+
+    /// Returns when the data was last updated, or None if it never has been.
+    ///
+    /// This is the time of the last call to `update`, `update_section` or `update_file` that
+    /// changed the payload, which is also sent to scrapers in the `Last-Modified` header so
+    /// stale exporters can be detected. Files are sent with their modification time instead.
+    pub fn last_updated(&self) -> Option<SystemTime> {
+        self.shared.data.load().updated
+    }
+
+    /// Returns when the metrics were last scraped, or None if they never have been.
+    ///
+    /// Producers with expensive encoders can use this to skip updates while nothing is scraping
+    /// the server, e.g. `server.last_scrape().is_some_and(|t| t.elapsed() < interval)`. Only
+    /// authorized requests for the metrics count as scrapes.
+    pub fn last_scrape(&self) -> Option<Instant> {
+        self.shared.last_scrape()
+    }
+
+    /// Returns a cheap, cloneable handle that can update the data in this server.
+    ///
+    /// Handles can be passed to the subsystems producing metrics, while the owner of the
+    /// `MetricsServer` keeps control of stopping it.
+    pub fn handle(&self) -> MetricsHandle {
+        MetricsHandle {
+            shared: Arc::clone(&self.shared),
         }
     }
+
+    /// Returns a `tower::Service` serving this server's metrics, so they can also be mounted in an
+    /// existing HTTP server such as axum or hyper. Requires the `tower` feature.
+    #[cfg(feature = "tower")]
+    pub fn service(&self) -> crate::MetricsService {
+        crate::MetricsService::new(self.handle())
+    }
+
+    /// Start serving requests to the /metrics URL path on the underlying server.
+    ///
+    /// The server will only respond synchronously as it blocks until receiving new requests.
+    /// Suqsequent calls to this method will return a no-op and not affect the underlying server.
+    pub fn serve(&mut self) {
+        self.serve_uri(MetricsPath::default())
+    }
+
+    /// Start serving requests to a specific URL path on the underlying server.
+    ///
+    /// Listeners configured with their own path via `ListenerConfig::path` continue to serve
+    /// that path.
+    ///
+    /// Plain strings are lowercased, and invalid paths fall back to the default metrics path,
+    /// see `MetricsPath::legacy`. Pass a `MetricsPath` to reject invalid paths up front instead.
+    ///
+    /// The server will only respond synchronously as it blocks until receiving new requests.
+    /// Suqsequent calls to this method will return a no-op and not affect the underlying server,
+    /// even if given a different path. Use `try_serve_uri` to detect this.
+    pub fn serve_uri<P>(&mut self, path: P)
+    where
+        P: Into<MetricsPath>,
+    {
+        if let Err(e) = self.try_serve_uri(path) {
+            debug!("{e}, continuing");
+        }
+    }
+
+    /// Start serving requests to the /metrics URL path on the underlying server.
+    ///
+    /// This is the fallible version of `serve`. See `try_serve_uri`.
+    pub fn try_serve(&mut self) -> Result<(), ServerError> {
+        self.try_serve_uri(MetricsPath::default())
+    }
+
+    /// Start serving requests to a specific URL path on the underlying server.
+    ///
+    /// This is the fallible version of `serve_uri`. If the server is already running, it keeps
+    /// serving its current path: calling this again with the same path succeeds, while a
+    /// different path returns `ServerError::AlreadyRunning` with the active path. To switch
+    /// paths, stop the server first.
+    ///
+    /// If enabled with `Builder::self_check`, each listener is then sent a request, and the
+    /// server is stopped again, returning `ServerError::SelfCheck`, if any of them fails.
+    pub fn try_serve_uri<P>(&mut self, path: P) -> Result<(), ServerError>
+    where
+        P: Into<MetricsPath>,
+    {
+        let path = path.into().into_string();
+
+        // Check if we already have threads running.
+        if self.threads.iter().any(|t| !t.is_finished()) {
+            return match self.path.as_deref() {
+                Some(active) if active == path => Ok(()),
+                active => Err(ServerError::AlreadyRunning(
+                    active.unwrap_or_default().to_string(),
+                )),
+            };
+        }
+
+        self.path = Some(path.clone());
+        self.shared
+            .audit(AuditKind::Start, || format!("serving {path}"));
+
+        // Handle requests to each listener in new threads so we can process in the background.
+        // Every worker accepts connections from the same listener, so slow clients only block
+        // the worker serving them.
+        let workers = self.shared.config.workers;
+        self.threads = Vec::new();
+        for i in 0..self.shared.listeners.len() {
+            if self.shared.config.max_connections.is_none() {
+                for _ in 0..workers {
+                    let path = path.clone();
+                    self.spawn(move |s| {
+                        let endpoint = Endpoint::new(&s.listeners[i], &path, &s.config);
+                        accept(s, &s.listeners[i], |conn| {
+                            let _in_flight = InFlight::track(s, &conn);
+                            handle(s, &endpoint, conn)
+                        });
+                    });
+                }
+                continue;
+            }
+
+            // With a connection limit, a single thread accepts connections so every open
+            // connection is counted, rejecting those over the limit before they're queued for
+            // the workers.
+            let (queue, queued) = mpsc::channel::<(Connection, ConnectionPermit)>();
+            let queued = Arc::new(Mutex::new(queued));
+            self.spawn(move |s| {
+                accept(s, &s.listeners[i], |mut conn| match s.admit() {
+                    Some(permit) => {
+                        let _ = queue.send((conn, permit));
+                    }
+                    None => send_early(s, &mut conn.stream, rejected()),
+                });
+            });
+            for _ in 0..workers {
+                let (path, queued) = (path.clone(), Arc::clone(&queued));
+                self.spawn(move |s| {
+                    let endpoint = Endpoint::new(&s.listeners[i], &path, &s.config);
+                    loop {
+                        // The queue is closed once the accepting thread stops.
+                        let next = queued.lock().unwrap().recv();
+                        let Ok((conn, _permit)) = next else {
+                            return;
+                        };
+                        // Connections still queued when stopping are dropped unanswered.
+                        if s.stop.load(Ordering::Relaxed) {
+                            continue;
+                        }
+                        let _in_flight = InFlight::track(s, &conn);
+                        handle(s, &endpoint, conn)
+                    }
+                });
+            }
+        }
+
+        if self.shared.config.self_check {
+            if let Err(e) = self.self_check(&path) {
+                self.signal_stop();
+                let _ = self.join();
+                return Err(e);
+            }
+        }
+
+        info!("{}", self.banner(&path));
+        Ok(())
+    }
+
+    // Runs a new thread with the shared data, counting it as running until it returns.
+    fn spawn<F>(&mut self, f: F)
+    where
+        F: FnOnce(&Arc<SharedData>) + Send + 'static,
+    {
+        // Invoking clone on Arc produces a new Arc instance, which points to the
+        // same allocation on the heap as the source Arc, while increasing a reference count.
+        let s = Arc::clone(&self.shared);
+        s.running.fetch_add(1, Ordering::Relaxed);
+        self.threads.push(thread::spawn(move || {
+            let _running = Running(&s.running);
+            f(&s)
+        }));
+    }
+
+    // Describes the effective configuration of every listener, so it can be confirmed from logs.
+    fn banner(&self, path: &str) -> String {
+        let listeners: Vec<String> = self
+            .shared
+            .listeners
+            .iter()
+            .map(|listener| {
+                let endpoint = Endpoint::new(listener, path, &self.shared.config);
+                let scheme = if listener.is_tls() { "https" } else { "http" };
+                format!(
+                    "scheme={scheme} address={} path={} tls={} auth={}",
+                    listener.local_addr(),
+                    endpoint.path.unwrap_or(path),
+                    listener.is_tls(),
+                    endpoint.auth.map_or("none", |auth| auth.scheme()),
+                )
+            })
+            .collect();
+        format!("metrics server started: {}", listeners.join("; "))
+    }
+
+    // Requests the metrics path from every listener, failing if any can't be reached or
+    // responds with a server error.
+    fn self_check(&self, path: &str) -> Result<(), ServerError> {
+        for listener in &self.shared.listeners {
+            let addr = listener.local_addr();
+            match listener.self_check(listener.path().unwrap_or(path)) {
+                // Listeners requiring client certificates can only be checked up to the handshake.
+                Ok(None) => {}
+                Ok(Some(res)) if res.status < 500 => {}
+                Ok(Some(res)) => {
+                    let err = format!("{addr} responded with status {}", res.status);
+                    return Err(ServerError::SelfCheck(err));
+                }
+                Err(e) => return Err(ServerError::SelfCheck(format!("{addr}: {e}"))),
+            }
+        }
+        Ok(())
+    }
+
+    /// Start serving requests to the /metrics URL path on the current tokio runtime.
+    ///
+    /// Unlike `serve`, no threads are spawned: connections are handled by tasks on the runtime
+    /// until the returned future is dropped, so it's typically spawned alongside the rest of an
+    /// async application. Requires the `tokio` feature.
+    ///
+    /// Listeners can't be served both synchronously and asynchronously, and TLS listeners
+    /// aren't supported, returning an error. Responses are buffered before they are sent, so
+    /// payloads served from files are read into memory.
+    #[cfg(feature = "tokio")]
+    pub async fn serve_async(&self) -> Result<(), ServerError> {
+        self.serve_uri_async(MetricsPath::default()).await
+    }
+
+    /// Start serving requests to a specific URL path on the current tokio runtime.
+    ///
+    /// See `serve_async`.
+    #[cfg(feature = "tokio")]
+    pub async fn serve_uri_async<P>(&self, path: P) -> Result<(), ServerError>
+    where
+        P: Into<MetricsPath>,
+    {
+        let path = path.into().into_string();
+        crate::asynchronous::serve(Arc::clone(&self.shared), path).await
+    }
+
+    /// Stop serving requests and free thread resources.
+    ///
+    /// Responses already being written are completed before returning, however long that
+    /// takes. See `stop_graceful` to bound the wait.
+    ///
+    /// The listeners stay bound, so the server can be restarted by calling `serve` again.
+    pub fn stop(&mut self) -> Result<(), ServerError> {
+        self.signal_stop();
+        self.join()
+    }
+
+    /// Stop accepting new connections, waiting up to `timeout` for in-flight responses to
+    /// complete before freeing thread resources.
+    ///
+    /// Connections still being handled once the timeout elapses are shut down, so slow or
+    /// idle clients can't delay stopping the server.
+    pub fn stop_graceful(&mut self, timeout: Duration) -> Result<(), ServerError> {
+        self.signal_stop();
+
+        // The deadline bounds real waiting, so doesn't use the configured clock, which may never
+        // advance.
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline && self.threads.iter().any(|t| !t.is_finished()) {
+            thread::sleep(ACCEPT_POLL_INTERVAL);
+        }
+
+        // Abort any remaining connections, failing their pending reads and writes.
+        let in_flight = self.shared.in_flight.lock().unwrap();
+        for socket in in_flight.values() {
+            let _ = socket.shutdown(Shutdown::Both);
+        }
+        drop(in_flight);
+
+        self.join()
+    }
+
+    // Signals that we should stop handling requests and unblocks every worker.
+    fn signal_stop(&self) {
+        self.shared.stop.store(true, Ordering::Relaxed);
+        if !self.threads.is_empty() {
+            // Only a single thread accepts connections when they're limited.
+            let accepting = match self.shared.config.max_connections {
+                Some(_) => 1,
+                None => self.shared.config.workers,
+            };
+            for listener in &self.shared.listeners {
+                for _ in 0..accepting {
+                    listener.unblock();
+                }
+            }
+        }
+    }
+
+    // Waits for every worker thread to finish.
+    fn join(&mut self) -> Result<(), ServerError> {
+        // Because join takes ownership of the threads, we need to drain them out of
+        // the Vec, leaving it empty. Every thread is joined, even if one of them panicked.
+        let mut result = Ok(());
+        for thread in self.threads.drain(..) {
+            if let Err(e) = thread.join() {
+                let err = match e.downcast_ref::<String>() {
+                    Some(s) => s,
+                    None => "unknown",
+                };
+
+                result = result.and(Err(ServerError::Stop(err.to_string())));
+            }
+        }
+
+        // All workers have exited, so the server can be served again.
+        self.shared.stop.store(false, Ordering::Relaxed);
+        if self.path.take().is_some() {
+            self.shared
+                .audit(AuditKind::Stop, || "stopped serving".to_string());
+        }
+        result
+    }
 }
 
-// Responds to a given request and logs in an Apache-like format.
-fn respond<D>(req: tiny_http::Request, res: tiny_http::Response<D>)
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        // Threads have already been joined if the server was stopped explicitly.
+        if self.threads.is_empty() {
+            return;
+        }
+
+        self.signal_stop();
+        if let Err(e) = self.join() {
+            error!("{e}");
+        }
+    }
+}
+
+// Computes the checksum header value of a metrics payload.
+fn checksum(data: &[u8]) -> HeaderValue {
+    let hash = xxhash_rust::xxh3::xxh3_64(data);
+    HeaderValue::try_from(format!("xxh3={hash:016x}")).expect("checksum is a valid header value")
+}
+
+// The effective settings for requests received on a single listener.
+pub(crate) struct Endpoint<'a> {
+    // The normalized path metrics are served at, or None to serve every path.
+    path: Option<&'a str>,
+    auth: Option<&'a dyn Authenticator>,
+}
+
+impl<'a> Endpoint<'a> {
+    // Settings for requests to a `MetricsService`, which the application mounts at a path of
+    // its choosing.
+    #[cfg(feature = "tower")]
+    pub(crate) fn service(config: &'a Config) -> Self {
+        Endpoint {
+            path: None,
+            auth: config.auth.as_deref(),
+        }
+    }
+
+    // Listener settings take precedence over server-wide settings.
+    pub(crate) fn new(listener: &'a Listener, path: &'a str, config: &'a Config) -> Self {
+        Endpoint {
+            path: Some(listener.path().unwrap_or(path)),
+            auth: listener.auth().unwrap_or(config.auth.as_deref()),
+        }
+    }
+}
+
+// Counts a worker thread as running until dropped, even if the worker panics.
+struct Running<'a>(&'a AtomicUsize);
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Registers a connection as in-flight until dropped, so it can be aborted when stopping.
+struct InFlight<'a> {
+    s: &'a SharedData,
+    id: u64,
+}
+
+impl<'a> InFlight<'a> {
+    fn track(s: &'a SharedData, conn: &Connection) -> Self {
+        let id = s.next_id.fetch_add(1, Ordering::Relaxed);
+        if let Ok(socket) = conn.socket.try_clone() {
+            let mut in_flight = s.in_flight.lock().unwrap();
+            in_flight.insert(id, socket);
+        }
+        InFlight { s, id }
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        let mut in_flight = self.s.in_flight.lock().unwrap();
+        in_flight.remove(&self.id);
+    }
+}
+
+// Accepts connections to the listener until the server is stopped, passing each to `conn`.
+fn accept<F>(s: &SharedData, listener: &Listener, mut conn: F)
 where
-    D: std::io::Read,
+    F: FnMut(Connection),
 {
-    let datetime = OffsetDateTime::now_utc()
+    loop {
+        // Blocks until the next connection is received.
+        let next = match listener.accept() {
+            Ok(next) => next,
+            Err(e) => {
+                error!("error accepting connection: {e}");
+                thread::sleep(ACCEPT_ERROR_DELAY);
+                None
+            }
+        };
+
+        // Check to see if we should stop handling requests.
+        if s.stop.load(Ordering::Relaxed) {
+            debug!("metrics server stopping");
+            return;
+        }
+
+        match next {
+            Some(next) => conn(next),
+            None if listener.is_nonblocking() => thread::sleep(ACCEPT_POLL_INTERVAL),
+            None => {}
+        }
+    }
+}
+
+// Counts a connection as open until dropped.
+pub(crate) struct ConnectionPermit(Arc<SharedData>);
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Sends a response without reading a request, e.g. one that couldn't be parsed, and closes
+// the connection.
+fn send_early(s: &SharedData, stream: &mut Box<dyn Stream>, res: Response) {
+    s.responded(res.status_code());
+    if let Err(e) = res
+        .write_to(http::Version::HTTP_11, stream, &s.config.write)
+        .and_then(|_| stream.close())
+    {
+        error!("error sending metrics response: {e}");
+    }
+}
+
+// Reads a single request from the connection and writes the response.
+fn handle(s: &SharedData, endpoint: &Endpoint, conn: Connection) {
+    let mut reader = BufReader::new(conn.stream);
+    let req = match Request::read(&mut reader, conn.remote_addr, &s.config.limits) {
+        Ok(req) => req,
+        Err(status) => {
+            // Only respond if the client is still there to read it.
+            if let Some(status) = status {
+                send_early(s, reader.get_mut(), Response::empty(status));
+            }
+            return;
+        }
+    };
+
+    // Handle expectations before reading any request body.
+    if let Some(expect) = req.expect() {
+        if !expect_continue(s, expect) {
+            let res = Response::empty(StatusCode::EXPECTATION_FAILED);
+            respond(s, reader.get_mut(), req, res);
+            return;
+        }
+
+        if let Err(e) = response::write_continue(reader.get_mut()) {
+            error!("error sending continue response: {e}");
+            return;
+        }
+    }
+    if let Err(e) = req.discard_body(&mut reader) {
+        error!("error reading request body: {e}");
+        return;
+    }
+
+    let res = route(s, endpoint, &req);
+    respond(s, reader.get_mut(), req, res);
+}
+
+// Returns whether a request with the given expectation should be told to continue.
+pub(crate) fn expect_continue(s: &SharedData, expect: &HeaderValue) -> bool {
+    expect.as_bytes().eq_ignore_ascii_case(b"100-continue") && s.config.expect_continue
+}
+
+// Builds the response to a request whose body has been read.
+pub(crate) fn route(s: &SharedData, endpoint: &Endpoint, req: &Request) -> Response {
+    // Only serve peers permitted by the configured address ranges, before they can use up the
+    // rate limit.
+    if !s.config.access.permits(req.remote_addr().map(|a| a.ip())) {
+        return Response::empty(StatusCode::FORBIDDEN);
+    }
+
+    // Shed load before doing any other work.
+    if let Some(res) = s.shed() {
+        return res;
+    }
+
+    // Refuse HTTP/1.0 clients, if configured.
+    if s.config.refuse_http10 && req.http_version() == Version::HTTP_10 {
+        return Response::empty(StatusCode::HTTP_VERSION_NOT_SUPPORTED);
+    }
+
+    // Only serve the specified URI path, or the audit log and variables if enabled.
+    let path = normalize_path(req.path(), CaseFolding::Preserve);
+    let matches = |target: &str, path: &str| match s.config.path_case {
+        CaseFolding::Preserve => target == path,
+        CaseFolding::Lowercase => target.eq_ignore_ascii_case(path),
+    };
+    let audit = s.config.audit_endpoint && matches(AUDIT_PATH, &path);
+    let vars = s.config.debug_vars && matches(VARS_PATH, &path);
+    let json = s.config.json
+        && endpoint
+            .path
+            .zip(path.strip_suffix(".json"))
+            .is_some_and(|(p, path)| matches(p, path));
+    if endpoint.path.is_some_and(|p| !matches(p, &path)) && !audit && !vars && !json {
+        return Response::empty(StatusCode::NOT_FOUND);
+    }
+
+    // Only serve authenticated clients, if required.
+    let decision = endpoint
+        .auth
+        .map(|a| a.authenticate(&RequestMeta::new(req, s.config.clock.now())));
+    let res = match decision {
+        None | Some(AuthDecision::Allow) => None,
+        Some(AuthDecision::Unauthorized(challenges)) => Some(
+            challenges
+                .into_iter()
+                .fold(Response::empty(StatusCode::UNAUTHORIZED), |res, c| {
+                    res.append_header(WWW_AUTHENTICATE, c)
+                }),
+        ),
+        Some(AuthDecision::Forbidden) => Some(Response::empty(StatusCode::FORBIDDEN)),
+    };
+    if let Some(res) = res {
+        s.audit(AuditKind::AuthFailure, || {
+            let remote_addr = req
+                .remote_addr()
+                .map_or("-".to_string(), |v| addr::display(&v));
+            let status = res.status_code().as_u16();
+            format!("{status} {} {} from {remote_addr}", req.method(), req.url())
+        });
+        return res;
+    }
+
+    // Only respond to GET and HEAD requests.
+    let head = req.method() == Method::HEAD;
+    if req.method() != Method::GET && !head {
+        return Response::empty(StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    let res = match &s.config.audit {
+        Some(log) if audit => Response::from_data(log.to_text()),
+        _ if vars => Response::from_data(s.vars.to_json())
+            .with_header(CONTENT_TYPE, HeaderValue::from_static("application/json")),
+        _ => {
+            // Health checks sending HEAD requests aren't scrapes.
+            if !head {
+                s.scraped();
+            }
+            if json || (s.config.json && prefers_json(req)) {
+                metrics_json(s, req)
+            } else {
+                metrics(s, req)
+            }
+        }
+    };
+
+    // Respond to HEAD requests with the same headers as GET, but no body.
+    if head {
+        res.without_body()
+    } else {
+        res
+    }
+}
+
+// Builds a response telling the client the server is overloaded, and to retry after the given
+// duration, rounded up to whole seconds.
+pub(crate) fn overloaded(wait: Duration) -> Response {
+    let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+    Response::empty(StatusCode::SERVICE_UNAVAILABLE).with_header(RETRY_AFTER, secs.max(1).into())
+}
+
+// Builds the response sent to connections over the connection limit.
+pub(crate) fn rejected() -> Response {
+    overloaded(CONNECTION_RETRY_AFTER)
+}
+
+// Builds the response containing the metrics requested by an authorized client.
+fn metrics(s: &SharedData, req: &Request) -> Response {
+    let gzip = accepts_gzip(req);
+    let page = req.query_param("page");
+
+    // Serve a variant in another format, if published and preferred by the client.
+    let (variant, negotiated) = negotiate(s, req);
+    let res = match variant {
+        Some((payload, content_type)) => payload
+            .response(page, gzip)
+            .with_header(CONTENT_TYPE, HeaderValue::from_static(content_type)),
+        None => {
+            // Serve only the requested sections, if any.
+            let collect: Vec<&str> = COLLECT_PARAMS
+                .iter()
+                .flat_map(|param| req.query_params(param))
+                .collect();
+            let res = if collect.is_empty() {
+                None
+            } else {
+                s.collect(&collect).map(|res| compress(res, gzip))
+            };
+
+            // Write the metrics, or the requested page, to the response buffer.
+            let res = res.unwrap_or_else(|| s.data.load_full().response(page, gzip));
+            res.with_header(CONTENT_TYPE, s.config.content_type.clone())
+        }
+    };
+
+    // Caches must keep the variants served to different clients apart.
+    let res = if negotiated || s.config.json {
+        res.append_header(VARY, HeaderValue::from_static("accept"))
+    } else {
+        res
+    };
+    #[cfg(feature = "gzip")]
+    let res = res.append_header(VARY, HeaderValue::from_static("accept-encoding"));
+    res
+}
+
+// Builds the response containing the metrics rendered as JSON.
+fn metrics_json(s: &SharedData, req: &Request) -> Response {
+    let res = match s.data.load().contents() {
+        Ok(data) => {
+            let res = Response::from_data(json::render(&data))
+                .with_header(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+            compress(res, accepts_gzip(req))
+        }
+        Err(e) => {
+            error!("error reading metrics: {e}");
+            Response::empty(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    };
+
+    let res = res.append_header(VARY, HeaderValue::from_static("accept"));
+    #[cfg(feature = "gzip")]
+    let res = res.append_header(VARY, HeaderValue::from_static("accept-encoding"));
+    res
+}
+
+// Returns true if the client prefers JSON to the Prometheus text format.
+fn prefers_json(req: &Request) -> bool {
+    let quality = |media_type| req.accept_quality(media_type).unwrap_or(0.0);
+    quality("application/json") > quality("text/plain")
+}
+
+// Selects the published variant, and its content type, that the client prefers to the text
+// format, if any. Also returns whether any variants are published.
+fn negotiate(s: &SharedData, req: &Request) -> (Option<(Arc<Payload>, &'static str)>, bool) {
+    let variants = [
+        (
+            s.openmetrics.load_full(),
+            "application/openmetrics-text",
+            OPENMETRICS_CONTENT_TYPE,
+        ),
+        #[cfg(feature = "protobuf")]
+        (
+            s.protobuf.load_full(),
+            PROTOBUF_MEDIA_TYPE,
+            PROTOBUF_CONTENT_TYPE,
+        ),
+    ];
+
+    let quality = |media_type| req.accept_quality(media_type).unwrap_or(0.0);
+    let mut best = quality("text/plain");
+    let (mut selected, mut negotiated) = (None, false);
+    for (payload, media_type, content_type) in variants {
+        let Some(payload) = payload else { continue };
+        negotiated = true;
+        let q = quality(media_type);
+        if q > best {
+            best = q;
+            selected = Some((payload, content_type));
+        }
+    }
+    (selected, negotiated)
+}
+
+// Removes a trailing `# EOF` line from an OpenMetrics exposition.
+fn strip_eof(mut data: Vec<u8>) -> Vec<u8> {
+    let end = data
+        .iter()
+        .rposition(|b| !b.is_ascii_whitespace())
+        .map_or(0, |i| i + 1);
+    let trimmed = &data[..end];
+    if trimmed == b"# EOF" || trimmed.ends_with(b"\n# EOF") {
+        data.truncate(end - b"# EOF".len());
+    }
+    data
+}
+
+// Returns true if the client accepts gzip responses and compression is enabled.
+#[cfg_attr(not(feature = "gzip"), allow(unused_variables))]
+fn accepts_gzip(req: &Request) -> bool {
+    #[cfg(feature = "gzip")]
+    return req.accepts_encoding("gzip");
+    #[cfg(not(feature = "gzip"))]
+    false
+}
+
+// Compresses a response built for a single request, if the client accepts gzip.
+#[cfg_attr(not(feature = "gzip"), allow(unused_variables))]
+fn compress(res: Response, gzip: bool) -> Response {
+    #[cfg(feature = "gzip")]
+    if gzip {
+        return res.gzip().unwrap_or_else(|e| {
+            error!("error compressing metrics response: {e}");
+            Response::empty(StatusCode::INTERNAL_SERVER_ERROR)
+        });
+    }
+    res
+}
+
+impl Payload {
+    // Returns the whole payload, reading it from the file served in place of the data if any.
+    fn contents(&self) -> io::Result<Cow<'_, [u8]>> {
+        match &self.file {
+            Some(path) => fs::read(path).map(Cow::Owned),
+            None => Ok(Cow::Borrowed(&self.data)),
+        }
+    }
+
+    // Builds the response for the whole payload, or a single page if requested, compressed
+    // with gzip if accepted.
+    //
+    // The response shares the payload rather than copying its data.
+    fn response(self: Arc<Self>, page: Option<&str>, gzip: bool) -> Response {
+        if let Some(path) = &self.file {
+            let file = File::open(path).and_then(|f| Ok((f.metadata()?, f)));
+            return match file {
+                Ok((metadata, file)) => {
+                    let res = Response::from_file(file, metadata.len());
+                    with_last_modified(res, metadata.modified().ok())
+                }
+                Err(e) => {
+                    error!("error opening metrics file {}: {e}", path.display());
+                    Response::empty(StatusCode::INTERNAL_SERVER_ERROR)
+                }
+            };
+        }
+
+        // Pagination is opt-in, so requests for pages are otherwise served the whole payload.
+        if self.pages.is_empty() {
+            return self.range_response(0, gzip);
+        }
+
+        let count = self.pages.len();
+        let res = match page {
+            None => self.range_response(0, gzip),
+            Some("index") => {
+                let pages: Vec<_> = self.pages.iter().map(|(p, _)| p.clone()).collect();
+                Response::from_data(page::index(&pages))
+            }
+            Some(n) => match n.parse::<usize>() {
+                Ok(n) if n >= 1 && n <= count => self.range_response(n, gzip),
+                Ok(_) => Response::empty(StatusCode::NOT_FOUND),
+                Err(_) => Response::empty(StatusCode::BAD_REQUEST),
+            },
+        };
+        res.with_header(PAGE_COUNT_HEADER, count.into())
+    }
+
+    // Builds a response containing the whole payload, or the given page if not zero, with a
+    // checksum header if enabled.
+    #[cfg_attr(not(feature = "gzip"), allow(unused_variables))]
+    fn range_response(self: Arc<Self>, page: usize, gzip: bool) -> Response {
+        let updated = self.updated;
+        let (range, checksum) = match page {
+            0 => (0..self.data.len(), self.checksum.clone()),
+            n => {
+                let (page, checksum) = &self.pages[n - 1];
+                (page.range.clone(), checksum.clone())
+            }
+        };
+
+        #[cfg(feature = "gzip")]
+        let res = if gzip {
+            self.compressed_response(page, range)
+        } else {
+            Response::from_shared(self, range)
+        };
+        #[cfg(not(feature = "gzip"))]
+        let res = Response::from_shared(self, range);
+
+        let res = with_last_modified(res, updated);
+        match checksum {
+            Some(checksum) => res.with_header(CHECKSUM_HEADER, checksum),
+            None => res,
+        }
+    }
+
+    // Builds a gzip response for a range of the payload, compressing it on the first request
+    // and sharing the compressed data with every later one.
+    #[cfg(feature = "gzip")]
+    fn compressed_response(
+        self: Arc<Self>,
+        page: usize,
+        range: std::ops::Range<usize>,
+    ) -> Response {
+        use http::header::CONTENT_ENCODING;
+
+        if self.compressed[page].get().is_none() {
+            match response::gzip(&self.data[range]) {
+                // Concurrent requests may compress the same data, but only one result is kept.
+                Ok(data) => {
+                    let _ = self.compressed[page].set(data);
+                }
+                Err(e) => {
+                    error!("error compressing metrics response: {e}");
+                    return Response::empty(StatusCode::INTERNAL_SERVER_ERROR);
+                }
+            }
+        }
+
+        let len = self.compressed[page].get().map_or(0, Vec::len);
+        Response::from_shared(Arc::new(Compressed(self, page)), 0..len)
+            .with_header(CONTENT_ENCODING, HeaderValue::from_static("gzip"))
+    }
+}
+
+impl AsRef<[u8]> for Payload {
+    fn as_ref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+// Adds a `Last-Modified` header, if the modification time is known.
+fn with_last_modified(res: Response, modified: Option<SystemTime>) -> Response {
+    match modified.and_then(http_date) {
+        Some(date) => res.with_header(LAST_MODIFIED, date),
+        None => res,
+    }
+}
+
+// Formats a time as an HTTP date, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+fn http_date(time: SystemTime) -> Option<HeaderValue> {
+    let t = OffsetDateTime::from(time);
+    let date = format!(
+        "{:.3}, {:02} {:.3} {:04} {:02}:{:02}:{:02} GMT",
+        t.weekday().to_string(),
+        t.day(),
+        t.month().to_string(),
+        t.year(),
+        t.hour(),
+        t.minute(),
+        t.second()
+    );
+    HeaderValue::try_from(date).ok()
+}
+
+// The cached gzip encoding of the whole payload, or one of its pages.
+#[cfg(feature = "gzip")]
+struct Compressed(Arc<Payload>, usize);
+
+#[cfg(feature = "gzip")]
+impl AsRef<[u8]> for Compressed {
+    fn as_ref(&self) -> &[u8] {
+        self.0.compressed[self.1].get().map_or(&[], Vec::as_slice)
+    }
+}
+
+// Responds to a given request and logs in an Apache-like format.
+fn respond(s: &SharedData, stream: &mut Box<dyn Stream>, req: Request, res: Response) {
+    log(s, &req, &res);
+    if let Err(e) = res
+        .write_to(req.http_version(), stream, &s.config.write)
+        .and_then(|_| stream.close())
+    {
+        error!("error sending metrics response: {e}");
+    };
+}
+
+// Logs a request and the status of its response in an Apache-like format, and records the
+// status and HTTP version in the self-metrics.
+pub(crate) fn log(s: &SharedData, req: &Request, res: &Response) {
+    s.responded(res.status_code());
+    s.requested(req.http_version());
+
+    let datetime = OffsetDateTime::from(s.config.clock.now())
         .format(&format_description::well_known::Rfc3339)
         .unwrap_or_else(|_| "-".to_string());
 
     debug!(
-        "{} [{}] \"{} {} HTTP/{}\" {}",
-        req.remote_addr().map_or("-".to_string(), |v| v.to_string()),
+        "{} [{}] \"{} {} {:?}\" {}",
+        req.remote_addr()
+            .map_or("-".to_string(), |v| addr::display(&v)),
         datetime,
         req.method(),
-        req.url(),
+        normalize_url(req.url()),
         req.http_version(),
-        res.status_code().0,
+        res.status_code().as_u16(),
     );
+}
 
-    if let Err(e) = req.respond(res) {
-        error!("error sending metrics response: {e}");
-    };
+// Normalizes the path of a request target, keeping any query string as it is.
+fn normalize_url(url: &str) -> Cow<'_, str> {
+    let (path, query) = url
+        .split_once('?')
+        .map_or((url, None), |(p, q)| (p, Some(q)));
+    match (normalize_path(path, CaseFolding::Preserve), query) {
+        (Cow::Borrowed(_), _) => Cow::Borrowed(url),
+        (Cow::Owned(path), None) => Cow::Owned(path),
+        (Cow::Owned(path), Some(query)) => Cow::Owned(format!("{path}?{query}")),
+    }
 }
 
 #[cfg(test)]
@@ -246,19 +1909,95 @@ mod tests {
     use super::*;
 
     #[test]
-    fn test_parse_path() {
-        let expected_default = DEFAULT_METRICS_PATH.to_string();
-        let expected_valid = "/debug/metrics".to_string();
-
-        // Invalid.
-        assert_eq!(parse_path("Hello, World!"), expected_default);
-        // Whitespace.
-        assert_eq!(parse_path(" metr ics  "), expected_default);
-        // Non-ASCII.
-        assert_eq!(parse_path("mëtrîcs"), expected_default);
-        // Valid.
-        assert_eq!(parse_path("/debug/metrics"), expected_valid);
-        assert_eq!(parse_path("debug/metrics"), expected_valid);
-        assert_eq!(parse_path("DEBUG/METRICS"), expected_valid);
+    fn test_checksum() {
+        assert_eq!(checksum(b""), "xxh3=2d06800538d394c2");
+        assert_eq!(
+            checksum(b"my_awesome_metric = 10"),
+            checksum(b"my_awesome_metric = 10")
+        );
+        assert_ne!(
+            checksum(b"my_awesome_metric = 10"),
+            checksum(b"my_awesome_metric = 11")
+        );
+    }
+
+    #[test]
+    fn test_banner() {
+        let server = MetricsServer::builder()
+            .address("127.0.0.1:0")
+            .auth(crate::BasicAuth::new("user", "secret"))
+            .listener(
+                crate::ListenerConfig::new("127.0.0.1:0")
+                    .without_auth()
+                    .path("/internal"),
+            )
+            .build()
+            .unwrap();
+        let addrs: Vec<SocketAddr> = server
+            .shared
+            .listeners
+            .iter()
+            .map(|l| l.local_addr())
+            .collect();
+
+        // Assert each listener's effective settings are described, without credentials.
+        let banner = server.banner("/metrics");
+        assert_eq!(
+            banner,
+            format!(
+                "metrics server started: \
+                scheme=http address={} path=/metrics tls=false auth=basic; \
+                scheme=http address={} path=/internal tls=false auth=none",
+                addrs[0], addrs[1]
+            )
+        );
+        assert!(!banner.contains("secret"));
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_compressed_cache() {
+        let config = Config {
+            page_size: Some(8),
+            ..Default::default()
+        };
+        let server = MetricsServer::from_parts(Vec::new(), config);
+        server.update(b"a 1\nb 2\nc 3\n".to_vec());
+
+        // Assert each page is compressed once, on the first request for it.
+        let payload = server.shared.data.load_full();
+        assert!(payload.compressed.iter().all(|c| c.get().is_none()));
+        Arc::clone(&payload).response(Some("2"), true);
+        assert!(payload.compressed[2].get().is_some());
+        let compressed = payload.compressed[2].get().unwrap().as_ptr();
+        Arc::clone(&payload).response(Some("2"), true);
+        assert_eq!(payload.compressed[2].get().unwrap().as_ptr(), compressed);
+        assert!(payload.compressed[0].get().is_none());
+
+        // Assert uncompressed requests don't populate the cache.
+        Arc::clone(&payload).response(None, false);
+        assert!(payload.compressed[0].get().is_none());
+    }
+
+    #[test]
+    fn test_strip_eof() {
+        assert_eq!(strip_eof(b"a 1\n# EOF\n".to_vec()), b"a 1\n");
+        assert_eq!(strip_eof(b"a 1\n# EOF".to_vec()), b"a 1\n");
+        assert_eq!(strip_eof(b"# EOF\n\n".to_vec()), b"");
+        assert_eq!(strip_eof(b"a 1\n".to_vec()), b"a 1\n");
+        assert_eq!(strip_eof(b"a 1 # EOF\n".to_vec()), b"a 1 # EOF\n");
+    }
+
+    #[test]
+    fn test_normalize_url() {
+        assert_eq!(normalize_url("/metrics?page=1"), "/metrics?page=1");
+        assert_eq!(normalize_url("//debug/../metrics"), "/metrics");
+        assert_eq!(normalize_url("/./metrics?a=/../b"), "/metrics?a=/../b");
+    }
+
+    #[test]
+    fn test_http_date() {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(784111777);
+        assert_eq!(http_date(time).unwrap(), "Sun, 06 Nov 1994 08:49:37 GMT");
     }
 }
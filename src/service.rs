@@ -0,0 +1,72 @@
+use std::convert::Infallible;
+use std::future::{ready, Ready};
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use http::StatusCode;
+use http_body_util::Full;
+use log::error;
+use tower_service::Service;
+
+use crate::request::Request;
+use crate::server::{self, Endpoint, MetricsHandle};
+
+/// A `tower::Service` serving metrics from an existing HTTP server, such as axum or hyper,
+/// without opening another port.
+///
+/// The service serves the metrics at whichever path it's mounted, applying the server-wide
+/// authentication and pagination settings. Create one with `MetricsServer::service`, to share
+/// a server's metrics, or `Builder::build_service`.
+///
+/// ```ignore
+/// let service = MetricsServer::builder().build_service()?;
+/// let handle = service.handle();
+/// let app = axum::Router::new().route_service("/metrics", service);
+/// ```
+#[derive(Clone)]
+pub struct MetricsService {
+    handle: MetricsHandle,
+}
+
+impl MetricsService {
+    pub(crate) fn new(handle: MetricsHandle) -> Self {
+        MetricsService { handle }
+    }
+
+    /// Returns a handle that can update the data served by this service.
+    pub fn handle(&self) -> MetricsHandle {
+        self.handle.clone()
+    }
+
+    // Builds the response to a request received by the application's server.
+    fn respond<B>(&self, req: &http::Request<B>) -> http::Response<Full<Bytes>> {
+        let s = &self.handle.shared;
+        let req = Request::from_http(req);
+        let res = server::route(s, &Endpoint::service(&s.config), &req);
+        server::log(s, &req, &res);
+
+        match res.into_http() {
+            Ok(res) => res.map(Full::new),
+            Err(e) => {
+                error!("error sending metrics response: {e}");
+                let mut res = http::Response::new(Full::default());
+                *res.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                res
+            }
+        }
+    }
+}
+
+impl<B> Service<http::Request<B>> for MetricsService {
+    type Response = http::Response<Full<Bytes>>;
+    type Error = Infallible;
+    type Future = Ready<Result<Self::Response, Infallible>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        ready(Ok(self.respond(&req)))
+    }
+}
@@ -0,0 +1,153 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+use http::{StatusCode, Version};
+
+// The metric family counting handled requests by status code.
+const REQUESTS_METRIC: &str = "metrics_server_http_requests_total";
+// The metric family counting requests rejected because the server was overloaded, by reason.
+const OVERLOADED_METRIC: &str = "metrics_server_overloaded_requests_total";
+// The metric family counting parsed requests by HTTP version.
+const VERSIONS_METRIC: &str = "metrics_server_http_requests_by_version_total";
+
+/// The limit that a request was rejected by.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Overload {
+    /// The rate limit set with `Builder::rate_limit`.
+    RateLimit,
+    /// The connection limit set with `Builder::max_connections`.
+    Connections,
+}
+
+impl Overload {
+    // Returns the value of the reason label.
+    fn reason(self) -> &'static str {
+        match self {
+            Overload::RateLimit => "rate_limit",
+            Overload::Connections => "connections",
+        }
+    }
+}
+
+/// Counts of the requests handled by the server, exposed as self-metrics.
+#[derive(Default)]
+pub(crate) struct RequestStats {
+    codes: Mutex<BTreeMap<u16, u64>>,
+    overloaded: Mutex<BTreeMap<Overload, u64>>,
+    versions: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+impl RequestStats {
+    /// Records a response sent with the given status.
+    pub(crate) fn record(&self, status: StatusCode) {
+        *self
+            .codes
+            .lock()
+            .unwrap()
+            .entry(status.as_u16())
+            .or_default() += 1;
+    }
+
+    /// Records a request rejected because the server was overloaded.
+    pub(crate) fn record_overload(&self, overload: Overload) {
+        *self.overloaded.lock().unwrap().entry(overload).or_default() += 1;
+    }
+
+    /// Records a request sent with the given HTTP version.
+    pub(crate) fn record_version(&self, version: Version) {
+        let version = match version {
+            Version::HTTP_09 => "0.9",
+            Version::HTTP_10 => "1.0",
+            Version::HTTP_11 => "1.1",
+            Version::HTTP_2 => "2",
+            Version::HTTP_3 => "3",
+            _ => "unknown",
+        };
+        *self.versions.lock().unwrap().entry(version).or_default() += 1;
+    }
+
+    /// Appends the request counts to the payload in the text exposition format.
+    pub(crate) fn append_metrics(&self, mut data: Vec<u8>) -> Vec<u8> {
+        let mut out = format!(
+            "# HELP {REQUESTS_METRIC} The number of HTTP requests handled, by response status code.\n\
+            # TYPE {REQUESTS_METRIC} counter\n"
+        );
+        for (code, count) in self.codes.lock().unwrap().iter() {
+            let _ = writeln!(out, "{REQUESTS_METRIC}{{code=\"{code}\"}} {count}");
+        }
+        let overloaded = self.overloaded.lock().unwrap();
+        if !overloaded.is_empty() {
+            let _ = write!(
+                out,
+                "# HELP {OVERLOADED_METRIC} The number of HTTP requests rejected because the server was overloaded, by limit.\n\
+                # TYPE {OVERLOADED_METRIC} counter\n"
+            );
+            for (overload, count) in overloaded.iter() {
+                let reason = overload.reason();
+                let _ = writeln!(out, "{OVERLOADED_METRIC}{{reason=\"{reason}\"}} {count}");
+            }
+        }
+        let versions = self.versions.lock().unwrap();
+        if !versions.is_empty() {
+            let _ = write!(
+                out,
+                "# HELP {VERSIONS_METRIC} The number of HTTP requests received, by protocol version.\n\
+                # TYPE {VERSIONS_METRIC} counter\n"
+            );
+            for (version, count) in versions.iter() {
+                let _ = writeln!(out, "{VERSIONS_METRIC}{{version=\"{version}\"}} {count}");
+            }
+        }
+
+        if !data.is_empty() && !data.ends_with(b"\n") {
+            data.push(b'\n');
+        }
+        data.extend_from_slice(out.as_bytes());
+        data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_stats() {
+        let stats = RequestStats::default();
+        stats.record(StatusCode::OK);
+        stats.record(StatusCode::NOT_FOUND);
+        stats.record(StatusCode::OK);
+
+        let out = String::from_utf8(stats.append_metrics(b"a 1".to_vec())).unwrap();
+        assert_eq!(
+            out,
+            "a 1\n\
+            # HELP metrics_server_http_requests_total The number of HTTP requests handled, by response status code.\n\
+            # TYPE metrics_server_http_requests_total counter\n\
+            metrics_server_http_requests_total{code=\"200\"} 2\n\
+            metrics_server_http_requests_total{code=\"404\"} 1\n"
+        );
+
+        // Assert overloaded requests are only reported once any are rejected.
+        stats.record_overload(Overload::RateLimit);
+        let out = String::from_utf8(stats.append_metrics(Vec::new())).unwrap();
+        assert!(out.ends_with(
+            "# HELP metrics_server_overloaded_requests_total The number of HTTP requests rejected because the server was overloaded, by limit.\n\
+            # TYPE metrics_server_overloaded_requests_total counter\n\
+            metrics_server_overloaded_requests_total{reason=\"rate_limit\"} 1\n"
+        ));
+
+        // Assert requests are counted by HTTP version.
+        stats.record_version(Version::HTTP_11);
+        stats.record_version(Version::HTTP_10);
+        stats.record_version(Version::HTTP_11);
+        let out = String::from_utf8(stats.append_metrics(Vec::new())).unwrap();
+        assert!(out.ends_with(
+            "# HELP metrics_server_http_requests_by_version_total The number of HTTP requests received, by protocol version.\n\
+            # TYPE metrics_server_http_requests_by_version_total counter\n\
+            metrics_server_http_requests_by_version_total{version=\"1.0\"} 1\n\
+            metrics_server_http_requests_by_version_total{version=\"1.1\"} 2\n"
+        ));
+    }
+}
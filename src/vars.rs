@@ -0,0 +1,146 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+use crate::exposition;
+use crate::json::write_string;
+
+/// The path the variables are served at, if enabled with `Builder::debug_vars`.
+pub(crate) const VARS_PATH: &str = "/debug/vars";
+
+/// A value published at `/debug/vars` with `MetricsServer::set_var`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Var {
+    /// An integer, such as a count.
+    Int(i64),
+    /// A floating point number. Values JSON can't represent, such as `NaN`, are served as strings.
+    Float(f64),
+    /// A boolean flag.
+    Bool(bool),
+    /// A string, such as a version.
+    String(String),
+}
+
+impl From<i64> for Var {
+    fn from(value: i64) -> Self {
+        Var::Int(value)
+    }
+}
+
+impl From<i32> for Var {
+    fn from(value: i32) -> Self {
+        Var::Int(value.into())
+    }
+}
+
+impl From<u32> for Var {
+    fn from(value: u32) -> Self {
+        Var::Int(value.into())
+    }
+}
+
+impl From<f64> for Var {
+    fn from(value: f64) -> Self {
+        Var::Float(value)
+    }
+}
+
+impl From<bool> for Var {
+    fn from(value: bool) -> Self {
+        Var::Bool(value)
+    }
+}
+
+impl From<&str> for Var {
+    fn from(value: &str) -> Self {
+        Var::String(value.to_string())
+    }
+}
+
+impl From<String> for Var {
+    fn from(value: String) -> Self {
+        Var::String(value)
+    }
+}
+
+/// The variables set by the application, keyed by name.
+#[derive(Default)]
+pub(crate) struct Vars {
+    vars: Mutex<BTreeMap<String, Var>>,
+}
+
+impl Vars {
+    /// Sets a variable, replacing any previous value.
+    pub(crate) fn set(&self, name: &str, value: Var) {
+        self.vars.lock().unwrap().insert(name.to_string(), value);
+    }
+
+    /// Renders the variables as a JSON object, in the same shape as Go's `expvar` package.
+    ///
+    /// Like `expvar`, the process's command line is included as `cmdline`, unless the
+    /// application sets a variable with that name. Keys are sorted.
+    pub(crate) fn to_json(&self) -> Vec<u8> {
+        let vars = self.vars.lock().unwrap();
+
+        let mut out = String::from("{");
+        if !vars.contains_key("cmdline") {
+            out.push_str("\"cmdline\":[");
+            for (i, arg) in std::env::args_os().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_string(&arg.to_string_lossy(), &mut out);
+            }
+            out.push(']');
+        }
+        for (name, value) in vars.iter() {
+            if out.len() > 1 {
+                out.push(',');
+            }
+            write_string(name, &mut out);
+            out.push(':');
+            match value {
+                Var::Int(v) => {
+                    let _ = write!(out, "{v}");
+                }
+                Var::Float(v) if v.is_finite() => {
+                    let _ = write!(out, "{v}");
+                }
+                Var::Float(v) => write_string(&exposition::format_number(*v), &mut out),
+                Var::Bool(v) => {
+                    let _ = write!(out, "{v}");
+                }
+                Var::String(v) => write_string(v, &mut out),
+            }
+        }
+        out.push('}');
+        out.into_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_json() {
+        let vars = Vars::default();
+        vars.set("cmdline", "server".into());
+        vars.set("version", "1.0 \"beta\"".into());
+        vars.set("workers", 4.into());
+        vars.set("load", 0.5.into());
+        vars.set("ratio", f64::NAN.into());
+        vars.set("ready", true.into());
+        vars.set("workers", 8.into());
+
+        assert_eq!(
+            String::from_utf8(vars.to_json()).unwrap(),
+            "{\"cmdline\":\"server\",\"load\":0.5,\"ratio\":\"NaN\",\"ready\":true,\
+            \"version\":\"1.0 \\\"beta\\\"\",\"workers\":8}"
+        );
+
+        // Assert the command line is included by default.
+        let json = String::from_utf8(Vars::default().to_json()).unwrap();
+        assert!(json.starts_with("{\"cmdline\":[\""), "{json}");
+    }
+}
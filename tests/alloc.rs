@@ -0,0 +1,57 @@
+//! Asserts that writing a response allocates nothing once the buffer pool is warm.
+//!
+//! This runs as its own binary without the test harness, so the counting allocator doesn't
+//! apply to any other tests. The response module is compiled in directly, as it isn't public.
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use http::Version;
+
+// Only part of the module is used here, and its unit tests aren't compiled without the harness.
+#[allow(dead_code, unused_imports)]
+#[path = "../src/response.rs"]
+mod response;
+
+use response::{Response, SharedBody, WriteConfig};
+
+// Counts every allocation made by the process.
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOC: CountingAlloc = CountingAlloc;
+
+fn main() {
+    let config = WriteConfig::default();
+    let data: SharedBody = Arc::new(vec![b'a'; 1024]);
+    let mut out = Vec::with_capacity(64 * 1024);
+
+    // The first response fills the pool.
+    Response::from_shared(Arc::clone(&data), 0..1024)
+        .write_to(Version::HTTP_11, &mut out, &config)
+        .unwrap();
+
+    // Assert subsequent responses are assembled without allocating.
+    out.clear();
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    Response::from_shared(Arc::clone(&data), 0..1024)
+        .write_to(Version::HTTP_11, &mut out, &config)
+        .unwrap();
+    assert_eq!(ALLOCATIONS.load(Ordering::Relaxed) - before, 0);
+    assert!(out.ends_with(&[b'a'; 1024]));
+
+    println!("test_write_allocations ... ok");
+}
@@ -0,0 +1,21 @@
+use metrics_server::{global, MetricsServer, ServerError};
+
+#[test]
+fn test_global_server() {
+    // Assert updates are dropped before the global server is set.
+    assert!(global::get().is_none());
+    assert_eq!(None, global::update(vec![1]));
+
+    global::init("127.0.0.1:0").unwrap();
+    let addr = global::get().unwrap().local_addr();
+
+    // Assert the global server can only be set once.
+    let server = MetricsServer::new("127.0.0.1:0", None, None).unwrap();
+    assert!(matches!(global::set(server), Err(ServerError::Create(_))));
+
+    // Assert calls to /metrics return the globally published data.
+    assert_eq!(Some(3), global::update(vec![1, 2, 3]));
+    let res = reqwest::blocking::get(format!("http://{addr}/metrics")).unwrap();
+    assert_eq!(200, res.status());
+    assert_eq!(vec![1, 2, 3], res.bytes().unwrap());
+}
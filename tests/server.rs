@@ -1,4 +1,12 @@
-use metrics_server::{MetricsServer, ServerError};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+
+#[cfg(feature = "tls")]
+use metrics_server::TlsVersion;
+use metrics_server::{
+    AuditKind, AuthDecision, Authenticator, BasicAuth, BearerAuth, DigestAuth, ListenerConfig,
+    MetricsPath, MetricsServer, MockClock, Redaction, RequestMeta, Rule, ServerError, Var,
+};
 
 #[test]
 fn test_new_server_invalid_address() {
@@ -9,7 +17,7 @@ fn test_new_server_invalid_address() {
 
 #[test]
 fn test_new_http_server() {
-    let server = MetricsServer::new("localhost:8001", None, None);
+    let server = MetricsServer::new("localhost:0", None, None);
     assert!(server.is_ok());
 }
 
@@ -24,7 +32,7 @@ invaid certificate
         .to_vec();
     let key = include_bytes!("./certs/private_key.pem").to_vec();
 
-    let server = MetricsServer::new("localhost:8441", Some(cert), Some(key));
+    let server = MetricsServer::new("127.0.0.1:0", Some(cert), Some(key));
     assert!(server.is_err());
     assert!(matches!(server, Err(ServerError::Create(_))));
 }
@@ -39,14 +47,14 @@ fn test_new_server_invalid_private_key() {
         .as_bytes()
         .to_vec();
 
-    let server = MetricsServer::new("localhost:8442", Some(cert), Some(key));
+    let server = MetricsServer::new("127.0.0.1:0", Some(cert), Some(key));
     assert!(server.is_err());
     assert!(matches!(server, Err(ServerError::Create(_))));
 }
 
 #[test]
 fn test_new_server_already_running() {
-    let mut server = MetricsServer::new("localhost:8002", None, None).unwrap();
+    let mut server = MetricsServer::new("localhost:0", None, None).unwrap();
 
     // Attempt to start an already running server should be ok
     // as we will return the pre-existing thread.
@@ -63,7 +71,7 @@ fn test_new_https_server() {
     let cert = include_bytes!("./certs/certificate.pem").to_vec();
     let key = include_bytes!("./certs/private_key.pem").to_vec();
 
-    let server = MetricsServer::new("localhost:8443", Some(cert), Some(key));
+    let server = MetricsServer::new("localhost:0", Some(cert), Some(key));
     assert!(server.is_ok());
 }
 
@@ -73,26 +81,42 @@ fn test_http_server_invalid_address() {
     let _ = MetricsServer::http("invalid:99999999");
 }
 
+#[test]
+fn test_try_http_server() {
+    assert!(matches!(
+        MetricsServer::try_http("invalid:99999999"),
+        Err(ServerError::Create(_))
+    ));
+
+    let mut server = MetricsServer::try_http("127.0.0.1:0").unwrap();
+    let addr = server.local_addr();
+    let res = reqwest::blocking::get(format!("http://{addr}/metrics")).unwrap();
+    assert_eq!(200, res.status());
+
+    // Stop the server.
+    server.stop().unwrap();
+}
+
 #[test]
 fn test_http_server_serve() {
-    let mut server = MetricsServer::new("localhost:8001", None, None).unwrap();
-    server.serve();
+    let mut server = MetricsServer::spawn_for_tests();
+    let url = format!("http://{}", server.local_addr());
 
     // Assert calls to non /metrics endpoint returns 404.
-    let res = reqwest::blocking::get("http://localhost:8001/invalid").unwrap();
+    let res = reqwest::blocking::get(format!("{url}/invalid")).unwrap();
     assert_eq!(404, res.status());
 
     // Assert calls to URLs with correct prefix but additional characters returns 404.
-    let res = reqwest::blocking::get("http://localhost:8001/metricsssss").unwrap();
+    let res = reqwest::blocking::get(format!("{url}/metricsssss")).unwrap();
     assert_eq!(404, res.status());
 
     // Assert calls to uppercase URLs returns 404.
-    let res = reqwest::blocking::get("http://localhost:8001/METRICS").unwrap();
+    let res = reqwest::blocking::get(format!("{url}/METRICS")).unwrap();
     assert_eq!(404, res.status());
 
     // Assert non GET requests to /metrics endpoint returns 405.
     let client = reqwest::blocking::Client::new();
-    let res = client.post("http://localhost:8001/metrics").send().unwrap();
+    let res = client.post(format!("{url}/metrics")).send().unwrap();
     assert_eq!(405, res.status());
 
     // Assert calls to /metrics return correct response.
@@ -103,7 +127,7 @@ fn test_http_server_serve() {
         assert_eq!(1, b);
 
         // Make a HTTP request to the metrics server.
-        let mut res = reqwest::blocking::get("http://localhost:8001/metrics").unwrap();
+        let mut res = reqwest::blocking::get(format!("{url}/metrics")).unwrap();
         assert_eq!(200, res.status());
 
         // Read the response body and check it is what we expected.
@@ -118,15 +142,16 @@ fn test_http_server_serve() {
 
 #[test]
 fn test_http_server_serve_uri() {
-    let mut server = MetricsServer::new("localhost:8004", None, None).unwrap();
+    let mut server = MetricsServer::new("127.0.0.1:0", None, None).unwrap();
     server.serve_uri("/test".to_string());
+    let addr = server.local_addr();
 
     // Assert calls to non /metrics endpoint returns 404.
-    let res = reqwest::blocking::get("http://localhost:8004/metrics").unwrap();
+    let res = reqwest::blocking::get(format!("http://{addr}/metrics")).unwrap();
     assert_eq!(404, res.status());
 
     // Assert calls to /test returns 200.
-    let res = reqwest::blocking::get("http://localhost:8004/test").unwrap();
+    let res = reqwest::blocking::get(format!("http://{addr}/test")).unwrap();
     assert_eq!(200, res.status());
 
     // Stop the server.
@@ -152,7 +177,7 @@ fn test_https_server_invalid_certificate() {
     let cert = Vec::new();
     let key = include_bytes!("./certs/private_key.pem").to_vec();
 
-    _ = MetricsServer::https("localhost:8441", cert, key);
+    _ = MetricsServer::https("127.0.0.1:0", cert, key);
 }
 
 #[test]
@@ -163,7 +188,15 @@ fn test_https_server_invalid_private_key() {
     let cert = include_bytes!("./certs/certificate.pem").to_vec();
     let key = Vec::new();
 
-    _ = MetricsServer::https("localhost:8442", cert, key);
+    _ = MetricsServer::https("127.0.0.1:0", cert, key);
+}
+
+#[test]
+#[cfg(feature = "tls")]
+fn test_try_https_server_invalid_certificate() {
+    let key = include_bytes!("./certs/private_key.pem").to_vec();
+    let server = MetricsServer::try_https("127.0.0.1:0", Vec::new(), key);
+    assert!(matches!(server, Err(ServerError::Create(_))));
 }
 
 #[test]
@@ -173,8 +206,2449 @@ fn test_https_server_serve() {
     let cert = include_bytes!("./certs/certificate.pem").to_vec();
     let key = include_bytes!("./certs/private_key.pem").to_vec();
 
-    let server = MetricsServer::https("localhost:8443", cert, key);
+    let mut server = MetricsServer::https("localhost:0", cert, key);
+    let url = format!("https://{}/metrics", server.local_addr());
+
+    // Assert calls to /metrics over TLS return the correct response.
+    server.update(vec![1, 2, 3]);
+    let client = reqwest::blocking::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap();
+    let res = client.get(&url).send().unwrap();
+    assert_eq!(200, res.status());
+    assert_eq!(vec![1, 2, 3], res.bytes().unwrap());
+
+    // Stop the server.
+    server.stop().unwrap();
+}
+
+#[test]
+fn test_builder_socket_options() {
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .backlog(16)
+        .nonblocking(true)
+        .linger(Some(std::time::Duration::from_secs(1)))
+        .build()
+        .unwrap();
+    server.serve();
+    let addr = server.local_addr();
+
+    server.update(vec![1, 2, 3]);
+    let res = reqwest::blocking::get(format!("http://{addr}/metrics")).unwrap();
+    assert_eq!(200, res.status());
+    assert_eq!(vec![1, 2, 3], res.bytes().unwrap());
+
+    // Stop the server.
+    server.stop().unwrap();
+}
+
+#[test]
+fn test_ephemeral_port() {
+    let mut server = MetricsServer::builder()
+        .address("localhost:0")
+        .listener(ListenerConfig::new("127.0.0.1:0"))
+        .build()
+        .unwrap();
+    server.serve();
+
+    // Assert the OS assigned ports are reported and served.
+    let addr = server.local_addr();
+    assert_ne!(0, addr.port());
+    assert_eq!(addr, server.local_addrs()[0]);
+    for addr in server.local_addrs() {
+        let res = reqwest::blocking::get(format!("http://{addr}/metrics")).unwrap();
+        assert_eq!(200, res.status());
+    }
+
+    // Stop the server.
+    server.stop().unwrap();
+}
+
+#[test]
+fn test_builder_no_address() {
+    let server = MetricsServer::builder().build();
+    assert!(matches!(server, Err(ServerError::Create(_))));
+}
+
+#[test]
+fn test_multiple_listeners() {
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .listener(ListenerConfig::new("127.0.0.1:0"))
+        .build()
+        .unwrap();
+    server.serve();
+
+    // Assert both listeners serve the same buffer.
+    server.update(vec![1, 2, 3]);
+    for addr in server.local_addrs() {
+        let res = reqwest::blocking::get(format!("http://{addr}/metrics")).unwrap();
+        assert_eq!(200, res.status());
+        assert_eq!(vec![1, 2, 3], res.bytes().unwrap());
+    }
+
+    // Stop the server.
+    server.stop().unwrap();
+}
+
+#[test]
+#[cfg(feature = "tls")]
+fn test_https_and_http_listeners() {
+    // Load TLS config.
+    let cert = include_bytes!("./certs/certificate.pem").to_vec();
+    let key = include_bytes!("./certs/private_key.pem").to_vec();
+
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .tls(cert, key)
+        .listener(ListenerConfig::new("127.0.0.1:0"))
+        .build()
+        .unwrap();
+    server.serve();
+    let addrs = server.local_addrs();
+    server.update(vec![1, 2, 3]);
+
+    // Assert the primary listener serves HTTPS.
+    let client = reqwest::blocking::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap();
+    let res = client
+        .get(format!("https://{}/metrics", addrs[0]))
+        .send()
+        .unwrap();
+    assert_eq!(vec![1, 2, 3], res.bytes().unwrap());
+
+    // Assert the additional listener serves plain HTTP.
+    let res = reqwest::blocking::get(format!("http://{}/metrics", addrs[1])).unwrap();
+    assert_eq!(vec![1, 2, 3], res.bytes().unwrap());
+
+    // Stop the server.
+    server.stop().unwrap();
+}
+
+#[test]
+fn test_listener_path_precedence() {
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .listener(ListenerConfig::new("127.0.0.1:0").path("/internal/metrics"))
+        .build()
+        .unwrap();
+    server.serve_uri("/test".to_string());
+    let addrs = server.local_addrs();
+
+    // Assert the primary listener serves the server path.
+    let res = reqwest::blocking::get(format!("http://{}/test", addrs[0])).unwrap();
+    assert_eq!(200, res.status());
+
+    // Assert the listener path overrides the server path.
+    let res = reqwest::blocking::get(format!("http://{}/test", addrs[1])).unwrap();
+    assert_eq!(404, res.status());
+    let res = reqwest::blocking::get(format!("http://{}/internal/metrics", addrs[1])).unwrap();
+    assert_eq!(200, res.status());
+
+    // Stop the server.
+    server.stop().unwrap();
+}
+
+#[test]
+fn test_request_limits() {
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .max_url_length(16)
+        .max_header_count(8)
+        .build()
+        .unwrap();
+    server.serve();
+    let addr = server.local_addr();
+
+    // Assert long URLs return 414.
+    let res = reqwest::blocking::get(format!("http://{addr}/metrics?aaaaaaaaaaaaaaaa")).unwrap();
+    assert_eq!(414, res.status());
+
+    // Assert too many headers return 431.
+    let client = reqwest::blocking::Client::new();
+    let mut req = client.get(format!("http://{addr}/metrics"));
+    for i in 0..10 {
+        req = req.header(format!("x-header-{i}"), "value");
+    }
+    let res = req.send().unwrap();
+    assert_eq!(431, res.status());
+
+    // Stop the server.
+    server.stop().unwrap();
+}
+
+// Sends a raw request to the given address and reads the response until the server closes it.
+fn raw_request(addr: SocketAddr, req: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(req.as_bytes()).unwrap();
+
+    let mut res = String::new();
+    stream.read_to_string(&mut res).unwrap();
+    res
+}
+
+#[test]
+fn test_http_10_client() {
+    let mut server = MetricsServer::new("127.0.0.1:0", None, None).unwrap();
+    server.serve();
+    let addr = server.local_addr();
+
+    // Publish a large payload so truncation would be detected.
+    let data = "a".repeat(1024 * 1024);
+    server.update(data.clone().into());
+
+    // Assert HTTP/1.0 requests without a Host header receive the full body and are closed.
+    let res = raw_request(addr, "GET /metrics HTTP/1.0\r\n\r\n");
+    let (head, body) = res.split_once("\r\n\r\n").unwrap();
+    let headers: Vec<&str> = head.lines().collect();
+    assert_eq!("HTTP/1.0 200 OK", headers[0]);
+    assert!(headers.contains(&format!("content-length: {}", data.len()).as_str()));
+    assert!(headers.contains(&"connection: close"));
+    assert_eq!(data, body);
+
+    // Assert keep-alive is not honoured for HTTP/1.0 clients.
+    let res = raw_request(
+        addr,
+        "GET /metrics HTTP/1.0\r\nConnection: keep-alive\r\n\r\n",
+    );
+    assert!(res.contains("connection: close\r\n"));
+    assert!(res.ends_with(&data));
+
+    // Stop the server.
+    server.stop().unwrap();
+}
+
+#[test]
+fn test_expect_continue() {
+    let mut server = MetricsServer::new("127.0.0.1:0", None, None).unwrap();
+    server.serve();
+    let addr = server.local_addr();
+
+    // Assert the client is told to continue before the final response is sent.
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let req = "POST /metrics HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\n";
+    stream.write_all(req.as_bytes()).unwrap();
+    let mut buf = [0; 25];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(b"HTTP/1.1 100 Continue\r\n\r\n", &buf);
+    stream.write_all(b"hello").unwrap();
+    let mut res = String::new();
+    stream.read_to_string(&mut res).unwrap();
+    assert!(res.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+
+    // Assert unknown expectations are rejected.
+    let res = raw_request(
+        addr,
+        "POST /metrics HTTP/1.1\r\nExpect: something-else\r\nContent-Length: 5\r\n\r\n",
+    );
+    assert!(res.starts_with("HTTP/1.1 417 Expectation Failed\r\n"));
+
+    // Stop the server.
+    server.stop().unwrap();
+}
+
+#[test]
+fn test_expect_continue_rejected() {
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .expect_continue(false)
+        .build()
+        .unwrap();
+    server.serve();
+    let addr = server.local_addr();
+
+    // Assert the request is rejected without waiting for the body.
+    let res = raw_request(
+        addr,
+        "POST /metrics HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\n",
+    );
+    assert!(res.starts_with("HTTP/1.1 417 Expectation Failed\r\n"));
+
+    // Stop the server.
+    server.stop().unwrap();
+}
+
+#[test]
+fn test_checksum_header() {
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .checksum(true)
+        .build()
+        .unwrap();
+    server.serve();
+    let addr = server.local_addr();
+
+    // Assert identical payloads have identical checksums.
+    let mut checksums = Vec::new();
+    for data in ["a", "a", "b"] {
+        server.update(data.into());
+        let res = reqwest::blocking::get(format!("http://{addr}/metrics")).unwrap();
+        let checksum = res.headers().get("x-content-checksum").unwrap().clone();
+        assert!(checksum.to_str().unwrap().starts_with("xxh3="));
+        checksums.push(checksum);
+    }
+    assert_eq!(checksums[0], checksums[1]);
+    assert_ne!(checksums[1], checksums[2]);
+
+    // Stop the server.
+    server.stop().unwrap();
+}
+
+#[test]
+fn test_basic_auth() {
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .auth(BasicAuth::new("user", "pass"))
+        .listener(ListenerConfig::new("127.0.0.1:0").without_auth())
+        .build()
+        .unwrap();
+    server.serve();
+    let addrs = server.local_addrs();
+
+    // Assert unauthenticated requests are challenged.
+    let res = reqwest::blocking::get(format!("http://{}/metrics", addrs[0])).unwrap();
+    assert_eq!(401, res.status());
+    assert!(res.headers()["www-authenticate"]
+        .to_str()
+        .unwrap()
+        .starts_with("Basic "));
+
+    // Assert authenticated requests are served.
+    let client = reqwest::blocking::Client::new();
+    let res = client
+        .get(format!("http://{}/metrics", addrs[0]))
+        .basic_auth("user", Some("wrong"))
+        .send()
+        .unwrap();
+    assert_eq!(401, res.status());
+    let res = client
+        .get(format!("http://{}/metrics", addrs[0]))
+        .basic_auth("user", Some("pass"))
+        .send()
+        .unwrap();
+    assert_eq!(200, res.status());
+
+    // Assert the listener override disables authentication.
+    let res = reqwest::blocking::get(format!("http://{}/metrics", addrs[1])).unwrap();
+    assert_eq!(200, res.status());
+
+    // Stop the server.
+    server.stop().unwrap();
+}
+
+#[test]
+fn test_digest_auth_challenge() {
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .auth(DigestAuth::new("metrics", "user", "pass"))
+        .build()
+        .unwrap();
+    server.serve();
+    let addr = server.local_addr();
+
+    // Assert unauthenticated requests are challenged for each supported algorithm.
+    let res = reqwest::blocking::get(format!("http://{addr}/metrics")).unwrap();
+    assert_eq!(401, res.status());
+    let challenges: Vec<_> = res
+        .headers()
+        .get_all("www-authenticate")
+        .iter()
+        .map(|v| v.to_str().unwrap().to_string())
+        .collect();
+    assert_eq!(2, challenges.len());
+    assert!(challenges[0].starts_with("Digest realm=\"metrics\", qop=\"auth\", algorithm=SHA-256"));
+    assert!(challenges[1].starts_with("Digest realm=\"metrics\", qop=\"auth\", algorithm=MD5"));
+
+    // Stop the server.
+    server.stop().unwrap();
+}
+
+#[test]
+fn test_bearer_auth() {
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .auth(BearerAuth::new("token"))
+        .build()
+        .unwrap();
+    server.serve();
+    let addr = server.local_addr();
+
+    // Assert requests without a token are challenged.
+    let res = reqwest::blocking::get(format!("http://{addr}/metrics")).unwrap();
+    assert_eq!(401, res.status());
+    assert_eq!(
+        "Bearer realm=\"metrics\"",
+        res.headers()["www-authenticate"]
+    );
+
+    // Assert requests with the wrong token are forbidden.
+    let client = reqwest::blocking::Client::new();
+    let res = client
+        .get(format!("http://{addr}/metrics"))
+        .bearer_auth("wrong")
+        .send()
+        .unwrap();
+    assert_eq!(403, res.status());
+    let res = client
+        .get(format!("http://{addr}/metrics"))
+        .bearer_auth("token")
+        .send()
+        .unwrap();
+    assert_eq!(200, res.status());
+
+    // Stop the server.
+    server.stop().unwrap();
+}
+
+#[test]
+fn test_custom_authenticator() {
+    // Only allows requests carrying a specific header.
+    struct HeaderAuth;
+
+    impl Authenticator for HeaderAuth {
+        fn authenticate(&self, req: &RequestMeta) -> AuthDecision {
+            match req.headers().get("x-api-key") {
+                Some(key) if key == "secret" => AuthDecision::Allow,
+                _ => AuthDecision::Forbidden,
+            }
+        }
+    }
+
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .auth(HeaderAuth)
+        .build()
+        .unwrap();
+    server.serve();
+    let addr = server.local_addr();
+
+    let client = reqwest::blocking::Client::new();
+    let res = client.get(format!("http://{addr}/metrics")).send().unwrap();
+    assert_eq!(403, res.status());
+    let res = client
+        .get(format!("http://{addr}/metrics"))
+        .header("x-api-key", "secret")
+        .send()
+        .unwrap();
+    assert_eq!(200, res.status());
+
+    // Stop the server.
+    server.stop().unwrap();
+}
+
+#[test]
+fn test_secret_file_rotation() {
+    let path = std::env::temp_dir().join(format!("metrics_server_token_{}", std::process::id()));
+    std::fs::write(&path, "first\n").unwrap();
+
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .auth(BearerAuth::from_file(&path).unwrap())
+        .build()
+        .unwrap();
+    server.serve();
+    let addr = server.local_addr();
+
+    let client = reqwest::blocking::Client::new();
+    let get = |token: &str| {
+        client
+            .get(format!("http://{addr}/metrics"))
+            .bearer_auth(token)
+            .send()
+            .unwrap()
+            .status()
+    };
+    assert_eq!(200, get("first"));
+
+    // Assert the rotated token is picked up without restarting the server.
+    std::fs::write(&path, "second-token\n").unwrap();
+    assert_eq!(403, get("first"));
+    assert_eq!(200, get("second-token"));
+
+    // Assert missing files are rejected when building the server.
+    std::fs::remove_file(&path).unwrap();
+    assert!(matches!(
+        BearerAuth::from_file(&path),
+        Err(ServerError::Create(_))
+    ));
+
+    // Stop the server.
+    server.stop().unwrap();
+}
+
+#[test]
+#[cfg(feature = "tls")]
+fn test_tls_files() {
+    let dir = std::env::temp_dir().join(format!("metrics_server_tls_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (cert, key) = (dir.join("certificate.pem"), dir.join("private_key.pem"));
+    std::fs::write(&cert, include_bytes!("./certs/certificate.pem")).unwrap();
+    std::fs::write(&key, include_bytes!("./certs/private_key.pem")).unwrap();
+
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .tls_files(&cert, &key)
+        .build()
+        .unwrap();
+    server.serve();
+    let addr = server.local_addr();
+
+    let client = reqwest::blocking::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap();
+    let res = client
+        .get(format!("https://{addr}/metrics"))
+        .send()
+        .unwrap();
+    assert_eq!(200, res.status());
+
+    // Assert the previous certificate is served while the files are invalid.
+    std::fs::write(&cert, "invalid").unwrap();
+    let res = client
+        .get(format!("https://{addr}/metrics"))
+        .send()
+        .unwrap();
+    assert_eq!(200, res.status());
+
+    // Stop the server.
+    server.stop().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_redact_labels() {
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .redact_label("user_email", Redaction::Mask)
+        .build()
+        .unwrap();
+    server.serve();
+    let addr = server.local_addr();
+
+    server.update("logins{user_email=\"a@example.com\",code=\"200\"} 1\n".into());
+    let res = reqwest::blocking::get(format!("http://{addr}/metrics")).unwrap();
+    assert_eq!(
+        "logins{user_email=\"[REDACTED]\",code=\"200\"} 1\n",
+        res.text().unwrap()
+    );
+
+    // Stop the server.
+    server.stop().unwrap();
+}
+
+#[test]
+fn test_drop_rules() {
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .rule(Rule::drop("go_.*").unwrap())
+        .build()
+        .unwrap();
+    server.serve();
+    let addr = server.local_addr();
+
+    server.update("go_goroutines 10\nhttp_requests_total 1\n".into());
+    let res = reqwest::blocking::get(format!("http://{addr}/metrics")).unwrap();
+    assert_eq!("http_requests_total 1\n", res.text().unwrap());
+
+    // Stop the server.
+    server.stop().unwrap();
+}
+
+#[test]
+fn test_transform() {
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .transform(|mut data| {
+            data.extend_from_slice(b"injected 1\n");
+            data
+        })
+        .rule(Rule::drop("dropped").unwrap())
+        .build()
+        .unwrap();
+    server.serve();
+    let addr = server.local_addr();
+
+    // Assert built-in filters are applied before custom transforms.
+    server.update("dropped 1\nkept 1\n".into());
+    let res = reqwest::blocking::get(format!("http://{addr}/metrics")).unwrap();
+    assert_eq!("kept 1\ninjected 1\n", res.text().unwrap());
+
+    // Stop the server.
+    server.stop().unwrap();
+}
+
+#[test]
+fn test_pagination() {
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .paginate(16)
+        .build()
+        .unwrap();
+    server.serve();
+    let addr = server.local_addr();
+    server.update("# TYPE a gauge\na 1\n# TYPE b gauge\nb 1\n".into());
+
+    let get = |url: String| reqwest::blocking::get(url).unwrap();
+
+    // Assert each family is served on its own page.
+    let res = get(format!("http://{addr}/metrics?page=2"));
+    assert_eq!(200, res.status());
+    assert_eq!("2", res.headers()["x-metrics-page-count"]);
+    assert_eq!("# TYPE b gauge\nb 1\n", res.text().unwrap());
+
+    // Assert the index describes every page.
+    let res = get(format!("http://{addr}/metrics?page=index"));
+    assert_eq!(
+        "page=1 bytes=19 families=a\npage=2 bytes=19 families=b\n",
+        res.text().unwrap()
+    );
+
+    // Assert the whole payload is still served without a page.
+    let res = get(format!("http://{addr}/metrics"));
+    assert_eq!(38, res.bytes().unwrap().len());
+
+    assert_eq!(404, get(format!("http://{addr}/metrics?page=3")).status());
+    assert_eq!(400, get(format!("http://{addr}/metrics?page=x")).status());
+
+    // Stop the server.
+    server.stop().unwrap();
+}
+
+#[test]
+fn test_handle() {
+    let mut server = MetricsServer::new("127.0.0.1:0", None, None).unwrap();
+    server.serve();
+    let addr = server.local_addr();
+
+    // Assert updates from cloned handles on other threads are served.
+    let handle = server.handle();
+    std::thread::spawn(move || handle.clone().update(vec![1, 2, 3]))
+        .join()
+        .unwrap();
+    let res = reqwest::blocking::get(format!("http://{addr}/metrics")).unwrap();
+    assert_eq!(vec![1, 2, 3], res.bytes().unwrap());
+
+    // Stop the server.
+    server.stop().unwrap();
+}
+
+#[test]
+fn test_write_options() {
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .write_buffer_size(64 * 1024)
+        .write_chunk_size(1000)
+        .build()
+        .unwrap();
+    server.serve();
+    let addr = server.local_addr();
+
+    // Assert large payloads are served intact.
+    let data: Vec<u8> = (0..1024 * 1024).map(|i| i as u8).collect();
+    server.update(data.clone());
+    let res = reqwest::blocking::get(format!("http://{addr}/metrics")).unwrap();
+    assert_eq!(data, res.bytes().unwrap());
 
     // Stop the server.
     server.stop().unwrap();
 }
+
+#[test]
+fn test_update_file() {
+    let mut server = MetricsServer::new("127.0.0.1:0", None, None).unwrap();
+    server.serve();
+    let addr = server.local_addr();
+
+    // Assert the file is served as it is when requested.
+    let path = std::env::temp_dir().join("metrics_server_test_update_file.prom");
+    std::fs::write(&path, "a 1\n").unwrap();
+    assert_eq!(4, server.update_file(&path).unwrap());
+    std::fs::write(&path, "a 2\n").unwrap();
+    let res = reqwest::blocking::get(format!("http://{addr}/metrics")).unwrap();
+    assert_eq!(200, res.status());
+    assert_eq!("a 2\n", res.text().unwrap());
+
+    // Assert a missing file is a server error.
+    std::fs::remove_file(&path).unwrap();
+    let res = reqwest::blocking::get(format!("http://{addr}/metrics")).unwrap();
+    assert_eq!(500, res.status());
+
+    // Assert directories are rejected.
+    assert!(server.update_file(std::env::temp_dir()).is_err());
+
+    // Assert updating the data replaces the file.
+    server.update(vec![1, 2, 3]);
+    let res = reqwest::blocking::get(format!("http://{addr}/metrics")).unwrap();
+    assert_eq!(vec![1, 2, 3], res.bytes().unwrap());
+
+    // Stop the server.
+    server.stop().unwrap();
+}
+
+#[test]
+#[cfg(unix)]
+fn test_mmap() {
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .mmap(std::env::temp_dir())
+        .paginate(16)
+        .build()
+        .unwrap();
+    server.serve();
+    let addr = server.local_addr();
+
+    // Assert mapped payloads are served whole and by page.
+    let data = "# TYPE a gauge\na 1\n# TYPE b gauge\nb 1\n";
+    server.update(data.into());
+    let res = reqwest::blocking::get(format!("http://{addr}/metrics")).unwrap();
+    assert_eq!(data, res.text().unwrap());
+    let res = reqwest::blocking::get(format!("http://{addr}/metrics?page=2")).unwrap();
+    assert_eq!("# TYPE b gauge\nb 1\n", res.text().unwrap());
+
+    // Assert empty payloads, which can't be mapped, are still served.
+    server.update(Vec::new());
+    let res = reqwest::blocking::get(format!("http://{addr}/metrics")).unwrap();
+    assert_eq!(200, res.status());
+    assert!(res.bytes().unwrap().is_empty());
+
+    // Stop the server.
+    server.stop().unwrap();
+}
+
+#[test]
+fn test_workers() {
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .workers(2)
+        .build()
+        .unwrap();
+    server.serve();
+    let addr = server.local_addr();
+    server.update(vec![1, 2, 3]);
+
+    // Occupy a worker with a client that never sends a request.
+    let slow = std::net::TcpStream::connect(addr).unwrap();
+
+    // Assert other clients are still served promptly.
+    let start = std::time::Instant::now();
+    let res = reqwest::blocking::get(format!("http://{addr}/metrics")).unwrap();
+    assert_eq!(vec![1, 2, 3], res.bytes().unwrap());
+    assert!(start.elapsed() < std::time::Duration::from_secs(2));
+
+    // Stop the server.
+    drop(slow);
+    server.stop().unwrap();
+}
+
+#[test]
+#[cfg(feature = "tokio")]
+fn test_serve_async() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let server = std::sync::Arc::new(MetricsServer::new("127.0.0.1:0", None, None).unwrap());
+        let addr = server.local_addr();
+        server.update(b"a 1\n".to_vec());
+        let s = std::sync::Arc::clone(&server);
+        let task = tokio::spawn(async move { s.serve_async().await });
+
+        // Occupy a connection with a client that never sends a request.
+        let _slow = tokio::net::TcpStream::connect(addr).await.unwrap();
+
+        // Assert other clients are still served.
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut res = String::new();
+        stream.read_to_string(&mut res).await.unwrap();
+        assert!(res.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(res.ends_with("\r\n\r\na 1\n"));
+
+        task.abort();
+    });
+}
+
+#[test]
+#[cfg(feature = "tower")]
+fn test_service() {
+    use http_body_util::BodyExt;
+    use tower_service::Service;
+
+    let mut service = MetricsServer::builder()
+        .auth(BearerAuth::new("secret"))
+        .build_service()
+        .unwrap();
+    service.handle().update(b"a 1\n".to_vec());
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let mut call = |auth: &str| {
+        let req = http::Request::get("/custom/metrics")
+            .header("authorization", auth)
+            .body(())
+            .unwrap();
+        runtime.block_on(async {
+            let res = service.call(req).await.unwrap();
+            let status = res.status();
+            (status, res.into_body().collect().await.unwrap().to_bytes())
+        })
+    };
+
+    // Assert metrics are served at whichever path the service is mounted.
+    let (status, body) = call("Bearer secret");
+    assert_eq!(200, status);
+    assert_eq!(&b"a 1\n"[..], body);
+
+    // Assert server-wide authentication still applies.
+    let (status, _) = call("Bearer wrong");
+    assert_eq!(403, status);
+
+    // Assert listeners can't be configured for a service.
+    let res = MetricsServer::builder()
+        .address("localhost:0")
+        .build_service();
+    assert!(matches!(res, Err(ServerError::Create(_))));
+}
+
+#[test]
+fn test_from_listener() {
+    let listener = std::net::TcpListener::bind("localhost:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut server = MetricsServer::from_listener(listener).unwrap();
+    assert_eq!(addr, server.local_addr());
+    server.serve();
+
+    // Assert requests are served on the caller's socket.
+    server.update(vec![1, 2, 3]);
+    let res = reqwest::blocking::get(format!("http://{addr}/metrics")).unwrap();
+    assert_eq!(vec![1, 2, 3], res.bytes().unwrap());
+
+    // Stop the server.
+    server.stop().unwrap();
+}
+
+#[test]
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+fn test_reuse_port() {
+    let build = |addr: SocketAddr| {
+        MetricsServer::builder()
+            .address(addr)
+            .reuse_port(true)
+            .build()
+    };
+
+    // Assert multiple servers can bind the same address.
+    let first = build("127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = first.local_addr();
+    let second = build(addr).unwrap();
+    assert_eq!(addr, second.local_addr());
+
+    // Assert the address is exclusive without the option.
+    let res = MetricsServer::builder().address(addr).build();
+    assert!(matches!(res, Err(ServerError::Create(_))));
+}
+
+#[test]
+fn test_stop_graceful() {
+    let mut server = MetricsServer::new("127.0.0.1:0", None, None).unwrap();
+    server.serve();
+    let addr = server.local_addr();
+    server.update(vec![1, 2, 3]);
+
+    // Assert requests are served before stopping.
+    let res = reqwest::blocking::get(format!("http://{addr}/metrics")).unwrap();
+    assert_eq!(vec![1, 2, 3], res.bytes().unwrap());
+
+    // Occupy the worker with a client that never finishes its request.
+    let mut slow = TcpStream::connect(addr).unwrap();
+    slow.write_all(b"GET /metrics HTTP/1.1\r\n").unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(50));
+
+    // Assert stopping doesn't wait for the client beyond the timeout.
+    let start = std::time::Instant::now();
+    server
+        .stop_graceful(std::time::Duration::from_millis(100))
+        .unwrap();
+    assert!(start.elapsed() < std::time::Duration::from_secs(2));
+
+    // Assert the connection was closed.
+    let mut buf = Vec::new();
+    assert!(matches!(slow.read_to_end(&mut buf), Ok(0) | Err(_)));
+}
+
+#[test]
+fn test_stop_graceful_mock_clock() {
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .clock(MockClock::new(std::time::SystemTime::now()))
+        .write_timeout(None)
+        .build()
+        .unwrap();
+    server.serve();
+    server.update(vec![0; 16 * 1024 * 1024]);
+    let addr = server.local_addr();
+
+    // Occupy the worker with a client that never reads its response.
+    let mut stuck = TcpStream::connect(addr).unwrap();
+    stuck.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(50));
+
+    // Assert stopping returns after the timeout, even though the configured clock never moves.
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let res = server.stop_graceful(std::time::Duration::from_millis(100));
+        tx.send(res.is_ok()).unwrap();
+    });
+    assert!(rx.recv_timeout(std::time::Duration::from_secs(5)).unwrap());
+}
+
+#[test]
+fn test_update_section() {
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .section_order(["http", "db"])
+        .section_headers(true)
+        .build()
+        .unwrap();
+    server.serve();
+    let addr = server.local_addr();
+
+    // Publish sections from separate handles.
+    let db = server.handle();
+    let http = server.handle();
+    db.update_section("db", b"db_queries 1\n".to_vec());
+    http.update_section("http", b"http_requests 2".to_vec());
+    server.update_section("cache", b"cache_hits 3\n".to_vec());
+
+    // Assert sections are merged in the configured order.
+    let res = reqwest::blocking::get(format!("http://{addr}/metrics")).unwrap();
+    assert_eq!(
+        res.text().unwrap(),
+        "# --- section: http ---\nhttp_requests 2\n\
+        # --- section: db ---\ndb_queries 1\n\
+        # --- section: cache ---\ncache_hits 3\n"
+    );
+
+    // Assert updating the whole payload discards the sections.
+    server.update(b"a 1\n".to_vec());
+    db.update_section("db", b"db_queries 4\n".to_vec());
+    let res = reqwest::blocking::get(format!("http://{addr}/metrics")).unwrap();
+    assert_eq!(res.text().unwrap(), "# --- section: db ---\ndb_queries 4\n");
+
+    // Stop the server.
+    server.stop().unwrap();
+}
+
+#[test]
+fn test_collect_sections() {
+    let mut server = MetricsServer::new("127.0.0.1:0", None, None).unwrap();
+    server.serve();
+    let addr = server.local_addr();
+    server.update_section("db", b"db_queries 1\n".to_vec());
+    server.update_section("http", b"http_requests 2\n".to_vec());
+    server.update_section("cache", b"cache_hits 3\n".to_vec());
+
+    // Assert only the requested sections are served.
+    let res = reqwest::blocking::get(format!(
+        "http://{addr}/metrics?collect[]=http&collect%5B%5D=db&collect[]=unknown"
+    ))
+    .unwrap();
+    assert_eq!(res.text().unwrap(), "db_queries 1\nhttp_requests 2\n");
+
+    // Assert every section is served by default.
+    let res = reqwest::blocking::get(format!("http://{addr}/metrics")).unwrap();
+    assert_eq!(
+        res.text().unwrap(),
+        "cache_hits 3\ndb_queries 1\nhttp_requests 2\n"
+    );
+
+    // Assert the parameter is ignored for payloads without sections.
+    server.update(b"a 1\n".to_vec());
+    let res = reqwest::blocking::get(format!("http://{addr}/metrics?collect[]=db")).unwrap();
+    assert_eq!(res.text().unwrap(), "a 1\n");
+
+    // Stop the server.
+    server.stop().unwrap();
+}
+
+#[test]
+fn test_stop_on_drop() {
+    let mut server = MetricsServer::new("127.0.0.1:0", None, None).unwrap();
+    server.serve();
+    let addr = server.local_addr();
+    server.update(vec![1, 2, 3]);
+
+    let res = reqwest::blocking::get(format!("http://{addr}/metrics")).unwrap();
+    assert_eq!(vec![1, 2, 3], res.bytes().unwrap());
+
+    // Assert dropping the server stops it and closes its socket.
+    drop(server);
+    assert!(TcpStream::connect(addr).is_err());
+}
+
+#[test]
+fn test_restart() {
+    let mut server = MetricsServer::new("127.0.0.1:0", None, None).unwrap();
+    let addr = server.local_addr();
+    server.update(vec![1, 2, 3]);
+
+    // Assert the server can be stopped and served again on the same listener.
+    for _ in 0..2 {
+        server.serve();
+        let res = reqwest::blocking::get(format!("http://{addr}/metrics")).unwrap();
+        assert_eq!(vec![1, 2, 3], res.bytes().unwrap());
+        server.stop().unwrap();
+    }
+
+    // Assert the listener is still bound, but no longer served.
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(std::time::Duration::from_millis(100)))
+        .unwrap();
+    stream.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+    let mut buf = [0; 1];
+    assert!(stream.read(&mut buf).is_err());
+}
+
+#[test]
+fn test_last_scrape() {
+    let mut server = MetricsServer::new("127.0.0.1:0", None, None).unwrap();
+    server.serve();
+    let addr = server.local_addr();
+    let handle = server.handle();
+    assert!(server.last_scrape().is_none());
+
+    // Assert requests for other paths aren't counted as scrapes.
+    let res = reqwest::blocking::get(format!("http://{addr}/other")).unwrap();
+    assert_eq!(404, res.status());
+    assert!(handle.last_scrape().is_none());
+
+    // Assert scrapes are recorded.
+    let before = std::time::Instant::now();
+    reqwest::blocking::get(format!("http://{addr}/metrics")).unwrap();
+    let scraped = handle.last_scrape().unwrap();
+    assert!(scraped >= before && scraped <= std::time::Instant::now());
+    assert_eq!(server.last_scrape(), Some(scraped));
+
+    // Stop the server.
+    server.stop().unwrap();
+}
+
+#[test]
+fn test_server_state() {
+    let mut server = MetricsServer::new("127.0.0.1:0", None, None).unwrap();
+    let handle = server.handle();
+    assert!(!server.is_running());
+    assert!(!server.is_stopping());
+    assert_eq!(server.path(), None);
+
+    // Assert the state of a running server.
+    server.serve_uri("/custom".to_string());
+    assert!(server.is_running());
+    assert!(handle.is_running());
+    assert!(!handle.is_stopping());
+    assert_eq!(server.path(), Some("/custom"));
+    assert_ne!(server.local_addr().port(), 0);
+
+    // Assert the state of a stopped server.
+    server.stop().unwrap();
+    assert!(!server.is_running());
+    assert!(!handle.is_running());
+    assert!(!handle.is_stopping());
+    assert_eq!(server.path(), None);
+}
+
+#[test]
+fn test_deduplicate_updates() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    // Count how many updates are transformed.
+    let transformed = Arc::new(AtomicUsize::new(0));
+    let count = Arc::clone(&transformed);
+    let server = MetricsServer::builder()
+        .address("localhost:0")
+        .deduplicate_updates(true)
+        .transform(move |data| {
+            count.fetch_add(1, Ordering::Relaxed);
+            data
+        })
+        .build()
+        .unwrap();
+
+    // Assert identical updates are skipped.
+    assert_eq!(server.update(b"a 1\n".to_vec()), 4);
+    assert_eq!(server.update(b"a 1\n".to_vec()), 4);
+    assert_eq!(transformed.load(Ordering::Relaxed), 1);
+
+    // Assert changed updates are published.
+    assert_eq!(server.update(b"a 10\n".to_vec()), 5);
+    assert_eq!(transformed.load(Ordering::Relaxed), 2);
+
+    // Assert unchanged sections are skipped too.
+    server.update_section("db", b"db_queries 1\n".to_vec());
+    server.update_section("db", b"db_queries 1\n".to_vec());
+    assert_eq!(transformed.load(Ordering::Relaxed), 3);
+}
+
+#[test]
+#[cfg(feature = "gzip")]
+fn test_gzip() {
+    let mut server = MetricsServer::new("127.0.0.1:0", None, None).unwrap();
+    server.serve();
+    let addr = server.local_addr();
+    let data = b"a 1\n".repeat(1000);
+    server.update(data.clone());
+
+    // Assert responses are compressed if accepted.
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(b"GET /metrics HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n")
+        .unwrap();
+    let mut res = Vec::new();
+    stream.read_to_end(&mut res).unwrap();
+    let split = res.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+    let head = String::from_utf8_lossy(&res[..split]);
+    assert!(head.contains("content-encoding: gzip\r\n"));
+    assert!(head.contains("vary: accept-encoding\r\n"));
+    let mut decoded = Vec::new();
+    flate2::read::GzDecoder::new(&res[split..])
+        .read_to_end(&mut decoded)
+        .unwrap();
+    assert_eq!(decoded, data);
+
+    // Assert responses are otherwise uncompressed.
+    let res = raw_request(addr, "GET /metrics HTTP/1.1\r\n\r\n");
+    assert!(!res.contains("content-encoding"));
+    assert!(res.ends_with("a 1\n"));
+
+    // Stop the server.
+    server.stop().unwrap();
+}
+
+#[test]
+fn test_audit() {
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .auth(BasicAuth::new("user", "pass"))
+        .audit_endpoint(true)
+        .build()
+        .unwrap();
+    server.serve();
+    let addr = server.local_addr();
+
+    // Assert authentication failures are recorded.
+    let client = reqwest::blocking::Client::new();
+    let res = client
+        .get(format!("http://{addr}/metrics"))
+        .basic_auth("user", Some("wrong"))
+        .send()
+        .unwrap();
+    assert_eq!(401, res.status());
+
+    // Assert the audit log requires authentication.
+    let res = client.get(format!("http://{addr}/-/audit")).send().unwrap();
+    assert_eq!(401, res.status());
+
+    let res = client
+        .get(format!("http://{addr}/-/audit"))
+        .basic_auth("user", Some("pass"))
+        .send()
+        .unwrap();
+    assert_eq!(200, res.status());
+    let text = res.text().unwrap();
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[0].ends_with(" start serving /metrics"));
+    assert!(lines[1].contains(" auth_failure 401 GET /metrics from 127.0.0.1:"));
+    assert!(lines[2].contains(" auth_failure 401 GET /-/audit from 127.0.0.1:"));
+
+    // Assert stopping is recorded.
+    server.stop().unwrap();
+    let kinds: Vec<AuditKind> = server.audit_events().iter().map(|e| e.kind).collect();
+    assert_eq!(
+        kinds,
+        [
+            AuditKind::Start,
+            AuditKind::AuthFailure,
+            AuditKind::AuthFailure,
+            AuditKind::Stop
+        ]
+    );
+}
+
+#[test]
+fn test_serve_already_running() {
+    let mut server = MetricsServer::new("127.0.0.1:0", None, None).unwrap();
+    server.try_serve_uri("/first".to_string()).unwrap();
+
+    // Assert serving the same path again succeeds.
+    server.try_serve_uri("/first".to_string()).unwrap();
+
+    // Assert serving a different path is an error reporting the active path.
+    let res = server.try_serve();
+    assert!(matches!(res, Err(ServerError::AlreadyRunning(ref path)) if path == "/first"));
+
+    // Assert the server can switch paths once stopped.
+    server.stop().unwrap();
+    server.try_serve().unwrap();
+    assert_eq!(server.path(), Some("/metrics"));
+
+    // Stop the server.
+    server.stop().unwrap();
+}
+
+#[test]
+fn test_last_modified() {
+    let mut server = MetricsServer::new("127.0.0.1:0", None, None).unwrap();
+    server.serve();
+    let addr = server.local_addr();
+    assert!(server.last_updated().is_none());
+
+    // Assert the update time is tracked and sent to scrapers.
+    let before = std::time::SystemTime::now();
+    server.update(vec![1, 2, 3]);
+    let updated = server.last_updated().unwrap();
+    assert!(updated >= before);
+    assert_eq!(server.handle().last_updated(), Some(updated));
+
+    let res = reqwest::blocking::get(format!("http://{addr}/metrics")).unwrap();
+    let last_modified = res.headers()["last-modified"].to_str().unwrap();
+    assert!(last_modified.ends_with(" GMT"));
+
+    // Stop the server.
+    server.stop().unwrap();
+}
+
+#[test]
+fn test_head_request() {
+    let mut server = MetricsServer::new("127.0.0.1:0", None, None).unwrap();
+    server.serve();
+    let addr = server.local_addr();
+    server.update(b"my_awesome_metric 10\n".to_vec());
+
+    // Assert HEAD requests receive the same headers as GET, but no body.
+    let get = raw_request(addr, "GET /metrics HTTP/1.1\r\n\r\n");
+    let head = raw_request(addr, "HEAD /metrics HTTP/1.1\r\n\r\n");
+    let (get_head, body) = get.split_once("\r\n\r\n").unwrap();
+    assert_eq!("my_awesome_metric 10\n", body);
+    assert_eq!(format!("{get_head}\r\n\r\n"), head);
+    assert!(head.contains("content-length: 21\r\n"));
+
+    // Assert health checks aren't counted as scrapes.
+    let _ = raw_request(addr, "HEAD /metrics HTTP/1.1\r\n\r\n");
+    let scraped = server.last_scrape().unwrap();
+    let _ = raw_request(addr, "HEAD /metrics HTTP/1.1\r\n\r\n");
+    assert_eq!(server.last_scrape(), Some(scraped));
+
+    // Assert HEAD requests to unknown paths still return 404.
+    let res = raw_request(addr, "HEAD /unknown HTTP/1.1\r\n\r\n");
+    assert!(res.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+    // Stop the server.
+    server.stop().unwrap();
+}
+
+#[test]
+fn test_serve_metrics_path() {
+    // Assert invalid paths are rejected when constructed.
+    assert!(MetricsPath::new("metrics").is_err());
+    assert!(MetricsPath::new("/metrics?page=1").is_err());
+
+    // Assert validated paths are served exactly as given.
+    let mut server = MetricsServer::new("127.0.0.1:0", None, None).unwrap();
+    server.serve_uri(MetricsPath::new("/Custom/Metrics").unwrap());
+    let addr = server.local_addr();
+    assert_eq!(server.path(), Some("/Custom/Metrics"));
+    server.update(vec![1, 2, 3]);
+
+    let res = reqwest::blocking::get(format!("http://{addr}/Custom/Metrics")).unwrap();
+    assert_eq!(200, res.status());
+    let res = reqwest::blocking::get(format!("http://{addr}/custom/metrics")).unwrap();
+    assert_eq!(404, res.status());
+
+    // Stop the server.
+    server.stop().unwrap();
+}
+
+#[test]
+fn test_content_type() {
+    // Assert invalid content types are rejected.
+    let res = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .content_type("text/plain\n")
+        .build();
+    assert!(matches!(res, Err(ServerError::Create(_))));
+
+    // Assert metrics are served as the Prometheus text format by default.
+    let mut server = MetricsServer::new("127.0.0.1:0", None, None).unwrap();
+    server.serve();
+    let addr = server.local_addr();
+    let res = reqwest::blocking::get(format!("http://{addr}/metrics")).unwrap();
+    assert_eq!(
+        res.headers()["content-type"],
+        metrics_server::DEFAULT_CONTENT_TYPE
+    );
+
+    // Assert errors aren't sent with a metrics content type.
+    let res = reqwest::blocking::get(format!("http://{addr}/unknown")).unwrap();
+    assert!(res.headers().get("content-type").is_none());
+    server.stop().unwrap();
+
+    // Assert the content type can be configured.
+    let content_type = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .content_type(content_type)
+        .build()
+        .unwrap();
+    server.serve();
+    let addr = server.local_addr();
+    let res = reqwest::blocking::get(format!("http://{addr}/metrics")).unwrap();
+    assert_eq!(res.headers()["content-type"], content_type);
+
+    // Stop the server.
+    server.stop().unwrap();
+}
+
+#[test]
+fn test_normalized_paths() {
+    let mut server = MetricsServer::new("127.0.0.1:0", None, None).unwrap();
+    server.serve_uri("/debug/metrics".to_string());
+    let addr = server.local_addr();
+    server.update(vec![1, 2, 3]);
+
+    // Assert equivalent request paths are matched.
+    for path in [
+        "/debug//metrics",
+        "/debug/./metrics",
+        "/internal/../debug/metrics",
+    ] {
+        let res = raw_request(addr, &format!("GET {path}?page=1 HTTP/1.1\r\n\r\n"));
+        assert!(res.starts_with("HTTP/1.1 200 OK\r\n"), "{path}");
+    }
+
+    // Assert case and trailing slashes are still significant.
+    for path in ["/Debug/Metrics", "/debug/metrics/"] {
+        let res = raw_request(addr, &format!("GET {path} HTTP/1.1\r\n\r\n"));
+        assert!(res.starts_with("HTTP/1.1 404 Not Found\r\n"), "{path}");
+    }
+
+    // Stop the server.
+    server.stop().unwrap();
+}
+
+#[test]
+fn test_openmetrics_negotiation() {
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .transform(|mut data| {
+            data.extend_from_slice(b"transformed 1\n");
+            data
+        })
+        .build()
+        .unwrap();
+    server.serve();
+    let addr = server.local_addr();
+    server.update(b"# TYPE a counter\na 1\n".to_vec());
+
+    // Prometheus' default Accept header, preferring OpenMetrics.
+    let accept = "application/openmetrics-text;version=1.0.0;q=0.5,\
+        application/openmetrics-text;version=0.0.1;q=0.4,text/plain;version=0.0.4;q=0.3,*/*;q=0.2";
+    let client = reqwest::blocking::Client::new();
+    let get = |accept: &str| {
+        client
+            .get(format!("http://{addr}/metrics"))
+            .header("accept", accept)
+            .send()
+            .unwrap()
+    };
+
+    // Assert the text format is served until an OpenMetrics variant is published.
+    let res = get(accept);
+    assert_eq!(
+        res.headers()["content-type"],
+        metrics_server::DEFAULT_CONTENT_TYPE
+    );
+    assert_eq!(
+        res.text().unwrap(),
+        "# TYPE a counter\na 1\ntransformed 1\n"
+    );
+
+    // Assert the OpenMetrics variant always ends with `# EOF`, after any transforms.
+    server.update_openmetrics(b"# TYPE a counter\na_total 1\n# EOF\n".to_vec());
+    let res = get(accept);
+    assert_eq!(
+        res.headers()["content-type"],
+        "application/openmetrics-text; version=1.0.0; charset=utf-8"
+    );
+    assert!(res.headers().get_all("vary").iter().any(|v| v == "accept"));
+    assert_eq!(
+        res.text().unwrap(),
+        "# TYPE a counter\na_total 1\ntransformed 1\n# EOF\n"
+    );
+
+    // Assert other clients still receive the text format.
+    for accept in ["text/plain", "*/*", "application/openmetrics-text;q=0, */*"] {
+        let res = get(accept);
+        assert_eq!(
+            res.headers()["content-type"],
+            metrics_server::DEFAULT_CONTENT_TYPE
+        );
+    }
+
+    // Stop the server.
+    server.stop().unwrap();
+}
+
+#[test]
+fn test_self_metrics() {
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .self_metrics(true)
+        .build()
+        .unwrap();
+    server.serve();
+    let addr = server.local_addr();
+
+    let client = reqwest::blocking::Client::new();
+    for _ in 0..2 {
+        let res = client.get(format!("http://{addr}/unknown")).send().unwrap();
+        assert_eq!(404, res.status());
+    }
+    let res = client
+        .post(format!("http://{addr}/metrics"))
+        .send()
+        .unwrap();
+    assert_eq!(405, res.status());
+
+    // Assert the responses sent before the update are counted by status code.
+    server.update(b"a 1\n".to_vec());
+    let res = client.get(format!("http://{addr}/metrics")).send().unwrap();
+    let body = res.text().unwrap();
+    assert!(body.starts_with("a 1\n"));
+    assert!(body.contains("# TYPE metrics_server_http_requests_total counter\n"));
+    assert!(body.contains("metrics_server_http_requests_total{code=\"404\"} 2\n"));
+    assert!(body.contains("metrics_server_http_requests_total{code=\"405\"} 1\n"));
+    assert!(!body.contains("code=\"200\""));
+
+    // Assert later responses are counted on the next update.
+    server.update(b"a 2\n".to_vec());
+    let res = client.get(format!("http://{addr}/metrics")).send().unwrap();
+    let body = res.text().unwrap();
+    assert!(body.contains("metrics_server_http_requests_total{code=\"200\"} 1\n"));
+
+    // Stop the server.
+    server.stop().unwrap();
+}
+
+#[test]
+fn test_probe() {
+    // Assert unreachable servers are an error, using a port that was free when released.
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    assert!(metrics_server::probe(addr, "/metrics").is_err());
+
+    let mut server = MetricsServer::new("127.0.0.1:0", None, None).unwrap();
+    server.serve();
+    let addr = server.local_addr();
+    server.update(b"a 1\n".to_vec());
+
+    let res = metrics_server::probe(addr, "/metrics").unwrap();
+    assert!(res.is_success());
+    assert_eq!(res.body, b"a 1\n");
+
+    let res = metrics_server::probe(addr, "/unknown").unwrap();
+    assert_eq!(res.status, 404);
+
+    // Stop the server.
+    server.stop().unwrap();
+}
+
+#[test]
+#[cfg(feature = "protobuf")]
+fn test_protobuf_negotiation() {
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .paginate(4)
+        .build()
+        .unwrap();
+    server.serve();
+    let addr = server.local_addr();
+    server.update(b"a 1\nb 2\n".to_vec());
+    server.update_openmetrics(b"a 1\nb 2\n".to_vec());
+
+    // A length-delimited MetricFamily with the name "a", which must not be transformed.
+    let protobuf = vec![0x03, 0x0a, 0x01, b'a'];
+    server.update_protobuf(protobuf.clone());
+
+    // Prometheus' Accept header when configured to prefer protobuf.
+    let accept = "application/vnd.google.protobuf;proto=io.prometheus.client.MetricFamily;\
+        encoding=delimited;q=0.6,application/openmetrics-text;version=1.0.0;q=0.5,\
+        text/plain;version=0.0.4;q=0.3,*/*;q=0.2";
+    let client = reqwest::blocking::Client::new();
+    let res = client
+        .get(format!("http://{addr}/metrics"))
+        .header("accept", accept)
+        .send()
+        .unwrap();
+    assert_eq!(
+        res.headers()["content-type"],
+        "application/vnd.google.protobuf; proto=io.prometheus.client.MetricFamily; encoding=delimited"
+    );
+    assert!(res.headers().get("x-metrics-page-count").is_none());
+    assert_eq!(res.bytes().unwrap(), protobuf);
+
+    // Assert OpenMetrics is still served to scrapers preferring it.
+    let res = client
+        .get(format!("http://{addr}/metrics"))
+        .header("accept", "application/openmetrics-text;q=0.5,*/*;q=0.1")
+        .send()
+        .unwrap();
+    assert!(res.headers()["content-type"]
+        .to_str()
+        .unwrap()
+        .starts_with("application/openmetrics-text"));
+
+    // Stop the server.
+    server.stop().unwrap();
+}
+
+#[test]
+fn test_json() {
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .json(true)
+        .build()
+        .unwrap();
+    server.serve();
+    let addr = server.local_addr();
+    server.update(b"# TYPE a counter\na_total{b=\"c\"} 1\n".to_vec());
+
+    let expected = serde_json::json!({
+        "metrics": [{"name": "a_total", "type": "counter", "labels": {"b": "c"}, "value": 1}]
+    });
+
+    // Assert JSON is served to clients requesting it.
+    let client = reqwest::blocking::Client::new();
+    let res = client
+        .get(format!("http://{addr}/metrics"))
+        .header("accept", "application/json")
+        .send()
+        .unwrap();
+    assert_eq!(res.headers()["content-type"], "application/json");
+    let json: serde_json::Value = serde_json::from_slice(&res.bytes().unwrap()).unwrap();
+    assert_eq!(json, expected);
+
+    // Assert JSON is served at the metrics path with a .json extension.
+    let res = client
+        .get(format!("http://{addr}/metrics.json"))
+        .send()
+        .unwrap();
+    assert_eq!(200, res.status());
+    let json: serde_json::Value = serde_json::from_slice(&res.bytes().unwrap()).unwrap();
+    assert_eq!(json, expected);
+
+    // Assert other clients still receive the text format.
+    let res = client.get(format!("http://{addr}/metrics")).send().unwrap();
+    assert_eq!(
+        res.text().unwrap(),
+        "# TYPE a counter\na_total{b=\"c\"} 1\n"
+    );
+
+    // Stop the server.
+    server.stop().unwrap();
+
+    // Assert JSON isn't served unless enabled.
+    let mut server = MetricsServer::new("127.0.0.1:0", None, None).unwrap();
+    server.serve();
+    let addr = server.local_addr();
+    let res = client
+        .get(format!("http://{addr}/metrics.json"))
+        .send()
+        .unwrap();
+    assert_eq!(404, res.status());
+    server.stop().unwrap();
+}
+
+#[test]
+fn test_self_check() {
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .self_check(true)
+        .build()
+        .unwrap();
+    let addr = server.local_addr();
+
+    // Assert serving succeeds when the listener responds.
+    server.try_serve().unwrap();
+    let res = reqwest::blocking::get(format!("http://{addr}/metrics")).unwrap();
+    assert_eq!(200, res.status());
+
+    // Stop the server.
+    server.stop().unwrap();
+}
+
+#[test]
+#[cfg(feature = "tls")]
+fn test_self_check_tls() {
+    // Assert serving fails if the certificate can't be verified, here because it has expired.
+    let cert = include_bytes!("./certs/certificate.pem").to_vec();
+    let key = include_bytes!("./certs/private_key.pem").to_vec();
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .tls(cert, key)
+        .self_check(true)
+        .build()
+        .unwrap();
+    let res = server.try_serve();
+    assert!(matches!(res, Err(ServerError::SelfCheck(_))), "{res:?}");
+
+    // Assert the server was stopped.
+    assert!(server.path().is_none());
+
+    // Assert serving succeeds with a valid certificate.
+    let cert = include_bytes!("./certs/self_check_certificate.pem").to_vec();
+    let key = include_bytes!("./certs/self_check_private_key.pem").to_vec();
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .tls(cert, key)
+        .self_check(true)
+        .build()
+        .unwrap();
+    server.try_serve().unwrap();
+
+    // Stop the server.
+    server.stop().unwrap();
+}
+
+#[test]
+fn test_debug_vars() {
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .debug_vars(true)
+        .build()
+        .unwrap();
+    server.serve();
+    let addr = server.local_addr();
+    server.set_var("version", "1.2.3");
+    server.handle().set_var("connections", 2);
+    server.set_var("ratio", Var::Float(0.5));
+
+    // Assert the variables are served as a JSON object.
+    let res = reqwest::blocking::get(format!("http://{addr}/debug/vars")).unwrap();
+    assert_eq!(200, res.status());
+    assert_eq!(res.headers()["content-type"], "application/json");
+    let json: serde_json::Value = serde_json::from_slice(&res.bytes().unwrap()).unwrap();
+    assert_eq!(json["version"], "1.2.3");
+    assert_eq!(json["connections"], 2);
+    assert_eq!(json["ratio"], 0.5);
+    assert!(json["cmdline"].is_array());
+
+    // Assert the metrics are still served.
+    let res = reqwest::blocking::get(format!("http://{addr}/metrics")).unwrap();
+    assert_eq!(200, res.status());
+
+    // Stop the server.
+    server.stop().unwrap();
+
+    // Assert the variables aren't served unless enabled.
+    let mut server = MetricsServer::new("127.0.0.1:0", None, None).unwrap();
+    server.serve();
+    let addr = server.local_addr();
+    let res = reqwest::blocking::get(format!("http://{addr}/debug/vars")).unwrap();
+    assert_eq!(404, res.status());
+    server.stop().unwrap();
+}
+
+#[test]
+fn test_rate_limit() {
+    let clock = MockClock::new(std::time::SystemTime::now());
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .clock(clock.clone())
+        .rate_limit(1, 2)
+        .self_metrics(true)
+        .build()
+        .unwrap();
+    server.serve();
+    let addr = server.local_addr();
+
+    // Assert requests within the burst are served.
+    for _ in 0..2 {
+        let res = reqwest::blocking::get(format!("http://{addr}/metrics")).unwrap();
+        assert_eq!(200, res.status());
+    }
+
+    // Assert requests over the limit are rejected, and told when to retry.
+    let res = reqwest::blocking::get(format!("http://{addr}/metrics")).unwrap();
+    assert_eq!(503, res.status());
+    assert_eq!(res.headers()["retry-after"], "1");
+
+    // Assert rejected requests are counted, once the bucket has refilled.
+    server.update(Vec::new());
+    clock.advance(std::time::Duration::from_secs(1));
+    let res = reqwest::blocking::get(format!("http://{addr}/metrics")).unwrap();
+    assert_eq!(200, res.status());
+    let body = res.text().unwrap();
+    assert!(body.contains("metrics_server_http_requests_total{code=\"503\"} 1\n"));
+    assert!(body.contains("metrics_server_overloaded_requests_total{reason=\"rate_limit\"} 1\n"));
+
+    // Stop the server.
+    server.stop().unwrap();
+}
+
+#[test]
+fn test_basic_auth_hash() {
+    // The bcrypt hash of "secret".
+    let hash = "$2b$04$/uaF/uaF/uaF/uaF/uaF/uyUa5B4sNev9rTvA6TvEBWYO4koffwMy";
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .basic_auth("prometheus", hash)
+        .build()
+        .unwrap();
+    server.serve();
+    let addr = server.local_addr();
+    server.update(b"a 1\n".to_vec());
+
+    // Assert unauthenticated scrapes are challenged.
+    let client = reqwest::blocking::Client::new();
+    let res = client.get(format!("http://{addr}/metrics")).send().unwrap();
+    assert_eq!(401, res.status());
+    assert!(res.headers()["www-authenticate"]
+        .to_str()
+        .unwrap()
+        .starts_with("Basic realm=\"metrics\""));
+
+    // Assert the wrong password is rejected.
+    let res = client
+        .get(format!("http://{addr}/metrics"))
+        .basic_auth("prometheus", Some("wrong"))
+        .send()
+        .unwrap();
+    assert_eq!(401, res.status());
+
+    // Assert the password matching the hash is accepted.
+    let res = client
+        .get(format!("http://{addr}/metrics"))
+        .basic_auth("prometheus", Some("secret"))
+        .send()
+        .unwrap();
+    assert_eq!(200, res.status());
+    assert_eq!(res.text().unwrap(), "a 1\n");
+
+    // Stop the server.
+    server.stop().unwrap();
+
+    // Assert invalid hashes are rejected when building the server.
+    let res = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .basic_auth("prometheus", "secret")
+        .build();
+    assert!(matches!(res, Err(ServerError::Create(_))));
+}
+
+#[test]
+fn test_bearer_auth_validator() {
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .auth(BearerAuth::with_validator(|token| token == "rotated"))
+        .build()
+        .unwrap();
+    server.serve();
+    let addr = server.local_addr();
+
+    // Assert tokens accepted by the validator are allowed, and others forbidden.
+    let client = reqwest::blocking::Client::new();
+    let res = client
+        .get(format!("http://{addr}/metrics"))
+        .bearer_auth("rotated")
+        .send()
+        .unwrap();
+    assert_eq!(200, res.status());
+    let res = client
+        .get(format!("http://{addr}/metrics"))
+        .bearer_auth("stale")
+        .send()
+        .unwrap();
+    assert_eq!(403, res.status());
+
+    // Stop the server.
+    server.stop().unwrap();
+}
+
+#[test]
+fn test_ipv4_mapped_remote_addr() {
+    // Only allows IPv4 clients, as an IPv4 allowlist would.
+    struct Ipv4Only;
+
+    impl Authenticator for Ipv4Only {
+        fn authenticate(&self, req: &RequestMeta) -> AuthDecision {
+            match req.remote_addr() {
+                Some(addr) if addr.is_ipv4() => AuthDecision::Allow,
+                _ => AuthDecision::Forbidden,
+            }
+        }
+    }
+
+    // Dual-stack listeners accept IPv4 clients with IPv4-mapped IPv6 addresses.
+    let mut server = match MetricsServer::builder()
+        .address("[::]:0")
+        .auth(Ipv4Only)
+        .build()
+    {
+        Ok(server) => server,
+        // IPv6 isn't available.
+        Err(_) => return,
+    };
+    server.serve();
+    let port = server.local_addr().port();
+
+    // Assert IPv4 clients are seen with their IPv4 address.
+    let res = reqwest::blocking::get(format!("http://127.0.0.1:{port}/metrics")).unwrap();
+    assert_eq!(200, res.status());
+
+    // Stop the server.
+    server.stop().unwrap();
+}
+
+#[test]
+fn test_case_insensitive_paths() {
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .case_insensitive_paths(true)
+        .build()
+        .unwrap();
+    server.serve_uri(MetricsPath::new("/Debug/Metrics").unwrap());
+    let addr = server.local_addr();
+
+    // Assert the configured path keeps its case.
+    assert_eq!(server.path(), Some("/Debug/Metrics"));
+
+    // Assert requests match regardless of case.
+    for path in ["/Debug/Metrics", "/debug/metrics", "/DEBUG/METRICS"] {
+        let res = reqwest::blocking::get(format!("http://{addr}{path}")).unwrap();
+        assert_eq!(200, res.status(), "{path}");
+    }
+    let res = reqwest::blocking::get(format!("http://{addr}/metrics")).unwrap();
+    assert_eq!(404, res.status());
+
+    // Stop the server.
+    server.stop().unwrap();
+
+    // Assert paths are case sensitive by default.
+    let mut server = MetricsServer::new("127.0.0.1:0", None, None).unwrap();
+    server.serve_uri(MetricsPath::new("/Debug/Metrics").unwrap());
+    let addr = server.local_addr();
+    let res = reqwest::blocking::get(format!("http://{addr}/Debug/Metrics")).unwrap();
+    assert_eq!(200, res.status());
+    let res = reqwest::blocking::get(format!("http://{addr}/debug/metrics")).unwrap();
+    assert_eq!(404, res.status());
+    server.stop().unwrap();
+}
+
+#[test]
+fn test_auth_fn() {
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .auth_fn(|req| {
+            req.headers()
+                .get("x-scrape-key")
+                .is_some_and(|key| key == "secret")
+        })
+        .listener(
+            ListenerConfig::new("127.0.0.1:0").auth(|req: &RequestMeta| {
+                match req.headers().get("x-scrape-key") {
+                    Some(key) if key == "secret" => AuthDecision::Allow,
+                    Some(_) => AuthDecision::Forbidden,
+                    None => AuthDecision::Unauthorized(Vec::new()),
+                }
+            }),
+        )
+        .build()
+        .unwrap();
+    server.serve();
+    let addrs = server.local_addrs();
+
+    // Assert requests are allowed by the function.
+    let client = reqwest::blocking::Client::new();
+    for addr in &addrs {
+        let res = client
+            .get(format!("http://{addr}/metrics"))
+            .header("x-scrape-key", "secret")
+            .send()
+            .unwrap();
+        assert_eq!(200, res.status());
+        let res = client
+            .get(format!("http://{addr}/metrics"))
+            .header("x-scrape-key", "wrong")
+            .send()
+            .unwrap();
+        assert_eq!(403, res.status());
+    }
+
+    // Assert closures returning a decision can challenge clients.
+    let res = client
+        .get(format!("http://{}/metrics", addrs[0]))
+        .send()
+        .unwrap();
+    assert_eq!(403, res.status());
+    let res = client
+        .get(format!("http://{}/metrics", addrs[1]))
+        .send()
+        .unwrap();
+    assert_eq!(401, res.status());
+
+    // Stop the server.
+    server.stop().unwrap();
+}
+
+#[test]
+#[cfg(feature = "tls")]
+fn test_tls_client_ca() {
+    let cert = include_bytes!("./certs/self_check_certificate.pem").to_vec();
+    let key = include_bytes!("./certs/self_check_private_key.pem").to_vec();
+    let ca = include_bytes!("./certs/client_ca.pem").to_vec();
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .tls(cert, key)
+        .tls_client_ca(ca)
+        .self_check(true)
+        .build()
+        .unwrap();
+    let addr = server.local_addr();
+    server.try_serve().unwrap();
+
+    // Assert clients presenting a certificate issued by the CA are served.
+    let identity = reqwest::Identity::from_pkcs8_pem(
+        include_bytes!("./certs/client_certificate.pem"),
+        include_bytes!("./certs/client_private_key.pem"),
+    )
+    .unwrap();
+    let client = reqwest::blocking::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .identity(identity)
+        .build()
+        .unwrap();
+    let res = client
+        .get(format!("https://{addr}/metrics"))
+        .send()
+        .unwrap();
+    assert_eq!(200, res.status());
+
+    // Assert clients without a certificate are rejected during the handshake.
+    let client = reqwest::blocking::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap();
+    assert!(client
+        .get(format!("https://{addr}/metrics"))
+        .send()
+        .is_err());
+
+    // Stop the server.
+    server.stop().unwrap();
+
+    // Assert client certificates require TLS.
+    let ca = include_bytes!("./certs/client_ca.pem").to_vec();
+    let res = MetricsServer::builder()
+        .listener(ListenerConfig::new("127.0.0.1:0").tls_client_ca(ca))
+        .build();
+    assert!(matches!(res, Err(ServerError::Create(_))));
+}
+
+#[test]
+fn test_cidr_access() {
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .allow_cidr("10.0.0.0/8")
+        .allow_cidr("127.0.0.0/8")
+        .build()
+        .unwrap();
+    server.serve();
+    let addr = server.local_addr();
+
+    // Assert peers within an allowed range are served.
+    let res = reqwest::blocking::get(format!("http://{addr}/metrics")).unwrap();
+    assert_eq!(200, res.status());
+    server.stop().unwrap();
+
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .allow_cidr("127.0.0.0/8")
+        .deny_cidr("127.0.0.1")
+        .rate_limit(1, 1)
+        .build()
+        .unwrap();
+    server.serve();
+    let addr = server.local_addr();
+
+    // Assert denied ranges take precedence, for every path, without using up the rate limit.
+    for path in ["/metrics", "/missing", "/metrics"] {
+        let res = reqwest::blocking::get(format!("http://{addr}{path}")).unwrap();
+        assert_eq!(403, res.status());
+    }
+    server.stop().unwrap();
+
+    // Assert invalid ranges are rejected.
+    let res = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .allow_cidr("10.0.0.0/33")
+        .build();
+    assert!(matches!(res, Err(ServerError::Create(_))));
+}
+
+#[test]
+fn test_max_connections() {
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .workers(2)
+        .max_connections(1)
+        .self_metrics(true)
+        .build()
+        .unwrap();
+    server.serve();
+    let addr = server.local_addr();
+
+    // Hold the only connection open without sending a request.
+    let held = TcpStream::connect(addr).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(100));
+
+    // Assert connections over the limit are rejected without reading a request.
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let mut res = String::new();
+    stream.read_to_string(&mut res).unwrap();
+    assert!(res.starts_with("HTTP/1.1 503"), "{res}");
+    assert!(
+        res.to_ascii_lowercase().contains("retry-after: 1\r\n"),
+        "{res}"
+    );
+
+    // Assert connections are accepted again once the held connection is closed.
+    drop(held);
+    let accepted = (0..50).any(|_| {
+        let res = reqwest::blocking::get(format!("http://{addr}/metrics")).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        res.status() == 200
+    });
+    assert!(accepted, "connection limit wasn't released");
+
+    // Assert rejected connections are counted.
+    server.update(Vec::new());
+    let body = reqwest::blocking::get(format!("http://{addr}/metrics"))
+        .unwrap()
+        .text()
+        .unwrap();
+    assert!(body.contains("metrics_server_overloaded_requests_total{reason=\"connections\"} 1\n"));
+
+    server.stop().unwrap();
+}
+
+#[test]
+fn test_max_connections_queued() {
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .workers(1)
+        .max_connections(2)
+        .build()
+        .unwrap();
+    server.serve();
+    let addr = server.local_addr();
+
+    // Hold one connection in the only worker, and another waiting for it.
+    let held = TcpStream::connect(addr).unwrap();
+    let queued = TcpStream::connect(addr).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(100));
+
+    // Assert connections waiting for a worker count towards the limit.
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let mut res = String::new();
+    stream.read_to_string(&mut res).unwrap();
+    assert!(res.starts_with("HTTP/1.1 503"), "{res}");
+
+    // Assert the queued connection is served once the worker is free.
+    drop(held);
+    let mut queued = queued;
+    queued
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let mut res = String::new();
+    queued.read_to_string(&mut res).unwrap();
+    assert!(res.starts_with("HTTP/1.1 200 OK"), "{res}");
+
+    server.stop().unwrap();
+}
+
+#[test]
+fn test_read_timeout() {
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .read_timeout(std::time::Duration::from_millis(300))
+        .build()
+        .unwrap();
+    server.serve();
+    let addr = server.local_addr();
+
+    // Send a request a line at a time, each well within the timeout.
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let start = std::time::Instant::now();
+    let _ = stream.write_all(b"GET /metrics HTTP/1.1\r\n");
+    for _ in 0..10 {
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        if stream.write_all(b"X-Slow: 1\r\n").is_err() {
+            break;
+        }
+    }
+
+    // Assert the connection was closed without a response once the whole request timed out.
+    let mut res = Vec::new();
+    let _ = stream.read_to_end(&mut res);
+    assert!(res.is_empty());
+    assert!(start.elapsed() < std::time::Duration::from_secs(5));
+
+    // Assert other clients are still served.
+    let res = reqwest::blocking::get(format!("http://{addr}/metrics")).unwrap();
+    assert_eq!(200, res.status());
+
+    server.stop().unwrap();
+}
+
+#[test]
+fn test_request_size_limits() {
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .max_header_size(1024)
+        .max_body_size(16)
+        .build()
+        .unwrap();
+    server.serve();
+    let addr = server.local_addr();
+
+    let send = |raw: &str| {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(raw.as_bytes()).unwrap();
+        let mut res = String::new();
+        stream.read_to_string(&mut res).unwrap();
+        res
+    };
+
+    // Assert bodies over the limit are rejected without being sent.
+    let res = send("POST /metrics HTTP/1.1\r\nContent-Length: 1000000\r\n\r\n");
+    assert!(res.starts_with("HTTP/1.1 413"), "{res}");
+
+    // Assert headers over the limit are rejected.
+    let raw = format!(
+        "GET /metrics HTTP/1.1\r\nX-Large: {}\r\n\r\n",
+        "a".repeat(2048)
+    );
+    let res = send(&raw);
+    assert!(res.starts_with("HTTP/1.1 431"), "{res}");
+
+    // Assert requests within the limits are served.
+    let res = send("GET /metrics HTTP/1.1\r\nContent-Length: 4\r\n\r\nbody");
+    assert!(res.starts_with("HTTP/1.1 200"), "{res}");
+
+    server.stop().unwrap();
+}
+
+#[test]
+#[cfg(feature = "tls")]
+fn test_reload_tls() {
+    let cert = include_bytes!("./certs/certificate.pem").to_vec();
+    let key = include_bytes!("./certs/private_key.pem").to_vec();
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .tls(cert, key)
+        .build()
+        .unwrap();
+    server.serve();
+    let addr = server.local_addr();
+
+    // Assert clients trusting only the new certificate reject the expired one.
+    let root = reqwest::Certificate::from_pem(include_bytes!("./certs/self_check_certificate.pem"))
+        .unwrap();
+    let client = reqwest::blocking::Client::builder()
+        .add_root_certificate(root)
+        .build()
+        .unwrap();
+    assert!(client
+        .get(format!("https://{addr}/metrics"))
+        .send()
+        .is_err());
+
+    // Assert the new certificate is served once reloaded.
+    let cert = include_bytes!("./certs/self_check_certificate.pem").to_vec();
+    let key = include_bytes!("./certs/self_check_private_key.pem").to_vec();
+    server.reload_tls(cert, key).unwrap();
+    let res = client
+        .get(format!("https://{addr}/metrics"))
+        .send()
+        .unwrap();
+    assert_eq!(200, res.status());
+
+    // Assert invalid certificates are rejected, and the previous one is kept.
+    let res = server.reload_tls(b"invalid".to_vec(), b"invalid".to_vec());
+    assert!(matches!(res, Err(ServerError::Create(_))));
+    let res = client
+        .get(format!("https://{addr}/metrics"))
+        .send()
+        .unwrap();
+    assert_eq!(200, res.status());
+
+    server.stop().unwrap();
+
+    // Assert reloading requires a listener configured with PEM data.
+    let server = MetricsServer::http("127.0.0.1:0");
+    let cert = include_bytes!("./certs/self_check_certificate.pem").to_vec();
+    let key = include_bytes!("./certs/self_check_private_key.pem").to_vec();
+    assert!(server.reload_tls(cert, key).is_err());
+}
+
+#[test]
+#[cfg(feature = "tls")]
+fn test_https_files() {
+    let mut server = MetricsServer::https_files(
+        "127.0.0.1:0",
+        "tests/certs/certificate.pem",
+        "tests/certs/private_key.pem",
+    );
+    let addr = server.local_addr();
+
+    let client = reqwest::blocking::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap();
+    let res = client
+        .get(format!("https://{addr}/metrics"))
+        .send()
+        .unwrap();
+    assert_eq!(200, res.status());
+    server.stop().unwrap();
+
+    // Assert missing files are rejected.
+    let res = MetricsServer::try_https_files(
+        "127.0.0.1:0",
+        "tests/certs/missing.pem",
+        "tests/certs/private_key.pem",
+    );
+    assert!(matches!(res, Err(ServerError::Create(_))));
+}
+
+#[test]
+fn test_mock_clock() {
+    let clock = MockClock::new(
+        std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(784111777),
+    );
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .clock(clock.clone())
+        .rate_limit(1, 1)
+        .build()
+        .unwrap();
+    server.serve();
+    let addr = server.local_addr();
+    server.update(b"a 1\n".to_vec());
+
+    // Assert updates are timestamped by the clock.
+    let res = reqwest::blocking::get(format!("http://{addr}/metrics")).unwrap();
+    assert_eq!(200, res.status());
+    assert_eq!(
+        res.headers()["last-modified"],
+        "Sun, 06 Nov 1994 08:49:37 GMT"
+    );
+
+    // Assert the rate limit is only refilled as the clock advances.
+    let res = reqwest::blocking::get(format!("http://{addr}/metrics")).unwrap();
+    assert_eq!(503, res.status());
+    clock.advance(std::time::Duration::from_millis(500));
+    let res = reqwest::blocking::get(format!("http://{addr}/metrics")).unwrap();
+    assert_eq!(503, res.status());
+    clock.advance(std::time::Duration::from_millis(500));
+    let res = reqwest::blocking::get(format!("http://{addr}/metrics")).unwrap();
+    assert_eq!(200, res.status());
+
+    server.stop().unwrap();
+}
+
+#[test]
+#[cfg(feature = "pkcs12")]
+fn test_tls_pkcs12() {
+    let archive = include_bytes!("./certs/self_check.p12").to_vec();
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .tls_pkcs12(archive, "secret")
+        .self_check(true)
+        .build()
+        .unwrap();
+    let addr = server.local_addr();
+    server.try_serve().unwrap();
+
+    // Assert the certificate in the archive is served.
+    let root = reqwest::Certificate::from_pem(include_bytes!("./certs/self_check_certificate.pem"))
+        .unwrap();
+    let client = reqwest::blocking::Client::builder()
+        .add_root_certificate(root)
+        .build()
+        .unwrap();
+    let res = client
+        .get(format!("https://{addr}/metrics"))
+        .send()
+        .unwrap();
+    assert_eq!(200, res.status());
+    server.stop().unwrap();
+
+    // Assert archives are rejected with the wrong passphrase.
+    let archive = include_bytes!("./certs/self_check.p12").to_vec();
+    let res = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .tls_pkcs12(archive, "wrong")
+        .build();
+    assert!(matches!(res, Err(ServerError::Create(_))));
+}
+
+#[test]
+#[cfg(feature = "encrypted-keys")]
+fn test_tls_key_passphrase() {
+    let cert = include_bytes!("./certs/self_check_certificate.pem").to_vec();
+    let key = include_bytes!("./certs/self_check_encrypted_key.pem").to_vec();
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .tls(cert.clone(), key.clone())
+        .tls_key_passphrase("secret")
+        .self_check(true)
+        .build()
+        .unwrap();
+    let addr = server.local_addr();
+    server.try_serve().unwrap();
+
+    // Assert the decrypted key is used to serve the certificate.
+    let root = reqwest::Certificate::from_pem(&cert).unwrap();
+    let client = reqwest::blocking::Client::builder()
+        .add_root_certificate(root)
+        .build()
+        .unwrap();
+    let res = client
+        .get(format!("https://{addr}/metrics"))
+        .send()
+        .unwrap();
+    assert_eq!(200, res.status());
+
+    // Assert reloaded keys are decrypted with the same passphrase.
+    server.reload_tls(cert.clone(), key.clone()).unwrap();
+    server.stop().unwrap();
+
+    // Assert encrypted keys are rejected without a passphrase, or with the wrong one.
+    let res = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .tls(cert.clone(), key.clone())
+        .build();
+    assert!(matches!(res, Err(ServerError::Create(e)) if e.contains("no passphrase")));
+    let res = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .tls(cert, key)
+        .tls_key_passphrase("wrong")
+        .build();
+    assert!(matches!(res, Err(ServerError::Create(_))));
+}
+
+#[test]
+fn test_refuse_http10() {
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .self_metrics(true)
+        .refuse_http10(true)
+        .build()
+        .unwrap();
+    server.serve();
+    let addr = server.local_addr();
+
+    // Assert HTTP/1.0 requests are refused, while HTTP/1.1 requests are served.
+    let res = raw_request(addr, "GET /metrics HTTP/1.0\r\n\r\n");
+    assert!(res.starts_with("HTTP/1.0 505 HTTP Version Not Supported\r\n"));
+    let res = raw_request(
+        addr,
+        "GET /metrics HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    );
+    assert!(res.starts_with("HTTP/1.1 200 OK\r\n"));
+
+    // Assert both requests are counted by HTTP version.
+    server.update(Vec::new());
+    let res = reqwest::blocking::get(format!("http://{addr}/metrics")).unwrap();
+    let body = res.text().unwrap();
+    assert!(body.contains("metrics_server_http_requests_by_version_total{version=\"1.0\"} 1\n"));
+    assert!(body.contains("metrics_server_http_requests_by_version_total{version=\"1.1\"} 1\n"));
+
+    server.stop().unwrap();
+}
+
+#[test]
+#[cfg(feature = "tls")]
+fn test_tls_protocol_options() {
+    let cert = include_bytes!("./certs/self_check_certificate.pem").to_vec();
+    let key = include_bytes!("./certs/self_check_private_key.pem").to_vec();
+    let mut server = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .tls(cert.clone(), key.clone())
+        .tls_min_version(TlsVersion::Tls13)
+        .tls_cipher_suites(["TLS13_AES_256_GCM_SHA384"])
+        .self_check(true)
+        .build()
+        .unwrap();
+    let addr = server.local_addr();
+    server.try_serve().unwrap();
+
+    // Assert TLS 1.3 clients are served.
+    let root = reqwest::Certificate::from_pem(&cert).unwrap();
+    let client = reqwest::blocking::Client::builder()
+        .add_root_certificate(root.clone())
+        .build()
+        .unwrap();
+    let res = client
+        .get(format!("https://{addr}/metrics"))
+        .send()
+        .unwrap();
+    assert_eq!(200, res.status());
+
+    // Assert TLS 1.2 clients are refused.
+    let client = reqwest::blocking::Client::builder()
+        .add_root_certificate(root)
+        .max_tls_version(reqwest::tls::Version::TLS_1_2)
+        .build()
+        .unwrap();
+    assert!(client
+        .get(format!("https://{addr}/metrics"))
+        .send()
+        .is_err());
+    server.stop().unwrap();
+
+    // Assert unknown cipher suites are an error.
+    let res = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .tls(cert.clone(), key.clone())
+        .tls_cipher_suites(["TLS_RSA_WITH_RC4_128_MD5"])
+        .build();
+    assert!(matches!(res, Err(ServerError::Create(e)) if e.contains("unknown cipher suite")));
+
+    // Assert suites not supporting the negotiated versions are an error.
+    let res = MetricsServer::builder()
+        .address("127.0.0.1:0")
+        .tls(cert, key)
+        .tls_min_version(TlsVersion::Tls13)
+        .tls_cipher_suites(["TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384"])
+        .build();
+    assert!(matches!(res, Err(ServerError::Create(_))));
+}